@@ -0,0 +1,436 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use lazy_static::lazy_static;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+/// Length of the header every system description table starts with.
+const HEADER_SIZE: usize = 36;
+
+/// MADT flag: the legacy 8259 PICs are present.
+pub const MADT_PCAT_COMPAT: u32 = 1;
+
+/// Where the BIOS keeps the segment of the extended BIOS data area.
+const EBDA_POINTER: u64 = 0x40e;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Copy `len` bytes of physical memory.
+fn read_physical(addr: u64, len: usize) -> Option<Vec<u8>> {
+    let virt = memory::map_mmio(PhysAddr::new(addr), len as u64).ok()?;
+    let slice = unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), len) };
+    Some(slice.to_vec())
+}
+
+/// A system description table, including its header.
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+impl Table {
+    /// Read and check the table at `address`.
+    fn read(address: u64) -> Option<Table> {
+        let header = read_physical(address, HEADER_SIZE)?;
+        let length = u32_at(&header, 4) as usize;
+        if length < HEADER_SIZE {
+            return None;
+        }
+        let data = read_physical(address, length)?;
+        if !checksum_ok(&data) {
+            return None;
+        }
+        Some(Table { address, data })
+    }
+
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.data[0..4]).unwrap_or("????")
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.data[8]
+    }
+
+    pub fn oem_id(&self) -> String {
+        String::from_utf8_lossy(&self.data[10..16]).into_owned()
+    }
+
+    /// The table contents after the header.
+    pub fn body(&self) -> &[u8] {
+        &self.data[HEADER_SIZE..]
+    }
+}
+
+/// Look for the RSDP signature on 16-byte boundaries.
+fn scan_rsdp(start: u64, len: usize) -> Option<u64> {
+    let area = read_physical(start, len)?;
+    (0..len.saturating_sub(20))
+        .step_by(16)
+        .find(|&offset| {
+            &area[offset..offset + 8] == b"RSD PTR " && checksum_ok(&area[offset..offset + 20])
+        })
+        .map(|offset| start + offset as u64)
+}
+
+/// Find the root system description pointer in the first KiB of the EBDA
+/// or in the BIOS ROM area.
+fn find_rsdp() -> Option<u64> {
+    let ebda = read_physical(EBDA_POINTER, 2).map(|segment| (u16_at(&segment, 0) as u64) << 4);
+    ebda.filter(|&ebda| ebda != 0)
+        .and_then(|ebda| scan_rsdp(ebda, 1024))
+        .or_else(|| scan_rsdp(0xe0000, 0x20000))
+}
+
+/// Read every table listed in the XSDT, or the RSDT on ACPI 1.0 systems.
+fn read_tables() -> Vec<Table> {
+    let rsdp_address = match find_rsdp() {
+        Some(address) => address,
+        None => return Vec::new(),
+    };
+    let rsdp = match read_physical(rsdp_address, 36) {
+        Some(rsdp) => rsdp,
+        None => return Vec::new(),
+    };
+    let revision = rsdp[15];
+    let (root, entry_size) = if revision >= 2 && u64_at(&rsdp, 24) != 0 {
+        (Table::read(u64_at(&rsdp, 24)), 8)
+    } else {
+        (Table::read(u32_at(&rsdp, 16) as u64), 4)
+    };
+    let root = match root {
+        Some(root) => root,
+        None => return Vec::new(),
+    };
+    root.body()
+        .chunks_exact(entry_size)
+        .filter_map(|entry| match entry_size {
+            8 => Table::read(u64_at(entry, 0)),
+            _ => Table::read(u32_at(entry, 0) as u64),
+        })
+        .collect()
+}
+
+lazy_static! {
+    static ref TABLES: Vec<Table> = read_tables();
+}
+
+/// All tables found, empty if there is no ACPI.
+pub fn tables() -> &'static [Table] {
+    &TABLES
+}
+
+/// The first table with `signature`, such as `b"APIC"` for the MADT.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static Table> {
+    TABLES.iter().find(|table| &table.data[0..4] == signature)
+}
+
+/// An entry of the multiple APIC description table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    /// An ISA IRQ that is connected to a different global system interrupt
+    /// or with different polarity and trigger mode than usual.
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub flags: u32,
+    pub entries: Vec<MadtEntry>,
+}
+
+impl Madt {
+    pub fn parse(body: &[u8]) -> Option<Madt> {
+        if body.len() < 8 {
+            return None;
+        }
+        let mut madt = Madt {
+            local_apic_address: u32_at(body, 0) as u64,
+            flags: u32_at(body, 4),
+            entries: Vec::new(),
+        };
+        let mut offset = 8;
+        while offset + 2 <= body.len() {
+            let kind = body[offset];
+            let len = body[offset + 1] as usize;
+            if len < 2 || offset + len > body.len() {
+                break;
+            }
+            let entry = &body[offset..offset + len];
+            let parsed = match (kind, len) {
+                (0, 8..) => Some(MadtEntry::LocalApic {
+                    processor_id: entry[2],
+                    apic_id: entry[3],
+                    flags: u32_at(entry, 4),
+                }),
+                (1, 12..) => Some(MadtEntry::IoApic {
+                    id: entry[2],
+                    address: u32_at(entry, 4),
+                    gsi_base: u32_at(entry, 8),
+                }),
+                (2, 10..) => Some(MadtEntry::InterruptSourceOverride {
+                    bus: entry[2],
+                    source: entry[3],
+                    gsi: u32_at(entry, 4),
+                    flags: u16_at(entry, 8),
+                }),
+                (5, 12..) => Some(MadtEntry::LocalApicAddressOverride {
+                    address: u64_at(entry, 4),
+                }),
+                _ => None,
+            };
+            if let Some(MadtEntry::LocalApicAddressOverride { address }) = parsed {
+                madt.local_apic_address = address;
+            }
+            madt.entries.extend(parsed);
+            offset += len;
+        }
+        Some(madt)
+    }
+}
+
+/// The MADT, if the firmware provides one.
+pub fn madt() -> Option<Madt> {
+    Madt::parse(find_table(b"APIC")?.body())
+}
+
+/// A generic address structure, locating a register in an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    /// 0 for memory, 1 for I/O ports
+    pub space: u8,
+    pub bit_width: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    fn parse(data: &[u8]) -> GenericAddress {
+        GenericAddress {
+            space: data[0],
+            bit_width: data[1],
+            address: u64_at(data, 4),
+        }
+    }
+}
+
+/// FADT flag: the reset register is supported.
+pub const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+/// The parts of the fixed ACPI description table used for power
+/// management.
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    pub dsdt: u64,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control: u32,
+    pub pm1b_control: u32,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Parse the FADT from the whole table, header included, since its
+    /// fields are usually given by offset into it.
+    pub fn parse(data: &[u8]) -> Option<Fadt> {
+        if data.len() < 116 {
+            return None;
+        }
+        let mut fadt = Fadt {
+            dsdt: u32_at(data, 40) as u64,
+            smi_command: u32_at(data, 48),
+            acpi_enable: data[52],
+            pm1a_control: u32_at(data, 64),
+            pm1b_control: u32_at(data, 68),
+            flags: u32_at(data, 112),
+            reset_register: None,
+            reset_value: 0,
+        };
+        if data.len() >= 129 && fadt.flags & FADT_RESET_REG_SUP != 0 {
+            fadt.reset_register = Some(GenericAddress::parse(&data[116..128]));
+            fadt.reset_value = data[128];
+        }
+        // ACPI 2.0 adds a 64-bit DSDT address
+        if data.len() >= 148 && u64_at(data, 140) != 0 {
+            fadt.dsdt = u64_at(data, 140);
+        }
+        Some(fadt)
+    }
+}
+
+/// The FADT, if the firmware provides one.
+pub fn fadt() -> Option<Fadt> {
+    Fadt::parse(&find_table(b"FACP")?.data)
+}
+
+/// The differentiated system description table, with the AML code that
+/// describes the machine.
+pub fn dsdt() -> Option<Table> {
+    Table::read(fadt()?.dsdt)
+}
+
+/// Values for the SLP_TYP fields of the PM1a and PM1b control registers
+/// that select a sleep state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u16,
+    pub b: u16,
+}
+
+/// Find the `\_S5_` package in AML code without interpreting it, which
+/// works for the simple way firmware defines it.
+pub fn parse_s5(aml: &[u8]) -> Option<SleepType> {
+    let start = aml.windows(4).position(|window| window == b"_S5_")?;
+    // NameOp before the name (possibly with a root prefix) and PackageOp
+    // after it
+    let name_op = start >= 1 && aml[start - 1] == 0x08
+        || start >= 2 && aml[start - 2] == 0x08 && aml[start - 1] == b'\\';
+    if !name_op || aml.get(start + 4) != Some(&0x12) {
+        return None;
+    }
+    let mut offset = start + 5;
+    // the top two bits of the package length give the number of extra bytes
+    offset += ((aml.get(offset)? >> 6) + 1) as usize;
+    // number of elements
+    offset += 1;
+    let mut element = || -> Option<u16> {
+        let value = match *aml.get(offset)? {
+            // BytePrefix
+            0x0a => {
+                offset += 1;
+                *aml.get(offset)? as u16
+            }
+            // ZeroOp and OneOp
+            value @ (0x00 | 0x01) => value as u16,
+            // OnesOp
+            0xff => 0xff,
+            _ => return None,
+        };
+        offset += 1;
+        Some(value)
+    };
+    let a = element()?;
+    let b = element()?;
+    Some(SleepType { a, b })
+}
+
+/// The high precision event timer description.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub address: GenericAddress,
+    pub number: u8,
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub fn parse(body: &[u8]) -> Option<Hpet> {
+        if body.len() < 19 {
+            return None;
+        }
+        Some(Hpet {
+            event_timer_block_id: u32_at(body, 0),
+            address: GenericAddress::parse(&body[4..16]),
+            number: body[16],
+            minimum_tick: u16_at(body, 17),
+        })
+    }
+}
+
+/// The HPET description, if the machine has one.
+pub fn hpet() -> Option<Hpet> {
+    Hpet::parse(find_table(b"HPET")?.body())
+}
+
+#[test_case]
+fn test_parse_madt() {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    body.extend_from_slice(&MADT_PCAT_COMPAT.to_le_bytes());
+    // processor 0 with APIC ID 0, enabled
+    body.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    // I/O APIC 1 at 0xfec00000 from GSI 0
+    body.extend_from_slice(&[1, 12, 1, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+    // IRQ 0 on GSI 2
+    body.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+
+    let madt = Madt::parse(&body).unwrap();
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert_eq!(madt.flags, MADT_PCAT_COMPAT);
+    assert_eq!(
+        madt.entries,
+        [
+            MadtEntry::LocalApic {
+                processor_id: 0,
+                apic_id: 0,
+                flags: 1
+            },
+            MadtEntry::IoApic {
+                id: 1,
+                address: 0xfec0_0000,
+                gsi_base: 0
+            },
+            MadtEntry::InterruptSourceOverride {
+                bus: 0,
+                source: 0,
+                gsi: 2,
+                flags: 0
+            },
+        ]
+    );
+}
+
+#[test_case]
+fn test_parse_s5() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+    let aml = [
+        0x08, 0x5c, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(parse_s5(&aml), Some(SleepType { a: 5, b: 0 }));
+    // Name (_S5, Package (0x02) { One, 0x07 })
+    let aml = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x01, 0x0a, 0x07,
+    ];
+    assert_eq!(parse_s5(&aml), Some(SleepType { a: 1, b: 7 }));
+    // not a definition
+    let aml = [
+        0x70, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x01, 0x0a, 0x07,
+    ];
+    assert_eq!(parse_s5(&aml), None);
+}
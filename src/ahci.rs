@@ -0,0 +1,363 @@
+//! SATA disks behind an AHCI host bus adapter, as on QEMU's q35 machine.
+//!
+//! Each port gets one command slot with a single PRD entry pointing at a
+//! bounce buffer, and commands are polled for like `ata` and `virtio` do.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::block::{self, BlockDevice, BlockError, BLOCK_SIZE};
+use crate::memory;
+use crate::pci::{self, Bar, Device};
+use crate::time::{Duration, Instant};
+
+/// Mass storage, SATA
+const CLASS: u8 = 0x01;
+const SUBCLASS: u8 = 0x06;
+/// Programming interface of AHCI 1.0 controllers.
+const PROG_IF_AHCI: u8 = 0x01;
+
+// HBA registers
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize = 0x0c;
+const GHC_AE: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+
+// Port registers, as offsets from the port's base
+const PORT_CLB: usize = 0x00;
+const PORT_FB: usize = 0x08;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+/// Task file error
+const IS_TFES: u32 = 1 << 30;
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+/// Device present and communication established
+const SSTS_DET_PRESENT: u32 = 3;
+/// Signature of a SATA disk (as opposed to ATAPI, port multipliers, ...)
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Set in the H2D FIS for a command rather than a control update.
+const FIS_COMMAND: u8 = 0x80;
+const DEVICE_LBA: u8 = 1 << 6;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Command {
+    ReadDmaExt = 0x25,
+    WriteDmaExt = 0x35,
+    FlushCacheExt = 0xea,
+    Identify = 0xec,
+}
+
+const PAGE_SIZE: usize = 4096;
+/// In the first page of a port: the command list, then received FISes.
+const FIS_OFFSET: usize = 0x400;
+/// Where the PRD table starts in a command table.
+const PRDT_OFFSET: usize = 0x80;
+/// Words (dwords) in a register H2D FIS.
+const FIS_LENGTH: u32 = 5;
+
+/// Pages of data moved by one command.
+const DATA_PAGES: usize = 8;
+const BLOCKS_PER_COMMAND: usize = DATA_PAGES * PAGE_SIZE / BLOCK_SIZE;
+
+/// How long the port may take to stop, or a command to finish.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Drives found so far, for naming them `sda`, `sdb`, ...
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn read_mmio(base: VirtAddr, offset: usize) -> u32 {
+    ptr::read_volatile((base.as_u64() as usize + offset) as *const u32)
+}
+
+unsafe fn write_mmio(base: VirtAddr, offset: usize, value: u32) {
+    ptr::write_volatile((base.as_u64() as usize + offset) as *mut u32, value)
+}
+
+/// Spin until `done` or the timeout.
+fn wait(done: impl Fn() -> bool) -> Result<(), BlockError> {
+    let start = Instant::now();
+    while !done() {
+        if start.elapsed() > TIMEOUT {
+            return Err(BlockError::Io);
+        }
+        spin_loop();
+    }
+    Ok(())
+}
+
+/// One port of the HBA, with a disk attached.
+struct Port {
+    registers: VirtAddr,
+    /// Command list and received FIS area.
+    memory: PhysAddr,
+    /// Command table for slot 0.
+    table: PhysAddr,
+    /// `DATA_PAGES` pages of bounce buffer.
+    data: PhysAddr,
+}
+
+impl Port {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { read_mmio(self.registers, register) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { write_mmio(self.registers, register, value) }
+    }
+
+    fn stop(&self) -> Result<(), BlockError> {
+        self.write(PORT_CMD, self.read(PORT_CMD) & !(CMD_ST | CMD_FRE));
+        wait(|| self.read(PORT_CMD) & (CMD_CR | CMD_FR) == 0)
+    }
+
+    fn start(&self) -> Result<(), BlockError> {
+        wait(|| self.read(PORT_CMD) & CMD_CR == 0)?;
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_FRE);
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_ST);
+        Ok(())
+    }
+
+    /// Point the port at freshly allocated memory and start it.
+    fn new(registers: VirtAddr) -> Option<Port> {
+        let port = Port {
+            registers,
+            memory: memory::allocate_dma(1)?,
+            table: memory::allocate_dma(1)?,
+            data: memory::allocate_dma(DATA_PAGES)?,
+        };
+        port.stop().ok()?;
+        let clb = port.memory.as_u64();
+        let fb = clb + FIS_OFFSET as u64;
+        port.write(PORT_CLB, clb as u32);
+        port.write(PORT_CLB + 4, (clb >> 32) as u32);
+        port.write(PORT_FB, fb as u32);
+        port.write(PORT_FB + 4, (fb >> 32) as u32);
+        // commands are polled for
+        port.write(PORT_IE, 0);
+        port.write(PORT_SERR, !0);
+        port.write(PORT_IS, !0);
+
+        // slot 0 always uses the same table with one PRD entry
+        let table = port.table.as_u64();
+        unsafe {
+            let list = memory::phys_to_virt(port.memory);
+            write_mmio(list, 8, table as u32);
+            write_mmio(list, 12, (table >> 32) as u32);
+            let prd = memory::phys_to_virt(port.table) + PRDT_OFFSET as u64;
+            write_mmio(prd, 0, port.data.as_u64() as u32);
+            write_mmio(prd, 4, (port.data.as_u64() >> 32) as u32);
+        }
+        port.start().ok()?;
+        Some(port)
+    }
+
+    /// Run `command` on `count` sectors from `lba`, moving `bytes` bytes
+    /// through the data buffer.
+    fn command(
+        &mut self,
+        command: Command,
+        lba: u64,
+        count: u16,
+        bytes: usize,
+    ) -> Result<(), BlockError> {
+        wait(|| self.read(PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+
+        let fis = [
+            FIS_TYPE_REG_H2D,
+            FIS_COMMAND,
+            command as u8,
+            0,
+            lba as u8,
+            (lba >> 8) as u8,
+            (lba >> 16) as u8,
+            DEVICE_LBA,
+            (lba >> 24) as u8,
+            (lba >> 32) as u8,
+            (lba >> 40) as u8,
+            0,
+            count as u8,
+            (count >> 8) as u8,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let write = command == Command::WriteDmaExt;
+        let prdt_length = if bytes > 0 { 1 } else { 0 };
+        unsafe {
+            let table = memory::phys_to_virt(self.table);
+            ptr::copy_nonoverlapping(fis.as_ptr(), table.as_mut_ptr::<u8>(), fis.len());
+            if bytes > 0 {
+                // byte count minus one
+                let prd = table + PRDT_OFFSET as u64;
+                write_mmio(prd, 12, bytes as u32 - 1);
+            }
+            let header = memory::phys_to_virt(self.memory);
+            let flags = FIS_LENGTH | if write { 1 << 6 } else { 0 } | prdt_length << 16;
+            write_mmio(header, 0, flags);
+            // bytes transferred, updated by the HBA
+            write_mmio(header, 4, 0);
+        }
+
+        self.write(PORT_IS, !0);
+        self.write(PORT_CI, 1);
+        wait(|| self.read(PORT_CI) & 1 == 0 || self.read(PORT_IS) & IS_TFES != 0)?;
+        if self.read(PORT_IS) & IS_TFES != 0 || self.read(PORT_TFD) & TFD_ERR != 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    fn data(&self) -> *mut u8 {
+        memory::phys_to_virt(self.data).as_mut_ptr()
+    }
+
+    fn identify(&mut self) -> Option<[u16; 256]> {
+        self.command(Command::Identify, 0, 0, BLOCK_SIZE).ok()?;
+        let mut words = [0; 256];
+        unsafe { ptr::copy_nonoverlapping(self.data() as *const u16, words.as_mut_ptr(), 256) };
+        Some(words)
+    }
+}
+
+struct Drive {
+    name: String,
+    model: String,
+    port: Port,
+    sectors: u64,
+}
+
+impl Drive {
+    fn new(mut port: Port) -> Option<Drive> {
+        let words = port.identify()?;
+        // the model string has its bytes swapped in each word
+        let model: String = words[27..47]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .map(|byte| byte as char)
+            .collect();
+        let sectors = words[100..104]
+            .iter()
+            .rev()
+            .fold(0, |sectors, &word| sectors << 16 | word as u64);
+        Some(Drive {
+            name: block::device_name("sd", COUNT.fetch_add(1, Ordering::Relaxed)),
+            model: String::from(model.trim()),
+            port,
+            sectors,
+        })
+    }
+}
+
+impl BlockDevice for Drive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf.chunks_mut(BLOCKS_PER_COMMAND * BLOCK_SIZE).enumerate() {
+            let lba = block + (i * BLOCKS_PER_COMMAND) as u64;
+            let count = (chunk.len() / BLOCK_SIZE) as u16;
+            self.port
+                .command(Command::ReadDmaExt, lba, count, chunk.len())?;
+            unsafe { ptr::copy_nonoverlapping(self.port.data(), chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf.chunks(BLOCKS_PER_COMMAND * BLOCK_SIZE).enumerate() {
+            let lba = block + (i * BLOCKS_PER_COMMAND) as u64;
+            let count = (chunk.len() / BLOCK_SIZE) as u16;
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), self.port.data(), chunk.len()) };
+            self.port
+                .command(Command::WriteDmaExt, lba, count, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.port.command(Command::FlushCacheExt, 0, 0, 0)
+    }
+}
+
+fn probe(device: &Device) -> bool {
+    if device.prog_if != PROG_IF_AHCI {
+        return false;
+    }
+    let (address, size) = match device.bars[5] {
+        Bar::Memory { address, size, .. } => (address, size),
+        _ => return false,
+    };
+    let hba = match memory::map_mmio(PhysAddr::new(address), size) {
+        Ok(hba) => hba,
+        Err(_) => return false,
+    };
+    device.enable();
+    unsafe {
+        // AHCI mode, without interrupts
+        let ghc = read_mmio(hba, HBA_GHC);
+        write_mmio(hba, HBA_GHC, (ghc | GHC_AE) & !GHC_IE);
+    }
+    let implemented = unsafe { read_mmio(hba, HBA_PI) };
+    for i in 0..32 {
+        if implemented & 1 << i == 0 {
+            continue;
+        }
+        let registers = hba + 0x100u64 + i as u64 * 0x80;
+        let (status, signature) = unsafe {
+            (
+                read_mmio(registers, PORT_SSTS),
+                read_mmio(registers, PORT_SIG),
+            )
+        };
+        if status & 0xf != SSTS_DET_PRESENT || signature != SIG_ATA {
+            continue;
+        }
+        if let Some(drive) = Port::new(registers).and_then(Drive::new) {
+            block::register(Box::new(drive));
+        }
+    }
+    true
+}
+
+pub fn init() {
+    pci::register_driver(pci::Driver {
+        name: "ahci",
+        matches: &[pci::Match::Class {
+            class: CLASS,
+            subclass: SUBCLASS,
+        }],
+        probe,
+    });
+}
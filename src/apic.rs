@@ -0,0 +1,306 @@
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::{self, MadtEntry};
+use crate::interrupts::{InterruptIndex, PICS, PIC_1_OFFSET};
+use crate::memory;
+use crate::task::timer;
+use crate::time::{self, Duration, Instant};
+
+/// Vector for spurious interrupts from the local APIC. Its low four bits
+/// must be set on older CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers, as offsets from its base address
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide configuration for dividing the bus clock by 16
+const TIMER_DIVIDE_16: u32 = 0x3;
+
+/// How long to count local APIC timer ticks against the TSC.
+const TIMER_CALIBRATION: Duration = Duration::from_millis(10);
+
+// I/O APIC registers, selected through IOREGSEL
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Whether interrupts are delivered through the APICs instead of the PICs.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Virtual address of the local APIC registers.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+/// Local APIC timer counts per second, or 0 while the PIT drives the timer.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirections: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            let select = (self.base.as_u64() as usize + IOREGSEL) as *mut u32;
+            let window = (self.base.as_u64() as usize + IOWIN) as *mut u32;
+            select.write_volatile(register);
+            window.read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            let select = (self.base.as_u64() as usize + IOREGSEL) as *mut u32;
+            let window = (self.base.as_u64() as usize + IOWIN) as *mut u32;
+            select.write_volatile(register);
+            window.write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirections
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOREDTBL + (gsi - self.gsi_base) * 2;
+        self.write(register, entry as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+    }
+}
+
+/// How an ISA IRQ is wired to the I/O APICs.
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    gsi: u32,
+    active_low: bool,
+    level: bool,
+}
+
+struct Controllers {
+    io_apics: Vec<IoApic>,
+    /// Routes of ISA IRQs 0-15
+    routes: [Route; 16],
+    /// Local APIC ID of the CPU interrupts are sent to.
+    destination: u8,
+}
+
+lazy_static! {
+    static ref CONTROLLERS: Mutex<Option<Controllers>> = Mutex::new(None);
+}
+
+fn lapic_read(register: usize) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed) as usize;
+    unsafe { ((base + register) as *const u32).read_volatile() }
+}
+
+fn lapic_write(register: usize, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed) as usize;
+    unsafe { ((base + register) as *mut u32).write_volatile(value) }
+}
+
+/// Whether interrupts come through the APICs.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The local APIC ID of this CPU.
+pub fn local_apic_id() -> u8 {
+    (lapic_read(LAPIC_ID) >> 24) as u8
+}
+
+/// Signal the end of an interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+/// Switch from the 8259 PICs to the local and I/O APICs described by the
+/// ACPI MADT, returning false and leaving the PICs in charge if there are
+/// none.
+///
+/// Needs `memory::install` to have been called to map the registers, and
+/// the TSC to be calibrated to measure the local APIC timer against.
+pub fn init() -> bool {
+    if time::tsc_frequency().is_none() {
+        return false;
+    }
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+
+    let mut io_apics = Vec::new();
+    let mut routes = [Route {
+        gsi: 0,
+        active_low: false,
+        level: false,
+    }; 16];
+    for (irq, route) in routes.iter_mut().enumerate() {
+        route.gsi = irq as u32;
+    }
+    for entry in &madt.entries {
+        match *entry {
+            MadtEntry::IoApic {
+                address, gsi_base, ..
+            } => {
+                let base = match memory::map_mmio(PhysAddr::new(address as u64), 0x20) {
+                    Ok(base) => base,
+                    Err(_) => continue,
+                };
+                let mut io_apic = IoApic {
+                    base,
+                    gsi_base,
+                    redirections: 0,
+                };
+                io_apic.redirections = ((io_apic.read(IOAPICVER) >> 16) & 0xff) + 1;
+                io_apics.push(io_apic);
+            }
+            // bus 0 is ISA
+            MadtEntry::InterruptSourceOverride {
+                bus: 0,
+                source,
+                gsi,
+                flags,
+            } if source < 16 => {
+                routes[source as usize] = Route {
+                    gsi,
+                    // polarity 3 is active low, trigger mode 3 level
+                    active_low: flags & 0b11 == 0b11,
+                    level: (flags >> 2) & 0b11 == 0b11,
+                };
+            }
+            _ => {}
+        }
+    }
+    if io_apics.is_empty() {
+        return false;
+    }
+    let lapic = match memory::map_mmio(PhysAddr::new(madt.local_apic_address), 0x400) {
+        Ok(lapic) => lapic,
+        Err(_) => return false,
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // mask everything on the PICs; they may still raise spurious
+        // interrupts on IRQ 7 and 15
+        unsafe { PICS.lock().disable() };
+
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            base.write(value | APIC_BASE_ENABLE);
+        }
+        LAPIC_BASE.store(lapic.as_u64(), Ordering::Relaxed);
+        lapic_write(LAPIC_TASK_PRIORITY, 0);
+        lapic_write(LAPIC_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+
+        for io_apic in &io_apics {
+            for i in 0..io_apic.redirections {
+                io_apic.set_redirection(io_apic.gsi_base + i, REDIRECTION_MASKED);
+            }
+        }
+        *CONTROLLERS.lock() = Some(Controllers {
+            io_apics,
+            routes,
+            destination: local_apic_id(),
+        });
+        ENABLED.store(true, Ordering::Relaxed);
+    });
+
+    start_timer();
+    true
+}
+
+/// Replace the PIT with the local APIC timer at the same rate.
+fn start_timer() {
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    let start = Instant::now();
+    while start.elapsed() < TIMER_CALIBRATION {
+        spin_loop();
+    }
+    let counted = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+    let per_second = counted as u64 * 1_000_000_000 / TIMER_CALIBRATION.as_nanos() as u64;
+    TIMER_FREQUENCY.store(per_second.max(1), Ordering::Relaxed);
+
+    lapic_write(
+        LAPIC_LVT_TIMER,
+        InterruptIndex::Timer as u32 | LVT_TIMER_PERIODIC,
+    );
+    time::set_frequency(time::frequency());
+}
+
+/// Make the local APIC timer interrupt `hz` times a second, returning the
+/// frequency it ends up at, or `None` if it isn't running.
+pub fn set_timer_frequency(hz: u32) -> Option<u32> {
+    let per_second = match TIMER_FREQUENCY.load(Ordering::Relaxed) {
+        0 => return None,
+        per_second => per_second,
+    };
+    let initial = (per_second / hz.max(1) as u64).clamp(1, u32::MAX as u64);
+    lapic_write(LAPIC_TIMER_INITIAL, initial as u32);
+    timer::set_tick_duration(Duration::from_nanos(initial * 1_000_000_000 / per_second));
+    Some((per_second / initial) as u32)
+}
+
+/// Deliver ISA `irq` to the vector the PICs would have used for it.
+pub fn enable_irq(irq: u8) {
+    let controllers = CONTROLLERS.lock();
+    let controllers = match controllers.as_ref() {
+        Some(controllers) => controllers,
+        None => return,
+    };
+    let route = controllers.routes[irq as usize & 0xf];
+    let mut entry = (PIC_1_OFFSET + irq) as u64 | (controllers.destination as u64) << 56;
+    if route.active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if route.level {
+        entry |= REDIRECTION_LEVEL;
+    }
+    if let Some(io_apic) = controllers
+        .io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(route.gsi))
+    {
+        io_apic.set_redirection(route.gsi, entry);
+    }
+}
+
+/// Stop delivering ISA `irq`.
+pub fn disable_irq(irq: u8) {
+    let controllers = CONTROLLERS.lock();
+    if let Some(controllers) = controllers.as_ref() {
+        let gsi = controllers.routes[irq as usize & 0xf].gsi;
+        if let Some(io_apic) = controllers
+            .io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(gsi))
+        {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::block::{self, BlockDevice, BlockError};
+use crate::pci;
+use crate::task::timer;
 use crate::{print, println, serial_println};
 
 pub fn get_disks() {
@@ -6,33 +9,96 @@ pub fn get_disks() {
         println!("{}: {} ({}{})", disk.1, disk.2, disk.4, disk.5)
     }
 }
-pub fn read_data(bus: u8, drive: u8, offset: u32, blocks: usize) -> Vec<u8> {
+pub fn read_data(bus: u8, drive: u8, offset: u32, blocks: usize) -> Result<Vec<u8>, BlockError> {
     let mut buffer = alloc::vec![0;ATA_BLOCK_SIZE*blocks];
     // 2. Create a temporary buffer of size 512.
     let mut temp_buffer: [u8; ATA_BLOCK_SIZE] = [0; ATA_BLOCK_SIZE];
 
     for block in 0..blocks {
         // 3. Pass the buffer over to the Subsystem, to be filled.
-        read(bus, drive, offset, &mut temp_buffer);
+        read(bus, drive, offset + block as u32, &mut temp_buffer)?;
         buffer[block * ATA_BLOCK_SIZE..(block + 1) * ATA_BLOCK_SIZE].copy_from_slice(&temp_buffer);
     }
 
-    buffer
+    Ok(buffer)
 }
 pub fn init_ata() {
     // 1. Initialise ATA Subsystem. (Perform Once, on boot)
     init().expect("Failed To Start ATA...");
+    for (bus, drive, model, _, _, _, sectors) in list() {
+        block::register(Box::new(Drive {
+            name: block::device_name("hd", (bus * 2 + drive) as usize),
+            model,
+            bus,
+            drive,
+            sectors,
+        }));
+    }
+}
+
+/// A drive as a block device, named like Linux does: `hda` is the master
+/// on the primary bus, `hdb` its slave and so on.
+struct Drive {
+    name: String,
+    model: String,
+    bus: u8,
+    drive: u8,
+    sectors: u32,
+}
+
+impl BlockDevice for Drive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors as u64
+    }
+
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf.chunks_mut(ATA_BLOCK_SIZE).enumerate() {
+            read(
+                self.bus,
+                self.drive,
+                (block + i as u64) as BlockIndex,
+                chunk,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf.chunks(ATA_BLOCK_SIZE).enumerate() {
+            write(
+                self.bus,
+                self.drive,
+                (block + i as u64) as BlockIndex,
+                chunk,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        BUSES.lock()[self.bus as usize].flush(self.drive)
+    }
 }
 
 /// Implementation Courtesy of MOROS.
 /// Currently Only Supports ATA-PIO, with 24-bit LBA Addressing.
 extern crate alloc;
 
+use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
 use bit_field::BitField;
-use core::{hint::spin_loop, str};
+use core::{hint::spin_loop, str, time::Duration};
 use lazy_static::lazy_static;
+use log::trace;
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 
@@ -40,17 +106,15 @@ pub type BlockIndex = u32;
 
 pub const ATA_BLOCK_SIZE: usize = 512;
 
-fn sleep_ticks(ticks: usize) {
-    for _ in 0..=ticks {
-        x86_64::instructions::hlt();
-    }
-}
+/// How long a command may keep the drive busy before the bus is reset.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[repr(u16)]
 enum Command {
     Read = 0x20,
     Write = 0x30,
     Identify = 0xEC,
+    FlushCache = 0xE7,
 }
 
 #[allow(dead_code)]
@@ -114,9 +178,9 @@ impl Bus {
     fn reset(&mut self) {
         unsafe {
             self.control_register.write(6); // Set SRST bit and nIEN bit
-            sleep_ticks(2);
+            timer::wait_ticks(2);
             self.control_register.write(0); // Then clear it
-            sleep_ticks(2);
+            timer::wait_ticks(2);
         }
     }
 
@@ -161,17 +225,25 @@ impl Bus {
         unsafe { self.data_register.write(data) }
     }
 
-    fn busy_loop(&mut self) {
+    /// Wait for the drive to finish a command, failing if it reports an
+    /// error or doesn't finish within `BUSY_TIMEOUT`.
+    fn busy_loop(&mut self) -> Result<(), BlockError> {
         self.wait();
-        let start = 0;
+        let deadline = timer::ticks() + timer::duration_to_ticks(BUSY_TIMEOUT);
         while self.is_busy() {
-            if 0 - start > 1 {
+            if timer::ticks() > deadline {
                 // Hanged
-                return self.reset();
+                self.reset();
+                return Err(BlockError::Io);
             }
 
             spin_loop();
         }
+        let status = self.status();
+        if status.get_bit(Status::ERR as usize) || status.get_bit(Status::DF as usize) {
+            return Err(BlockError::Io);
+        }
+        Ok(())
     }
 
     fn is_busy(&mut self) -> bool {
@@ -224,7 +296,8 @@ impl Bus {
             return None;
         }
 
-        self.busy_loop();
+        // ATAPI devices abort the command
+        self.busy_loop().ok()?;
 
         if self.lba1() != 0 || self.lba2() != 0 {
             return None;
@@ -265,21 +338,17 @@ impl Bus {
     ///     read(0, 0, 0, &mut buffer);
     /// }
 
-    pub fn read(&mut self, drive: u8, block: BlockIndex, buf: &mut [u8]) {
+    pub fn read(&mut self, drive: u8, block: BlockIndex, buf: &mut [u8]) -> Result<(), BlockError> {
         assert!(buf.len() == 512);
-        //log!("Reading Block 0x{:8X}\n", block);
-        //log!("{:?}", self);
-
         self.setup(drive, block);
         self.write_command(Command::Read);
-        self.busy_loop();
-        for i in (0..256).step_by(2) {
+        self.busy_loop()?;
+        for i in 0..256 {
             let data = self.read_data();
-
-            //log!("Read[{:08X}][{:02X}]: 0x{:04X}\n", block, i, data);
-            buf[i + 0] = data.get_bits(0..8) as u8;
-            buf[i + 1] = data.get_bits(8..16) as u8;
+            buf[i * 2] = data.get_bits(0..8) as u8;
+            buf[i * 2 + 1] = data.get_bits(8..16) as u8;
         }
+        Ok(())
     }
 
     /// Write A single, 512-byte long slice to a given block
@@ -297,21 +366,30 @@ impl Bus {
     ///     write(0, 0, 0, &buffer);
     /// }
 
-    pub fn write(&mut self, drive: u8, block: BlockIndex, buf: &[u8]) {
+    /// Write the drive's cache to the disk.
+    pub fn flush(&mut self, drive: u8) -> Result<(), BlockError> {
+        self.select_drive(drive);
+        self.wait();
+        if matches!(self.status(), 0 | 0xFF) {
+            // No drive
+            return Ok(());
+        }
+        self.write_command(Command::FlushCache);
+        self.busy_loop()
+    }
+
+    pub fn write(&mut self, drive: u8, block: BlockIndex, buf: &[u8]) -> Result<(), BlockError> {
         assert!(buf.len() == 512);
         self.setup(drive, block);
         self.write_command(Command::Write);
-        self.busy_loop();
+        self.busy_loop()?;
         for i in 0..256 {
             let mut data = 0 as u16;
             data.set_bits(0..8, buf[i * 2] as u16);
             data.set_bits(8..16, buf[i * 2 + 1] as u16);
-
-            //log!("Data: 0x{:04X} | {}{}    \n", data, buf[i * 2] as char, buf[i * 2 + 1] as char);
-
             self.write_data(data);
         }
-        self.busy_loop();
+        self.busy_loop()
     }
 }
 
@@ -383,25 +461,78 @@ pub fn indentify_drive(bus: u8, drive: u8) -> Option<(u8, u8, String, String, u3
     }
 }
 
-pub fn read(bus: u8, drive: u8, block: BlockIndex, buf: &mut [u8]) {
+pub fn read(bus: u8, drive: u8, block: BlockIndex, buf: &mut [u8]) -> Result<(), BlockError> {
+    let mut buses = BUSES.lock();
+    trace!("Reading block {:#010x}", block);
+    buses[bus as usize].read(drive, block, buf)
+}
+
+pub fn write(bus: u8, drive: u8, block: BlockIndex, buf: &[u8]) -> Result<(), BlockError> {
     let mut buses = BUSES.lock();
-    //log!("Reading Block 0x{:08X}\n", block);
-    buses[bus as usize].read(drive, block, buf);
+    trace!("Writing block {:#010x}", block);
+    buses[bus as usize].write(drive, block, buf)
 }
 
-pub fn write(bus: u8, drive: u8, block: BlockIndex, buf: &[u8]) {
+/// Flush the write caches of all drives.
+pub fn flush() -> Result<(), BlockError> {
     let mut buses = BUSES.lock();
-    //log!("Writing Block 0x{:08X}\n", block);
-    buses[bus as usize].write(drive, block, buf);
+    for bus in buses.iter_mut() {
+        for drive in 0..2 {
+            bus.flush(drive)?;
+        }
+    }
+    Ok(())
 }
 
 pub fn drive_is_present(bus: usize) -> bool {
     unsafe { BUSES.lock()[bus].status_register.read() != 0xFF }
 }
 
+/// Programming interface bits saying a channel uses its BARs instead of the
+/// ISA compatibility ports.
+const PRIMARY_NATIVE: u8 = 1 << 0;
+const SECONDARY_NATIVE: u8 = 1 << 2;
+
+/// The ports of channel `channel` of an IDE controller: the command block
+/// and the control register, which sits 2 bytes into the control block.
+fn channel_ports(device: &pci::Device, channel: usize) -> (u16, u16, u8) {
+    let native = [PRIMARY_NATIVE, SECONDARY_NATIVE][channel];
+    if device.prog_if & native != 0 {
+        if let (pci::Bar::Io { port: io, .. }, pci::Bar::Io { port: ctrl, .. }) =
+            (device.bars[channel * 2], device.bars[channel * 2 + 1])
+        {
+            return (io, ctrl + 2, device.interrupt_line);
+        }
+    }
+    [(0x1F0, 0x3F6, 14), (0x170, 0x376, 15)][channel]
+}
+
+/// Take the first IDE controller on the PCI bus.
+fn probe(device: &pci::Device) -> bool {
+    let mut buses = BUSES.lock();
+    if !buses.is_empty() {
+        return false;
+    }
+    device.set_command(device.command() | pci::COMMAND_IO);
+    for channel in 0..2 {
+        let (io, ctrl, irq) = channel_ports(device, channel);
+        buses.push(Bus::new(channel as u8, io, ctrl, irq));
+    }
+    true
+}
+
 pub fn init() -> Result<(), ()> {
-    {
-        let mut buses = BUSES.lock();
+    pci::register_driver(pci::Driver {
+        name: "ata",
+        matches: &[pci::Match::Class {
+            class: 0x01,
+            subclass: 0x01,
+        }],
+        probe,
+    });
+    let mut buses = BUSES.lock();
+    if buses.is_empty() {
+        // no PCI IDE controller; try the ISA ports anyway
         buses.push(Bus::new(0, 0x1F0, 0x3F6, 14));
         buses.push(Bus::new(1, 0x170, 0x376, 15));
     }
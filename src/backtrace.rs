@@ -0,0 +1,222 @@
+//! Stack traces for panics and exceptions.
+//!
+//! The kernel is built with frame pointers, so the stack is walked by
+//! following the chain of saved `rbp`s. Return addresses are resolved to
+//! function names with the ELF symbol table the linker puts in the kernel
+//! image, which the bootloader loads into memory along with the rest of it.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::convert::TryInto;
+use core::fmt::{self, Write};
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+use crate::serial::SERIAL1;
+use crate::vga_buffer::WRITER;
+
+/// Stop walking after this many frames, in case the chain loops.
+const MAX_FRAMES: usize = 64;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+
+/// Section type of the symbol table
+const SHT_SYMTAB: u32 = 2;
+/// Symbol type of functions
+const STT_FUNC: u8 = 2;
+
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+static SYMBOLS: Once<SymbolTable<'static>> = Once::new();
+
+/// The `.symtab` and `.strtab` sections of the kernel image.
+struct SymbolTable<'a> {
+    symbols: &'a [u8],
+    names: &'a [u8],
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+impl<'a> SymbolTable<'a> {
+    fn parse(elf: &'a [u8]) -> Option<SymbolTable<'a>> {
+        if !elf.starts_with(ELF_MAGIC) || elf.get(4) != Some(&ELF_CLASS_64) {
+            return None;
+        }
+        let section_headers = u64_at(elf, 0x28)? as usize;
+        let count = usize::from(u16_at(elf, 0x3c)?);
+        let header = |index: usize| {
+            let start = section_headers.checked_add(index * SECTION_HEADER_SIZE)?;
+            elf.get(start..start.checked_add(SECTION_HEADER_SIZE)?)
+        };
+        let contents = |header: &[u8]| {
+            let offset = u64_at(header, 24)? as usize;
+            let size = u64_at(header, 32)? as usize;
+            elf.get(offset..offset.checked_add(size)?)
+        };
+        let symtab = (0..count)
+            .filter_map(header)
+            .find(|header| u32_at(header, 4) == Some(SHT_SYMTAB))?;
+        // the string table holding the names is linked from the header
+        let strtab = header(u32_at(symtab, 40)? as usize)?;
+        Some(SymbolTable {
+            symbols: contents(symtab)?,
+            names: contents(strtab)?,
+        })
+    }
+
+    /// The function containing `addr` and how far into it `addr` is.
+    fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+        let mut best: Option<(u64, u32)> = None;
+        for symbol in self.symbols.chunks_exact(SYMBOL_SIZE) {
+            if symbol[4] & 0xf != STT_FUNC {
+                continue;
+            }
+            let (name, value, size) = (u32_at(symbol, 0)?, u64_at(symbol, 8)?, u64_at(symbol, 16)?);
+            // symbols without a size are taken to reach up to the next one
+            let contains = value <= addr && (size == 0 || addr - value < size);
+            if contains && best.is_none_or(|(best, _)| value > best) {
+                best = Some((value, name));
+            }
+        }
+        let (value, name) = best?;
+        let name = self.names.get(name as usize..)?;
+        let end = name.iter().position(|&byte| byte == 0)?;
+        let name = core::str::from_utf8(&name[..end]).ok()?;
+        Some((name, addr - value))
+    }
+}
+
+/// Find the kernel image in the memory the bootloader loaded it to and
+/// read its symbol table. Without it, backtraces only show addresses.
+pub fn init(memory_map: &MemoryMap) {
+    let region = match memory_map
+        .iter()
+        .find(|region| region.region_type == MemoryRegionType::Kernel)
+    {
+        Some(region) => region,
+        None => return,
+    };
+    let start = memory::phys_to_virt(PhysAddr::new(region.range.start_addr()));
+    let len = (region.range.end_addr() - region.range.start_addr()) as usize;
+    let image: &'static [u8] = unsafe { core::slice::from_raw_parts(start.as_ptr(), len) };
+    // the image is only aligned to sectors, while the region is in pages
+    let elf = (0..len.min(4096))
+        .step_by(512)
+        .map(|offset| &image[offset..])
+        .find(|elf| elf.starts_with(ELF_MAGIC));
+    if let Some(symbols) = elf.and_then(SymbolTable::parse) {
+        SYMBOLS.call_once(|| symbols);
+    }
+}
+
+/// The return addresses of the frames above the one `rbp` points to,
+/// innermost first.
+pub fn frames(mut rbp: u64) -> impl Iterator<Item = u64> {
+    core::iter::from_fn(move || {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_readable(rbp) || !is_readable(rbp + 8) {
+            return None;
+        }
+        let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_address == 0 {
+            return None;
+        }
+        // callers' frames are further up the stack
+        rbp = if next > rbp { next } else { 0 };
+        Some(return_address)
+    })
+    .take(MAX_FRAMES)
+}
+
+fn is_readable(addr: u64) -> bool {
+    VirtAddr::try_new(addr).is_ok_and(memory::is_mapped)
+}
+
+/// Write to both the screen and the serial port, skipping either if it is
+/// locked by the code that was interrupted or panicked.
+pub fn emit(args: fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        if let Some(mut writer) = WRITER.try_lock() {
+            let _ = writer.write_fmt(args);
+        }
+        if let Some(mut serial) = SERIAL1.try_lock() {
+            let _ = serial.write_fmt(args);
+        }
+    });
+}
+
+/// Print the frame executing the instruction at `addr`.
+fn print_frame(index: usize, addr: u64) {
+    match SYMBOLS.get().and_then(|symbols| symbols.lookup(addr)) {
+        Some((name, offset)) => emit(format_args!(
+            "{:4}: {:#x}  {:#}+{:#x}\n",
+            index,
+            addr,
+            rustc_demangle::demangle(name),
+            offset
+        )),
+        None => emit(format_args!("{:4}: {:#x}\n", index, addr)),
+    }
+}
+
+/// Print the stack trace of the code that called this.
+#[inline(never)]
+pub fn print() {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp) };
+    emit(format_args!("Backtrace:\n"));
+    // return addresses point after the call, which may be the next function
+    for (index, addr) in frames(rbp).enumerate() {
+        print_frame(index, addr - 1);
+    }
+}
+
+/// Print the stack trace of code that was interrupted at `rip` with the
+/// frame pointer `rbp`.
+pub fn print_from(rip: u64, rbp: u64) {
+    emit(format_args!("Backtrace:\n"));
+    print_frame(0, rip);
+    for (index, addr) in frames(rbp).enumerate() {
+        print_frame(index + 1, addr - 1);
+    }
+}
+
+#[test_case]
+fn test_lookup() {
+    let mut symbols = [0; 3 * SYMBOL_SIZE];
+    let entries = [
+        (1u32, STT_FUNC, 0x1000u64, 0x10u64),
+        (7, STT_FUNC, 0x1010, 0),
+        // not a function
+        (14, 1, 0x1008, 8),
+    ];
+    for (symbol, (name, kind, value, size)) in symbols.chunks_exact_mut(SYMBOL_SIZE).zip(entries) {
+        symbol[0..4].copy_from_slice(&name.to_le_bytes());
+        symbol[4] = kind;
+        symbol[8..16].copy_from_slice(&value.to_le_bytes());
+        symbol[16..24].copy_from_slice(&size.to_le_bytes());
+    }
+    let table = SymbolTable {
+        symbols: &symbols,
+        names: b"\0first\0second\0data\0",
+    };
+    assert_eq!(table.lookup(0x1008), Some(("first", 8)));
+    assert_eq!(table.lookup(0x1020), Some(("second", 0x10)));
+    assert_eq!(table.lookup(0xfff), None);
+}
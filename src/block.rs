@@ -0,0 +1,182 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::println;
+use crate::shell::command::{self, Builtin, Handler};
+
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// No device with that name.
+    NotFound,
+    /// The blocks are past the end of the device.
+    OutOfRange,
+    ReadOnly,
+    /// The device reported an error or stopped responding.
+    Io,
+}
+
+/// A disk, read and written in blocks of `BLOCK_SIZE` bytes.
+pub trait BlockDevice: Send {
+    /// Short name, like `hdb` or `vda`.
+    fn name(&self) -> &str;
+    /// What the device says it is.
+    fn model(&self) -> &str;
+    fn block_count(&self) -> u64;
+    fn read_only(&self) -> bool {
+        false
+    }
+    /// Read `buf.len() / BLOCK_SIZE` blocks from `block` on.
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    /// Write `buf.len() / BLOCK_SIZE` blocks from `block` on.
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError>;
+    /// Make sure writes have reached the medium.
+    fn flush(&mut self) -> Result<(), BlockError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub model: String,
+    pub blocks: u64,
+    pub read_only: bool,
+}
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<Box<dyn BlockDevice>>> = Mutex::new(Vec::new());
+}
+
+/// Make a device available to the filesystems and the shell.
+pub fn register(device: Box<dyn BlockDevice>) {
+    log::info!(
+        "{}: {}, {} blocks{}",
+        device.name(),
+        device.model(),
+        device.block_count(),
+        if device.read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    DEVICES.lock().push(device);
+}
+
+/// The name of the `index`th device of a driver, like Linux does: `prefix`
+/// followed by `a` to `z`, then `aa`, `ab` and so on.
+pub fn device_name(prefix: &str, index: usize) -> String {
+    let mut suffix = Vec::new();
+    let mut n = index + 1;
+    while n > 0 {
+        n -= 1;
+        suffix.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    let mut name = String::from(prefix);
+    name.extend(suffix.iter().rev().map(|&letter| letter as char));
+    name
+}
+
+pub fn list() -> Vec<DeviceInfo> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|device| DeviceInfo {
+            name: String::from(device.name()),
+            model: String::from(device.model()),
+            blocks: device.block_count(),
+            read_only: device.read_only(),
+        })
+        .collect()
+}
+
+pub fn exists(name: &str) -> bool {
+    DEVICES.lock().iter().any(|device| device.name() == name)
+}
+
+fn with_device<T>(
+    name: &str,
+    f: impl FnOnce(&mut dyn BlockDevice) -> Result<T, BlockError>,
+) -> Result<T, BlockError> {
+    let mut devices = DEVICES.lock();
+    let device = devices
+        .iter_mut()
+        .find(|device| device.name() == name)
+        .ok_or(BlockError::NotFound)?;
+    f(device.as_mut())
+}
+
+fn check_range(device: &dyn BlockDevice, block: u64, len: usize) -> Result<(), BlockError> {
+    let blocks = (len / BLOCK_SIZE) as u64;
+    if len % BLOCK_SIZE != 0 || block + blocks > device.block_count() {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(())
+}
+
+pub fn read(name: &str, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    with_device(name, |device| {
+        check_range(device, block, buf.len())?;
+        device.read(block, buf)
+    })
+}
+
+pub fn write(name: &str, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+    with_device(name, |device| {
+        check_range(device, block, buf.len())?;
+        if device.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        device.write(block, buf)
+    })
+}
+
+/// Flush every device.
+pub fn flush() {
+    for device in DEVICES.lock().iter_mut() {
+        // nothing to do about a failure this late
+        let _ = device.flush();
+    }
+}
+
+fn format_size(blocks: u64) -> String {
+    let bytes = blocks * BLOCK_SIZE as u64;
+    if bytes >> 20 < 1000 {
+        alloc::format!("{} MB", bytes >> 20)
+    } else {
+        alloc::format!("{} GB", bytes >> 30)
+    }
+}
+
+pub fn init() {
+    command::register(Builtin {
+        name: "disks",
+        usage: "disks",
+        help: "List the disks.",
+        handler: Handler::Sync(|_| {
+            for device in list() {
+                println!(
+                    "{}: {} ({}{})",
+                    device.name,
+                    device.model,
+                    format_size(device.blocks),
+                    if device.read_only { ", read-only" } else { "" }
+                );
+            }
+            0
+        }),
+    });
+}
+
+#[test_case]
+fn test_device_name() {
+    assert_eq!(device_name("vd", 0), "vda");
+    assert_eq!(device_name("vd", 25), "vdz");
+    assert_eq!(device_name("vd", 26), "vdaa");
+    assert_eq!(device_name("vd", 27), "vdab");
+    assert_eq!(device_name("vd", 26 + 26 * 26), "vdaaa");
+}
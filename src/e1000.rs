@@ -0,0 +1,395 @@
+//! Intel 8254x (e1000) network cards, the default NIC of QEMU.
+//!
+//! Frames are copied through fixed buffers in legacy-format descriptor
+//! rings. Receive interrupts only wake the receiver; the rings themselves
+//! are handled from tasks.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+use crate::net::{self, MacAddress, NetError, NetworkDevice, Stats};
+use crate::pci::{self, Bar, Device};
+use crate::time::{Duration, Instant};
+
+const VENDOR: u16 = 0x8086;
+const DEVICES: [(u16, &str); 3] = [
+    (0x100e, "Intel 82540EM"),
+    (0x100f, "Intel 82545EM"),
+    (0x10d3, "Intel 82574L"),
+];
+
+// Registers
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const ICR: usize = 0x00c0;
+const IMS: usize = 0x00d0;
+const IMC: usize = 0x00d8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+/// Multicast table array, 128 registers
+const MTA: usize = 0x5200;
+const RAL: usize = 0x5400;
+const RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+const RAH_AV: u32 = 1 << 31;
+
+const RCTL_EN: u32 = 1 << 1;
+/// Accept broadcasts
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the CRC
+const RCTL_SECRC: u32 = 1 << 26;
+const TCTL_EN: u32 = 1 << 1;
+/// Pad short packets
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// Recommended inter-packet gap for copper
+const TIPG_VALUE: u32 = 10 | 8 << 10 | 6 << 20;
+
+// Interrupt causes
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXDMT0: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT0: u32 = 1 << 7;
+
+const DESC_DD: u8 = 1 << 0;
+const DESC_EOP: u8 = 1 << 1;
+const CMD_EOP: u8 = 1 << 0;
+const CMD_IFCS: u8 = 1 << 1;
+const CMD_RS: u8 = 1 << 3;
+
+const PAGE_SIZE: usize = 4096;
+/// Descriptors per ring, a multiple of 8.
+const RING_SIZE: usize = 32;
+/// The default RCTL.BSIZE
+const BUFFER_SIZE: usize = 2048;
+
+/// Register bases of the cards, for the interrupt handler.
+static BASES: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn read_mmio(base: VirtAddr, offset: usize) -> u32 {
+    ptr::read_volatile((base.as_u64() as usize + offset) as *const u32)
+}
+
+unsafe fn write_mmio(base: VirtAddr, offset: usize, value: u32) {
+    ptr::write_volatile((base.as_u64() as usize + offset) as *mut u32, value)
+}
+
+/// A descriptor, in the legacy layout shared by both rings. The receive
+/// ring uses `cso` as the low byte of its checksum and `cmd` as the high
+/// one.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+/// A ring of descriptors with a buffer each.
+struct Ring {
+    descriptors: PhysAddr,
+    buffers: PhysAddr,
+    /// Next descriptor to look at.
+    next: usize,
+}
+
+impl Ring {
+    fn new() -> Option<Ring> {
+        let ring = Ring {
+            descriptors: memory::allocate_dma(1)?,
+            buffers: memory::allocate_dma(RING_SIZE * BUFFER_SIZE / PAGE_SIZE)?,
+            next: 0,
+        };
+        for i in 0..RING_SIZE {
+            ring.set(
+                i,
+                Descriptor {
+                    addr: ring.buffer(i).as_u64(),
+                    length: 0,
+                    cso: 0,
+                    cmd: 0,
+                    status: 0,
+                    css: 0,
+                    special: 0,
+                },
+            );
+        }
+        Some(ring)
+    }
+
+    fn buffer(&self, i: usize) -> PhysAddr {
+        self.buffers + (i * BUFFER_SIZE) as u64
+    }
+
+    fn descriptor(&self, i: usize) -> *mut Descriptor {
+        (memory::phys_to_virt(self.descriptors).as_u64() as usize + i * 16) as *mut Descriptor
+    }
+
+    fn get(&self, i: usize) -> Descriptor {
+        unsafe { ptr::read_volatile(self.descriptor(i)) }
+    }
+
+    fn set(&self, i: usize, descriptor: Descriptor) {
+        unsafe { ptr::write_volatile(self.descriptor(i), descriptor) }
+    }
+}
+
+struct E1000 {
+    name: String,
+    model: &'static str,
+    registers: VirtAddr,
+    mac: MacAddress,
+    rx: Ring,
+    tx: Ring,
+    stats: Stats,
+}
+
+impl E1000 {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { read_mmio(self.registers, register) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { write_mmio(self.registers, register, value) }
+    }
+
+    fn new(registers: VirtAddr, model: &'static str) -> Option<E1000> {
+        let mut card = E1000 {
+            name: format!("eth{}", COUNT.load(Ordering::Relaxed)),
+            model,
+            registers,
+            mac: MacAddress([0; 6]),
+            rx: Ring::new()?,
+            tx: Ring::new()?,
+            stats: Stats::default(),
+        };
+
+        card.write(IMC, !0);
+        card.write(CTRL, card.read(CTRL) | CTRL_RST);
+        let start = Instant::now();
+        while card.read(CTRL) & CTRL_RST != 0 {
+            if start.elapsed() > Duration::from_millis(100) {
+                return None;
+            }
+            spin_loop();
+        }
+        card.write(IMC, !0);
+        card.read(ICR);
+        card.write(CTRL, card.read(CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        // the EEPROM is loaded into the first receive address at reset
+        let (low, high) = (card.read(RAL), card.read(RAH));
+        if high & RAH_AV == 0 {
+            return None;
+        }
+        let mut mac = [0; 6];
+        mac[..4].copy_from_slice(&low.to_le_bytes());
+        mac[4..].copy_from_slice(&high.to_le_bytes()[..2]);
+        card.mac = MacAddress(mac);
+        for i in 0..128 {
+            card.write(MTA + i * 4, 0);
+        }
+
+        let ring_length = (RING_SIZE * 16) as u32;
+        let rx = card.rx.descriptors.as_u64();
+        card.write(RDBAL, rx as u32);
+        card.write(RDBAH, (rx >> 32) as u32);
+        card.write(RDLEN, ring_length);
+        card.write(RDH, 0);
+        // every descriptor but one belongs to the card
+        card.write(RDT, RING_SIZE as u32 - 1);
+        card.write(RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        // mark the transmit descriptors as done so they can be used
+        for i in 0..RING_SIZE {
+            let mut descriptor = card.tx.get(i);
+            descriptor.status = DESC_DD;
+            card.tx.set(i, descriptor);
+        }
+        let tx = card.tx.descriptors.as_u64();
+        card.write(TDBAL, tx as u32);
+        card.write(TDBAH, (tx >> 32) as u32);
+        card.write(TDLEN, ring_length);
+        card.write(TDH, 0);
+        card.write(TDT, 0);
+        card.write(TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        card.write(TIPG, TIPG_VALUE);
+
+        COUNT.fetch_add(1, Ordering::Relaxed);
+        Some(card)
+    }
+}
+
+impl NetworkDevice for E1000 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model(&self) -> &str {
+        self.model
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.read(STATUS) & STATUS_LU != 0
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > BUFFER_SIZE {
+            return Err(NetError::TooLong);
+        }
+        let i = self.tx.next;
+        let mut descriptor = self.tx.get(i);
+        if descriptor.status & DESC_DD == 0 {
+            return Err(NetError::Busy);
+        }
+        let buffer = memory::phys_to_virt(self.tx.buffer(i)).as_mut_ptr::<u8>();
+        unsafe { ptr::copy_nonoverlapping(frame.as_ptr(), buffer, frame.len()) };
+        descriptor.length = frame.len() as u16;
+        descriptor.cmd = CMD_EOP | CMD_IFCS | CMD_RS;
+        descriptor.status = 0;
+        self.tx.set(i, descriptor);
+        self.tx.next = (i + 1) % RING_SIZE;
+        self.write(TDT, self.tx.next as u32);
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += frame.len() as u64;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let i = self.rx.next;
+            let mut descriptor = self.rx.get(i);
+            if descriptor.status & DESC_DD == 0 {
+                return None;
+            }
+            // frames never span buffers, as they fit in one
+            let frame = if descriptor.status & DESC_EOP != 0 && descriptor.css == 0 {
+                let length = descriptor.length as usize;
+                let buffer = memory::phys_to_virt(self.rx.buffer(i)).as_ptr::<u8>();
+                let mut frame = alloc::vec![0; length];
+                unsafe { ptr::copy_nonoverlapping(buffer, frame.as_mut_ptr(), length) };
+                Some(frame)
+            } else {
+                None
+            };
+            // hand the descriptor back
+            descriptor.status = 0;
+            self.rx.set(i, descriptor);
+            self.write(RDT, i as u32);
+            self.rx.next = (i + 1) % RING_SIZE;
+            if let Some(frame) = frame {
+                self.stats.rx_packets += 1;
+                self.stats.rx_bytes += frame.len() as u64;
+                return Some(frame);
+            }
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+/// Acknowledge the interrupts of all cards and wake the receiver.
+fn handle_interrupt() {
+    for base in &BASES {
+        let base = base.load(Ordering::Relaxed);
+        if base == 0 {
+            continue;
+        }
+        // reading the cause register clears it
+        let cause = unsafe { read_mmio(VirtAddr::new(base), ICR) };
+        if cause & (ICR_RXT0 | ICR_RXO | ICR_RXDMT0) != 0 {
+            net::wake_receiver();
+        }
+    }
+}
+
+fn probe(device: &Device) -> bool {
+    let model = match DEVICES.iter().find(|(id, _)| *id == device.device_id) {
+        Some((_, model)) => *model,
+        None => return false,
+    };
+    let (address, size) = match device.bars[0] {
+        Bar::Memory { address, size, .. } => (address, size),
+        _ => return false,
+    };
+    let registers = match memory::map_mmio(PhysAddr::new(address), size) {
+        Ok(registers) => registers,
+        Err(_) => return false,
+    };
+    device.enable();
+    let card = match E1000::new(registers, model) {
+        Some(card) => card,
+        None => return false,
+    };
+    if let Some(slot) = BASES.iter().find(|base| base.load(Ordering::Relaxed) == 0) {
+        slot.store(registers.as_u64(), Ordering::Relaxed);
+        // the line is only right where PCI interrupts are routed to ISA
+        // IRQs, see `set_irq_handler`; elsewhere the stack polls the card
+        if crate::interrupts::set_irq_handler(device.interrupt_line, handle_interrupt) {
+            card.write(IMS, ICR_LSC | ICR_RXDMT0 | ICR_RXO | ICR_RXT0);
+        }
+    }
+    net::register(Box::new(card));
+    true
+}
+
+pub fn init() {
+    let matches: &'static [pci::Match] = &[
+        pci::Match::Id {
+            vendor: VENDOR,
+            device: DEVICES[0].0,
+        },
+        pci::Match::Id {
+            vendor: VENDOR,
+            device: DEVICES[1].0,
+        },
+        pci::Match::Id {
+            vendor: VENDOR,
+            device: DEVICES[2].0,
+        },
+    ];
+    pci::register_driver(pci::Driver {
+        name: "e1000",
+        matches,
+        probe,
+    });
+}
@@ -0,0 +1,191 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::block::{self, BlockError, BLOCK_SIZE};
+use crate::rtc::{self, DateTime};
+use crate::simplefs::{self, pack, unpack};
+
+/// Disks that may hold the simplefs image, most preferred first: a virtio
+/// disk if one is attached, otherwise the second IDE or SATA disk. Disks
+/// that hold something else are skipped.
+pub const FS_DEVICES: [&str; 3] = ["vda", "hdb", "sdb"];
+/// The image starts at block 0 and is 2048 blocks (1M) long.
+pub const FS_BLOCKS: usize = 2048;
+
+/// Files below this prefix only live in memory and are lost on reboot.
+pub const TMP_PREFIX: &str = "/tmp/";
+
+/// simplefs has no room for metadata, so modification times of the files
+/// on disk are kept in this file as lines of `NAME UNIX_TIME`.
+pub const TIMES_FILE: &str = ".mtimes";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NoSpace,
+    /// There is no disk, or it failed.
+    Disk(BlockError),
+}
+
+lazy_static! {
+    static ref TMP: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+    static ref TMP_TIMES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+    /// The disk found by `device`.
+    static ref DEVICE: Mutex<Option<&'static str>> = Mutex::new(None);
+}
+
+fn is_tmp(name: &str) -> bool {
+    name.starts_with(TMP_PREFIX)
+}
+
+/// The disk the image is on: the first of `FS_DEVICES` that holds a valid
+/// image, so that files are never written over another disk's contents.
+pub fn device() -> Option<&'static str> {
+    let mut device = DEVICE.lock();
+    if device.is_none() {
+        *device = FS_DEVICES
+            .iter()
+            .copied()
+            .find(|name| read_image(name).is_some_and(|image| simplefs::is_valid(&image)));
+    }
+    *device
+}
+
+fn read_image(device: &str) -> Option<Vec<u8>> {
+    let mut image = alloc::vec![0; FS_BLOCKS * BLOCK_SIZE];
+    block::read(device, 0, &mut image).ok()?;
+    Some(image)
+}
+
+fn read_disk() -> Vec<(String, Vec<u8>)> {
+    match device().and_then(read_image) {
+        Some(image) => unpack(image),
+        None => Vec::new(),
+    }
+}
+
+fn write_disk(files: Vec<(String, Vec<u8>)>) -> Result<(), FsError> {
+    let device = device().ok_or(FsError::Disk(BlockError::NotFound))?;
+    let mut image = pack(files);
+    // terminate the file list and pad to whole blocks
+    image.push(0);
+    if image.len() > FS_BLOCKS * BLOCK_SIZE {
+        return Err(FsError::NoSpace);
+    }
+    let blocks = (image.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    image.resize(blocks * BLOCK_SIZE, 0);
+    block::write(device, 0, &image).map_err(FsError::Disk)
+}
+
+fn parse_times(data: &[u8]) -> BTreeMap<String, u64> {
+    String::from_utf8_lossy(data)
+        .lines()
+        .filter_map(|line| {
+            let (name, time) = line.rsplit_once(' ')?;
+            Some((String::from(name), time.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Record that `name` was modified now in the file list of the disk.
+fn touch(files: &mut Vec<(String, Vec<u8>)>, name: &str) {
+    let mut times = match files.iter().find(|file| file.0 == TIMES_FILE) {
+        Some(file) => parse_times(&file.1),
+        None => BTreeMap::new(),
+    };
+    times.insert(String::from(name), rtc::unix_time().as_secs());
+    let mut data = String::new();
+    for (name, time) in times {
+        // forget files that are gone
+        if files.iter().any(|file| file.0 == name) {
+            data.push_str(&format!("{} {}\n", name, time));
+        }
+    }
+    match files.iter_mut().find(|file| file.0 == TIMES_FILE) {
+        Some(file) => file.1 = data.into_bytes(),
+        None => files.push((String::from(TIMES_FILE), data.into_bytes())),
+    }
+}
+
+/// Names of all files on disk followed by those in `/tmp`. Files starting
+/// with a dot, like `.mtimes` and `.history`, are hidden.
+pub fn list() -> Vec<String> {
+    let mut names: Vec<String> = read_disk()
+        .into_iter()
+        .map(|file| file.0)
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    names.extend(TMP.lock().keys().cloned());
+    names
+}
+
+/// The files of `list` with their contents, reading the disk only once.
+pub fn read_all() -> Vec<(String, Vec<u8>)> {
+    let mut files: Vec<(String, Vec<u8>)> = read_disk()
+        .into_iter()
+        .filter(|file| !file.0.starts_with('.'))
+        .collect();
+    files.extend(
+        TMP.lock()
+            .iter()
+            .map(|(name, data)| (name.clone(), data.clone())),
+    );
+    files
+}
+
+pub fn read(name: &str) -> Result<Vec<u8>, FsError> {
+    if is_tmp(name) {
+        return TMP.lock().get(name).cloned().ok_or(FsError::NotFound);
+    }
+    read_disk()
+        .into_iter()
+        .find(|file| file.0 == name)
+        .map(|file| file.1)
+        .ok_or(FsError::NotFound)
+}
+
+/// Create or replace `name` with `contents`.
+pub fn write(name: &str, contents: Vec<u8>) -> Result<(), FsError> {
+    if is_tmp(name) {
+        TMP.lock().insert(String::from(name), contents);
+        TMP_TIMES
+            .lock()
+            .insert(String::from(name), rtc::unix_time().as_secs());
+        return Ok(());
+    }
+    let mut files = read_disk();
+    match files.iter_mut().find(|file| file.0 == name) {
+        Some(file) => file.1 = contents,
+        None => files.push((String::from(name), contents)),
+    }
+    if name != TIMES_FILE {
+        touch(&mut files, name);
+    }
+    write_disk(files)
+}
+
+/// When `name` was last written, if known.
+pub fn modified(name: &str) -> Option<DateTime> {
+    let time = if is_tmp(name) {
+        TMP_TIMES.lock().get(name).copied()
+    } else {
+        let times = parse_times(&read(TIMES_FILE).ok()?);
+        times.get(name).copied()
+    }?;
+    Some(DateTime::from_unix(time))
+}
+
+/// Append `contents` to `name`, creating it if it doesn't exist.
+pub fn append(name: &str, contents: &[u8]) -> Result<(), FsError> {
+    let mut data = match read(name) {
+        Ok(data) => data,
+        Err(FsError::NotFound) => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    data.extend_from_slice(contents);
+    write(name, data)
+}
@@ -0,0 +1,503 @@
+//! A stub for GDB's remote serial protocol on COM2, so the kernel can be
+//! debugged on any machine with a serial port, not just under QEMU's
+//! gdbstub.
+//!
+//! The `debug` command activates the stub and stops in a breakpoint, after
+//! which breakpoints and single steps trap into it until GDB detaches. It
+//! polls the port with interrupts off, so the rest of the kernel stands
+//! still while stopped. GDB can't interrupt a running kernel with Ctrl-C;
+//! set breakpoints before continuing instead.
+//!
+//! Under QEMU, connect COM2 to a socket with
+//! `-serial stdio -serial tcp::1234,server,nowait` and in GDB run
+//! `target remote :1234`.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::VirtAddr;
+
+use crate::interrupts::TrapFrame;
+use crate::memory;
+use crate::println;
+use crate::shell::command::{self, Builtin, Handler};
+
+const COM2: u16 = 0x2F8;
+
+/// UART registers, as offsets from the base port
+const DATA: u16 = 0;
+const LINE_STATUS: u16 = 5;
+
+const LSR_DATA_READY: u8 = 0x01;
+
+/// Single step: trap after the next instruction.
+pub const TRAP_FLAG: u64 = 1 << 8;
+
+/// The longest packet we take or send, told to GDB in `qSupported`.
+const PACKET_SIZE: usize = 4096;
+
+/// The stop reply: stopped by SIGTRAP.
+const STOP_REPLY: &str = "S05";
+
+/// Whether traps go to the stub.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether GDB resumed the kernel and waits for a stop reply.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref COM2_PORT: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM2) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+    /// Packet buffers, kept out of the stack and the heap since the stub
+    /// runs in exception handlers.
+    static ref BUFFERS: Mutex<Buffers> = Mutex::new(Buffers {
+        packet: [0; PACKET_SIZE],
+        reply: Reply {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        },
+    });
+}
+
+struct Buffers {
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+}
+
+/// A reply being built.
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(HEX_DIGITS[usize::from(byte >> 4)]);
+            self.push(HEX_DIGITS[usize::from(byte & 0xf)]);
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl fmt::Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Parse a hexadecimal number like the `addr` and `length` of packets.
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0, |value, &byte| {
+        Some(value << 4 | u64::from(hex_digit(byte)?))
+    })
+}
+
+/// Decode pairs of hex digits into `buf`, returning how many bytes there
+/// were.
+fn decode_hex(hex: &[u8], buf: &mut [u8]) -> Option<usize> {
+    if !hex.len().is_multiple_of(2) || hex.len() / 2 > buf.len() {
+        return None;
+    }
+    for (byte, pair) in buf.iter_mut().zip(hex.chunks(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(hex.len() / 2)
+}
+
+fn read_byte() -> u8 {
+    let mut line_status = Port::<u8>::new(COM2 + LINE_STATUS);
+    let mut data = Port::<u8>::new(COM2 + DATA);
+    loop {
+        if unsafe { line_status.read() } & LSR_DATA_READY != 0 {
+            return unsafe { data.read() };
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn write_bytes(bytes: &[u8]) {
+    let mut port = COM2_PORT.lock();
+    for &byte in bytes {
+        port.send(byte);
+    }
+}
+
+/// Wait for a packet with a good checksum, acknowledge it and return its
+/// length.
+fn read_packet(packet: &mut [u8]) -> usize {
+    loop {
+        while read_byte() != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        loop {
+            match read_byte() {
+                b'#' => break,
+                // a packet starting over after a lost byte
+                b'$' => {
+                    len = 0;
+                    sum = 0;
+                }
+                byte => {
+                    sum = sum.wrapping_add(byte);
+                    if len < packet.len() {
+                        packet[len] = byte;
+                        len += 1;
+                    }
+                }
+            }
+        }
+        let checksum = [read_byte(), read_byte()];
+        if parse_hex(&checksum) == Some(u64::from(sum)) {
+            write_bytes(b"+");
+            return len;
+        }
+        write_bytes(b"-");
+    }
+}
+
+/// Send a packet until GDB acknowledges it.
+fn write_packet(data: &[u8]) {
+    let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    loop {
+        write_bytes(b"$");
+        write_bytes(data);
+        write_bytes(&[
+            b'#',
+            HEX_DIGITS[usize::from(sum >> 4)],
+            HEX_DIGITS[usize::from(sum & 0xf)],
+        ]);
+        match read_byte() {
+            b'-' => continue,
+            _ => return,
+        }
+    }
+}
+
+/// Whether traps go to GDB.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Activate the stub and stop in it, returning once GDB continues or
+/// detaches.
+pub fn breakpoint() {
+    ACTIVE.store(true, Ordering::Relaxed);
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Registers in GDB's amd64 order: the general purpose registers and rip
+/// are 8 bytes long, eflags and the segment registers 4.
+const REGISTER_COUNT: usize = 24;
+
+fn register_size(number: usize) -> usize {
+    if number <= 16 {
+        8
+    } else {
+        4
+    }
+}
+
+fn read_register(frame: &TrapFrame, number: usize) -> u64 {
+    match number {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        // ds, es, fs and gs aren't used in long mode
+        _ => 0,
+    }
+}
+
+/// Change a register of the stopped code. The segment registers stay as
+/// they are.
+fn write_register(frame: &mut TrapFrame, number: usize, value: u64) {
+    let register = match number {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        _ => return,
+    };
+    *register = value;
+}
+
+/// Whether all of `len` bytes at `addr` can be accessed.
+fn is_accessible(addr: u64, len: u64) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let mut page = addr & !0xfff;
+    while page < end {
+        match VirtAddr::try_new(page) {
+            Ok(page) if memory::is_mapped(page) => {}
+            _ => return false,
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+/// Parse `addr,length` of the memory packets.
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let comma = args.iter().position(|&byte| byte == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+/// What to do after a packet.
+enum Action {
+    Reply,
+    Resume { step: bool },
+    Detach,
+    /// GDB doesn't wait for a reply to `k`. The kernel can't be killed, so
+    /// this detaches as well.
+    Kill,
+}
+
+/// Talk to GDB until it resumes the code that trapped.
+pub fn handle_trap(frame: &mut TrapFrame) {
+    // the stub may have stopped anything, including holders of these locks,
+    // but nothing else runs while it does
+    let mut buffers = match BUFFERS.try_lock() {
+        Some(buffers) => buffers,
+        None => return,
+    };
+    let Buffers { packet, reply } = &mut *buffers;
+    frame.rflags &= !TRAP_FLAG;
+    if RUNNING.swap(false, Ordering::Relaxed) {
+        write_packet(STOP_REPLY.as_bytes());
+    }
+    loop {
+        let len = read_packet(packet);
+        reply.clear();
+        match handle_packet(frame, &packet[..len], reply) {
+            Action::Reply => write_packet(reply.as_bytes()),
+            Action::Resume { step } => {
+                if step {
+                    frame.rflags |= TRAP_FLAG;
+                }
+                RUNNING.store(true, Ordering::Relaxed);
+                return;
+            }
+            Action::Detach => {
+                write_packet(b"OK");
+                ACTIVE.store(false, Ordering::Relaxed);
+                return;
+            }
+            Action::Kill => {
+                ACTIVE.store(false, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+fn handle_packet(frame: &mut TrapFrame, packet: &[u8], reply: &mut Reply) -> Action {
+    let (&kind, args) = match packet.split_first() {
+        Some(split) => split,
+        None => return Action::Reply,
+    };
+    match kind {
+        b'?' => {
+            let _ = reply.write_str(STOP_REPLY);
+        }
+        b'g' => {
+            for number in 0..REGISTER_COUNT {
+                let value = read_register(frame, number).to_le_bytes();
+                reply.push_hex(&value[..register_size(number)]);
+            }
+        }
+        b'G' => {
+            let mut offset = 0;
+            for number in 0..REGISTER_COUNT {
+                let size = register_size(number);
+                let hex = match args.get(offset..offset + size * 2) {
+                    Some(hex) => hex,
+                    // GDB may send fewer registers than it knows of
+                    None => break,
+                };
+                let mut value = [0; 8];
+                if decode_hex(hex, &mut value[..size]).is_none() {
+                    return error(reply);
+                }
+                write_register(frame, number, u64::from_le_bytes(value));
+                offset += size * 2;
+            }
+            let _ = reply.write_str("OK");
+        }
+        b'p' => match parse_hex(args).map(|number| number as usize) {
+            Some(number) if number < REGISTER_COUNT => {
+                let value = read_register(frame, number).to_le_bytes();
+                reply.push_hex(&value[..register_size(number)]);
+            }
+            _ => return error(reply),
+        },
+        b'P' => {
+            let equals = match args.iter().position(|&byte| byte == b'=') {
+                Some(equals) => equals,
+                None => return error(reply),
+            };
+            let number = match parse_hex(&args[..equals]) {
+                Some(number) if (number as usize) < REGISTER_COUNT => number as usize,
+                _ => return error(reply),
+            };
+            let mut value = [0; 8];
+            match decode_hex(&args[equals + 1..], &mut value[..register_size(number)]) {
+                Some(_) => write_register(frame, number, u64::from_le_bytes(value)),
+                None => return error(reply),
+            }
+            let _ = reply.write_str("OK");
+        }
+        b'm' => {
+            let (addr, len) = match parse_range(args) {
+                Some((addr, len)) if len <= (PACKET_SIZE / 2) as u64 => (addr, len),
+                _ => return error(reply),
+            };
+            if !is_accessible(addr, len) {
+                return error(reply);
+            }
+            let memory = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+            reply.push_hex(memory);
+        }
+        b'M' => {
+            let colon = match args.iter().position(|&byte| byte == b':') {
+                Some(colon) => colon,
+                None => return error(reply),
+            };
+            let (addr, len) = match parse_range(&args[..colon]) {
+                Some((addr, len)) if len <= (PACKET_SIZE / 2) as u64 => (addr, len as usize),
+                _ => return error(reply),
+            };
+            let mut data = [0; PACKET_SIZE / 2];
+            if decode_hex(&args[colon + 1..], &mut data) != Some(len) {
+                return error(reply);
+            }
+            if !is_accessible(addr, len as u64) {
+                return error(reply);
+            }
+            write_memory(addr, &data[..len]);
+            let _ = reply.write_str("OK");
+        }
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                frame.rip = addr;
+            }
+            return Action::Resume { step: kind == b's' };
+        }
+        b'D' => return Action::Detach,
+        b'k' => return Action::Kill,
+        b'H' | b'T' => {
+            let _ = reply.write_str("OK");
+        }
+        b'q' if args.starts_with(b"Supported") => {
+            let _ = write!(reply, "PacketSize={:x}", PACKET_SIZE);
+        }
+        b'q' if args == b"Attached" => reply.push(b'1'),
+        // everything else is unsupported, which an empty reply says
+        _ => {}
+    }
+    Action::Reply
+}
+
+/// Reply with an error, EFAULT since that's what most of them are.
+fn error(reply: &mut Reply) -> Action {
+    reply.clear();
+    let _ = reply.write_str("E0e");
+    Action::Reply
+}
+
+/// Write to memory that may be read-only, like code GDB sets breakpoints
+/// in.
+fn write_memory(addr: u64, data: &[u8]) {
+    let flags = Cr0::read();
+    unsafe {
+        Cr0::write(flags - Cr0Flags::WRITE_PROTECT);
+        core::ptr::copy_nonoverlapping(data.as_ptr(), addr as *mut u8, data.len());
+        Cr0::write(flags);
+    }
+}
+
+pub fn init() {
+    // `read_byte` polls the port without locking it, so it must be set up
+    // before the first trap
+    lazy_static::initialize(&COM2_PORT);
+    command::register(Builtin {
+        name: "debug",
+        usage: "debug",
+        help: "Stop in a breakpoint and wait for GDB on COM2 (target remote\n\
+               with the port COM2 is connected to). Returns once GDB\n\
+               continues or detaches.",
+        handler: Handler::Sync(debug),
+    });
+}
+
+fn debug(_args: &[String]) -> i32 {
+    println!("Waiting for GDB on COM2");
+    breakpoint();
+    0
+}
@@ -36,18 +36,27 @@ pub fn init() {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// NMIs and machine checks can arrive at any time, even while the stack is
+/// being switched.
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+/// A stack segment fault usually means the stack pointer is bad.
+pub const STACK_SEGMENT_IST_INDEX: u16 = 3;
+
+const IST_STACKS: usize = 4;
+const STACK_SIZE: usize = 4096 * 5;
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        static mut STACKS: [[u8; STACK_SIZE]; IST_STACKS] = [[0; STACK_SIZE]; IST_STACKS];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stacks = unsafe { &*core::ptr::addr_of!(STACKS) };
+        for (index, stack) in stacks.iter().enumerate() {
+            let stack_start = VirtAddr::from_ptr(stack);
             let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+            tss.interrupt_stack_table[index] = stack_end;
+        }
         tss
     };
 }
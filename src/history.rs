@@ -0,0 +1,143 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::fs::{self, FsError};
+
+/// Number of lines kept in the history ring.
+pub const HISTORY_SIZE: usize = 100;
+
+/// History is saved to this file once it exists, see `save`.
+pub const HISTORY_FILE: &str = ".history";
+
+/// Every write rewrites the whole disk image, so new lines are only saved
+/// once this many have piled up, or by `flush`.
+const SAVE_INTERVAL: usize = 10;
+
+struct History {
+    entries: VecDeque<String>,
+    persistent: bool,
+    /// Lines added since the history file was last written.
+    unsaved: usize,
+}
+
+lazy_static! {
+    static ref HISTORY: Mutex<History> = Mutex::new(History {
+        entries: VecDeque::with_capacity(HISTORY_SIZE),
+        persistent: false,
+        unsaved: 0,
+    });
+}
+
+impl History {
+    /// The contents of the history file, one entry per line.
+    fn file(&self) -> Vec<u8> {
+        let mut data = String::new();
+        for entry in self.entries.iter() {
+            data.push_str(entry);
+            data.push('\n');
+        }
+        data.into_bytes()
+    }
+}
+
+/// Add a line to the history, skipping empty lines and repeats of the last
+/// line, and save the history every few lines if persistence is enabled.
+pub fn push(line: &str) {
+    let line = line.trim();
+    let data = {
+        let mut history = HISTORY.lock();
+        if line.is_empty() || history.entries.back().map(String::as_str) == Some(line) {
+            return;
+        }
+        if history.entries.len() == HISTORY_SIZE {
+            history.entries.pop_front();
+        }
+        history.entries.push_back(String::from(line));
+        history.unsaved += 1;
+        if !history.persistent || history.unsaved < SAVE_INTERVAL {
+            return;
+        }
+        history.unsaved = 0;
+        history.file()
+    };
+    // losing history isn't worth bothering the user about
+    let _ = fs::write(HISTORY_FILE, data);
+}
+
+/// Save lines that haven't been yet, before shutting down.
+pub fn flush() {
+    let data = {
+        let mut history = HISTORY.lock();
+        if !history.persistent || history.unsaved == 0 {
+            return;
+        }
+        history.unsaved = 0;
+        history.file()
+    };
+    let _ = fs::write(HISTORY_FILE, data);
+}
+
+/// The entry `index` lines back, 0 being the newest.
+pub fn get(index: usize) -> Option<String> {
+    let history = HISTORY.lock();
+    let len = history.entries.len();
+    if index < len {
+        history.entries.get(len - 1 - index).cloned()
+    } else {
+        None
+    }
+}
+
+/// Index of the newest entry at or before `from` lines back that contains
+/// `query`.
+pub fn search(query: &str, from: usize) -> Option<usize> {
+    let history = HISTORY.lock();
+    history
+        .entries
+        .iter()
+        .rev()
+        .enumerate()
+        .skip(from)
+        .find(|(_, entry)| entry.contains(query))
+        .map(|(index, _)| index)
+}
+
+/// All entries, oldest first.
+pub fn entries() -> Vec<String> {
+    HISTORY.lock().entries.iter().cloned().collect()
+}
+
+pub fn clear() {
+    HISTORY.lock().entries.clear();
+}
+
+/// Load the history file if it exists and keep saving to it.
+pub fn load() -> bool {
+    let data = match fs::read(HISTORY_FILE) {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+    let text = String::from_utf8_lossy(&data);
+    let mut history = HISTORY.lock();
+    for line in text.lines() {
+        if history.entries.len() == HISTORY_SIZE {
+            history.entries.pop_front();
+        }
+        history.entries.push_back(String::from(line));
+    }
+    history.persistent = true;
+    true
+}
+
+/// Write the history to the history file, saving new lines from then on.
+pub fn save() -> Result<(), FsError> {
+    let data = HISTORY.lock().file();
+    fs::write(HISTORY_FILE, data)?;
+    let mut history = HISTORY.lock();
+    history.persistent = true;
+    history.unsaved = 0;
+    Ok(())
+}
@@ -1,11 +1,18 @@
+use crate::apic;
+use crate::backtrace;
 use crate::gdt;
-use crate::hlt_loop;
 use crate::print;
 use crate::println;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{
+    Entry, EntryOptions, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    SelectorErrorCode,
+};
+use x86_64::VirtAddr;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -13,11 +20,15 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Where the primary PIC sends spurious interrupts (IRQ 7).
+const PIC_SPURIOUS_VECTOR: u8 = PIC_1_OFFSET + 7;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard, // new
+    Rtc = PIC_2_OFFSET,
 }
 
 impl InterruptIndex {
@@ -33,44 +44,180 @@ impl InterruptIndex {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        set_trap_entry(&mut idt.divide_error, divide_error_entry);
+        set_trap_entry(&mut idt.debug, debug_entry);
+        unsafe {
+            set_trap_entry(&mut idt.non_maskable_interrupt, nmi_entry)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+        }
+        set_trap_entry(&mut idt.breakpoint, breakpoint_entry);
+        set_trap_entry(&mut idt.overflow, overflow_entry);
+        set_trap_entry(&mut idt.bound_range_exceeded, bound_range_entry);
+        set_trap_entry(&mut idt.invalid_opcode, invalid_opcode_entry);
+        set_trap_entry(&mut idt.device_not_available, device_not_available_entry);
         unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
+            set_trap_entry(&mut idt.double_fault, double_fault_entry)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        set_trap_entry(&mut idt.invalid_tss, invalid_tss_entry);
+        set_trap_entry(&mut idt.segment_not_present, segment_not_present_entry);
+        unsafe {
+            set_trap_entry(&mut idt.stack_segment_fault, stack_segment_entry)
+                .set_stack_index(gdt::STACK_SEGMENT_IST_INDEX);
+        }
+        set_trap_entry(&mut idt.general_protection_fault, general_protection_entry);
+        set_trap_entry(&mut idt.page_fault, page_fault_entry);
+        set_trap_entry(&mut idt.x87_floating_point, x87_floating_point_entry);
+        set_trap_entry(&mut idt.alignment_check, alignment_check_entry);
+        unsafe {
+            set_trap_entry(&mut idt.machine_check, machine_check_entry)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        set_trap_entry(&mut idt.simd_floating_point, simd_floating_point_entry);
+        set_trap_entry(&mut idt.virtualization, virtualization_entry);
+        set_trap_entry(&mut idt.cp_protection_exception, control_protection_entry);
+        set_trap_entry(&mut idt.hv_injection_exception, hv_injection_entry);
+        set_trap_entry(
+            &mut idt.vmm_communication_exception,
+            vmm_communication_entry,
+        );
+        set_trap_entry(&mut idt.security_exception, security_entry);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
         idt[46].set_handler_fn(irq14_handler);
         idt[47].set_handler_fn(irq15_handler);
+        for (irq, handler) in IRQ_STUBS {
+            idt[(PIC_1_OFFSET + irq) as usize].set_handler_fn(handler);
+        }
+        idt[PIC_SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
+/// ISA IRQs let through by `enable_irq`, one bit each, so that `init_apic`
+/// can route them again. The kernel handles 1, 14 and 15 itself.
+static ENABLED_IRQS: AtomicU16 = AtomicU16::new(1 << 1 | 1 << 14 | 1 << 15);
+
+/// Handlers installed by drivers for the IRQs in `IRQ_STUBS`.
+static IRQ_HANDLERS: spin::RwLock<[Option<fn()>; 16]> = spin::RwLock::new([None; 16]);
+
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                if let Some(handler) = IRQ_HANDLERS.read()[$irq] {
+                    handler();
+                }
+                end_of_interrupt(PIC_1_OFFSET + $irq);
+            }
+        )*
+
+        /// IRQs that drivers can handle with `set_irq_handler`.
+        const IRQ_STUBS: [(u8, extern "x86-interrupt" fn(InterruptStackFrame)); 9] =
+            [$(($irq, $name)),*];
+    };
+}
+
+irq_stubs!(
+    3 => irq3_handler,
+    4 => irq4_handler,
+    5 => irq5_handler,
+    6 => irq6_handler,
+    9 => irq9_handler,
+    10 => irq10_handler,
+    11 => irq11_handler,
+    12 => irq12_handler,
+    13 => irq13_handler
+);
+
+/// Call `handler` on IRQ `irq` and let the IRQ through, returning false if
+/// the IRQ is used by the kernel itself. PCI devices sharing a line each
+/// need to install a handler that checks all of them.
+///
+/// With the APICs, `irq` arrives on the GSI the MADT routes it to. That
+/// makes a PCI device's `interrupt_line` right only where the chipset
+/// routes INTx to ISA IRQs, like QEMU's default i440fx machine. On q35 INTx
+/// arrives on GSIs 16 to 23, which would take the ACPI _PRT or MSI to
+/// find, so the handler never runs and drivers have to poll.
+pub fn set_irq_handler(irq: u8, handler: fn()) -> bool {
+    if !IRQ_STUBS.iter().any(|&(stub, _)| stub == irq) {
+        return false;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.write()[irq as usize] = Some(handler);
+    });
+    enable_irq(irq);
+    true
+}
+
+/// Signal the end of the interrupt with vector `index` to whichever
+/// interrupt controller is in use.
+fn end_of_interrupt(index: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index);
+        }
+    }
+}
+
+/// Let ISA interrupt `irq` through to its handler.
+pub fn enable_irq(irq: u8) {
+    ENABLED_IRQS.fetch_or(1 << (irq & 0xf), Ordering::Relaxed);
+    if apic::is_enabled() {
+        apic::enable_irq(irq);
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = pics.read_masks();
+        if irq < 8 {
+            primary &= !(1 << irq);
+        } else {
+            // the secondary PIC is cascaded through IRQ 2
+            primary &= !(1 << 2);
+            secondary &= !(1 << (irq - 8));
+        }
+        pics.write_masks(primary, secondary);
+    });
+}
+
+/// Switch to the APICs if the machine has them, routing the interrupts
+/// that have been enabled so far.
+pub fn init_apic() -> bool {
+    if !apic::init() {
+        return false;
+    }
+    let enabled = ENABLED_IRQS.load(Ordering::Relaxed);
+    for irq in (0..16).filter(|irq| enabled & 1 << irq != 0) {
+        apic::enable_irq(irq);
+    }
+    true
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // spurious interrupts must not be acknowledged
+}
+
 extern "x86-interrupt" fn irq14_handler(_stack_frame: InterruptStackFrame) {
     //print!(".");
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(46);
-    }
+    end_of_interrupt(46);
 }
 
 extern "x86-interrupt" fn irq15_handler(_stack_frame: InterruptStackFrame) {
     //print!(".");
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(47);
-    }
+    end_of_interrupt(47);
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    //print!(".");
+    crate::task::timer::tick();
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    end_of_interrupt(InterruptIndex::Timer.as_u8());
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -80,38 +227,307 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode); // new
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::rtc::handle_interrupt();
+
+    end_of_interrupt(InterruptIndex::Rtc.as_u8());
+}
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// The registers of the interrupted code, saved by the entry stubs of
+/// `trap_entry!`. Changes made by the handler are restored on return.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// Zero for exceptions without an error code.
+    pub error_code: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "rip {:#018x} rsp {:#018x} rflags {:#x}",
+            self.rip, self.rsp, self.rflags
+        )?;
+        let registers = [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("r8 ", self.r8),
+            ("r9 ", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+        ];
+        // three to a line to fit the screen
+        for line in registers.chunks(3) {
+            for (name, value) in line {
+                write!(f, "{} {:#018x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
     }
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+// Saves the rest of the registers below the ones the entry stub pushed,
+// calls the handler in rax with the frame and restores them. The handler
+// gets a stack frame whose caller is the interrupted code, so backtraces
+// go on past the exception.
+core::arch::global_asm!(
+    ".global trap_common",
+    "trap_common:",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "mov rbx, rsp",
+    // the interrupted rip as return address, above its frame pointer
+    "push qword ptr [rsp + 16 * 8]",
+    "push rbp",
+    "mov rbp, rsp",
+    // the ABI wants an aligned stack and the direction flag clear
+    "and rsp, -16",
+    "cld",
+    "call rax",
+    "mov rsp, rbx",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 8",
+    "iretq",
+);
+
+/// Define the entry stub for an exception whose handler needs all
+/// registers of the interrupted code, like the debugger's. The handler is
+/// an `extern "C" fn(&mut TrapFrame)`.
+macro_rules! trap_entry {
+    ($entry:ident => $handler:ident) => {
+        trap_entry!($entry => $handler, "push 0");
+    };
+    // the CPU pushed an error code already
+    ($entry:ident => $handler:ident, error_code) => {
+        trap_entry!($entry => $handler, "");
+    };
+    ($entry:ident => $handler:ident, $push_error_code:literal) => {
+        extern "C" {
+            fn $entry();
+        }
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            $push_error_code,
+            "push rax",
+            "lea rax, [rip + {handler}]",
+            "jmp trap_common",
+            handler = sym $handler,
+        );
+    };
+}
+
+/// Point `entry` at a stub defined with `trap_entry!`.
+fn set_trap_entry<F>(entry: &mut Entry<F>, stub: unsafe extern "C" fn()) -> &mut EntryOptions {
+    unsafe { entry.set_handler_addr(VirtAddr::new(stub as usize as u64)) }
+}
+
+/// How to show the error code of an exception.
+#[derive(Debug, Clone, Copy)]
+enum ErrorCode {
+    /// The exception has none.
+    None,
+    Raw,
+    /// The segment selector the exception is about, if not zero.
+    Selector,
+    PageFault,
+    ControlProtection,
+}
+
+/// Define the entry stubs and handlers of exceptions caused by the running
+/// code, which are reported with `fault`.
+macro_rules! faults {
+    ($($entry:ident => $handler:ident($name:literal, $error_code:ident)),* $(,)?) => {
+        $(
+            faults!(@entry $entry => $handler, $error_code);
+
+            extern "C" fn $handler(frame: &mut TrapFrame) {
+                fault(frame, $name, ErrorCode::$error_code);
+            }
+        )*
+    };
+    (@entry $entry:ident => $handler:ident, None) => {
+        trap_entry!($entry => $handler);
+    };
+    (@entry $entry:ident => $handler:ident, $error_code:ident) => {
+        trap_entry!($entry => $handler, error_code);
+    };
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+trap_entry!(debug_entry => debug_handler);
+trap_entry!(nmi_entry => nmi_handler);
+trap_entry!(breakpoint_entry => breakpoint_handler);
+trap_entry!(machine_check_entry => machine_check_handler);
+trap_entry!(double_fault_entry => double_fault_handler, error_code);
+
+faults!(
+    divide_error_entry => divide_error_handler("DIVIDE ERROR", None),
+    overflow_entry => overflow_handler("OVERFLOW", None),
+    bound_range_entry => bound_range_handler("BOUND RANGE EXCEEDED", None),
+    invalid_opcode_entry => invalid_opcode_handler("INVALID OPCODE", None),
+    device_not_available_entry => device_not_available_handler("DEVICE NOT AVAILABLE", None),
+    invalid_tss_entry => invalid_tss_handler("INVALID TSS", Selector),
+    segment_not_present_entry => segment_not_present_handler("SEGMENT NOT PRESENT", Selector),
+    stack_segment_entry => stack_segment_handler("STACK SEGMENT FAULT", Selector),
+    general_protection_entry => general_protection_handler("GENERAL PROTECTION FAULT", Selector),
+    page_fault_entry => page_fault_handler("PAGE FAULT", PageFault),
+    x87_floating_point_entry => x87_floating_point_handler("X87 FLOATING POINT", None),
+    alignment_check_entry => alignment_check_handler("ALIGNMENT CHECK", Raw),
+    simd_floating_point_entry => simd_floating_point_handler("SIMD FLOATING POINT", None),
+    virtualization_entry => virtualization_handler("VIRTUALIZATION", None),
+    control_protection_entry => control_protection_handler("CONTROL PROTECTION", ControlProtection),
+    hv_injection_entry => hv_injection_handler("HYPERVISOR INJECTION", None),
+    vmm_communication_entry => vmm_communication_handler("VMM COMMUNICATION", Raw),
+    security_entry => security_handler("SECURITY", Raw),
+);
+
+/// Report an exception caused by the code in `frame` and panic. A fault
+/// leaves kernel state, like locks and borrows, halfway changed, so no task
+/// can safely go on; WASM programs' traps are errors from wasmi instead and
+/// end only the program.
+fn fault(frame: &mut TrapFrame, name: &str, error_code: ErrorCode) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    backtrace::emit(format_args!("EXCEPTION: {}\n", name));
+    match error_code {
+        ErrorCode::None => {}
+        ErrorCode::Raw => backtrace::emit(format_args!("Error Code: {:#x}\n", frame.error_code)),
+        ErrorCode::Selector if frame.error_code == 0 => {
+            backtrace::emit(format_args!("Error Code: 0\n"))
+        }
+        ErrorCode::Selector => backtrace::emit(format_args!(
+            "Error Code: {:?}\n",
+            SelectorErrorCode::new_truncate(frame.error_code)
+        )),
+        ErrorCode::PageFault => {
+            backtrace::emit(format_args!("Accessed Address: {:?}\n", Cr2::read()));
+            backtrace::emit(format_args!(
+                "Error Code: {:?}\n",
+                PageFaultErrorCode::from_bits_truncate(frame.error_code)
+            ));
+        }
+        ErrorCode::ControlProtection => {
+            let cause = match frame.error_code & 0x7fff {
+                1 => "near return",
+                2 => "far return or iret",
+                3 => "missing endbranch",
+                4 => "rstorssp",
+                5 => "setssbsy",
+                _ => "unknown",
+            };
+            backtrace::emit(format_args!(
+                "Error Code: {:#x} ({})\n",
+                frame.error_code, cause
+            ));
+        }
+    }
+    backtrace::emit(format_args!("{}", frame));
+    // the panic handler prints the backtrace
+    panic!("EXCEPTION: {}", name);
+}
+
+extern "C" fn breakpoint_handler(frame: &mut TrapFrame) {
+    if crate::gdb::is_active() {
+        crate::gdb::handle_trap(frame);
+        return;
+    }
+    println!("EXCEPTION: BREAKPOINT\n{:#x?}", frame);
 }
 
-pub fn init_idt() {
-    IDT.load();
+/// NMIs come from the hardware, like watchdogs and memory errors, rather
+/// than from the code they interrupt.
+extern "C" fn nmi_handler(frame: &mut TrapFrame) {
+    backtrace::emit(format_args!("NON-MASKABLE INTERRUPT at {:#x}\n", frame.rip));
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// A fault while the CPU was starting the handler of another exception,
+/// usually a kernel stack overflow. This runs on a stack of its own, and
+/// nothing that was running can be trusted to go on.
+extern "C" fn double_fault_handler(frame: &mut TrapFrame) -> ! {
+    // the error code is always zero
+    backtrace::emit(format_args!("EXCEPTION: DOUBLE FAULT\n{}", frame));
+    panic!("EXCEPTION: DOUBLE FAULT");
+}
+
+/// The machine can't be trusted after a machine check.
+extern "C" fn machine_check_handler(frame: &mut TrapFrame) {
+    panic!("EXCEPTION: MACHINE CHECK\n{}", frame);
+}
+
+/// Single steps trap here after each instruction.
+extern "C" fn debug_handler(frame: &mut TrapFrame) {
+    if crate::gdb::is_active() {
+        crate::gdb::handle_trap(frame);
+        return;
+    }
+    // a debugger that went away left the trap flag set
+    frame.rflags &= !crate::gdb::TRAP_FLAG;
 }
 
 #[test_case]
@@ -0,0 +1,207 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::task::{self, keyboard, TaskId};
+
+/// Input redirected to the running command, consumed from the front.
+struct Input {
+    data: String,
+    cursor: usize,
+}
+
+/// Redirections are kept per task, so that background jobs don't capture
+/// each other's output. Code running outside of any task uses `None`.
+type Stacks<T> = BTreeMap<Option<TaskId>, Vec<T>>;
+
+/// Where a task's terminal input comes from and its output goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    /// The VGA screen and the keyboard
+    Screen,
+    /// A terminal on the first serial port
+    Serial,
+}
+
+lazy_static! {
+    /// Consoles of the tasks that don't use the screen.
+    static ref CONSOLES: Mutex<BTreeMap<TaskId, Console>> = Mutex::new(BTreeMap::new());
+    /// Stacks of output captures; `print!` appends to the innermost one.
+    static ref STDOUT: Mutex<Stacks<String>> = Mutex::new(BTreeMap::new());
+    /// Stacks of redirected inputs; reads consume the innermost one.
+    static ref STDIN: Mutex<Stacks<Input>> = Mutex::new(BTreeMap::new());
+}
+
+fn push<T>(stacks: &Mutex<Stacks<T>>, item: T) {
+    interrupts::without_interrupts(|| stacks.lock().entry(task::current()).or_default().push(item));
+}
+
+fn pop<T>(stacks: &Mutex<Stacks<T>>) -> Option<T> {
+    interrupts::without_interrupts(|| {
+        let mut stacks = stacks.lock();
+        let key = task::current();
+        let stack = stacks.get_mut(&key)?;
+        let item = stack.pop();
+        if stack.is_empty() {
+            stacks.remove(&key);
+        }
+        item
+    })
+}
+
+fn is_empty<T>(stacks: &Mutex<Stacks<T>>) -> bool {
+    interrupts::without_interrupts(|| !stacks.lock().contains_key(&task::current()))
+}
+
+/// The innermost entry of the running task's stack.
+fn innermost<T>(stacks: &mut Stacks<T>) -> Option<&mut T> {
+    stacks
+        .get_mut(&task::current())
+        .and_then(|stack| stack.last_mut())
+}
+
+/// Drop the redirections left behind by a task that has stopped.
+pub(crate) fn release(id: TaskId) {
+    interrupts::without_interrupts(|| {
+        STDOUT.lock().remove(&Some(id));
+        STDIN.lock().remove(&Some(id));
+        CONSOLES.lock().remove(&id);
+    });
+}
+
+/// The console of the running task. Code outside of tasks uses the screen.
+pub fn console() -> Console {
+    let id = match task::current() {
+        Some(id) => id,
+        None => return Console::Screen,
+    };
+    interrupts::without_interrupts(|| CONSOLES.lock().get(&id).copied()).unwrap_or(Console::Screen)
+}
+
+/// Bind the running task to `console`, along with the tasks it spawns from
+/// now on.
+pub fn set_console(console: Console) {
+    if let Some(id) = task::current() {
+        interrupts::without_interrupts(|| {
+            let mut consoles = CONSOLES.lock();
+            match console {
+                Console::Screen => consoles.remove(&id),
+                console => consoles.insert(id, console),
+            }
+        });
+    }
+}
+
+/// Give `child`, just spawned by the running task, the same console.
+pub(crate) fn inherit_console(child: TaskId) {
+    let console = console();
+    if console != Console::Screen {
+        interrupts::without_interrupts(|| CONSOLES.lock().insert(child, console));
+    }
+}
+
+/// Start capturing everything printed with `print!` until the matching
+/// `pop_stdout`.
+pub fn push_stdout() {
+    push(&STDOUT, String::new());
+}
+
+/// Stop the innermost capture and return what was printed to it.
+pub fn pop_stdout() -> String {
+    pop(&STDOUT).unwrap_or_default()
+}
+
+/// Whether output currently goes to the screen.
+pub fn stdout_is_terminal() -> bool {
+    is_empty(&STDOUT)
+}
+
+/// Append `args` to the innermost capture, returning false if output is
+/// not being captured.
+pub(crate) fn write_captured(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    let mut stdout = STDOUT.lock();
+    match innermost(&mut stdout) {
+        Some(capture) => {
+            capture.write_fmt(args).unwrap();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Feed `data` to the following reads until the matching `pop_stdin`.
+pub fn push_stdin(data: String) {
+    push(&STDIN, Input { data, cursor: 0 });
+}
+
+pub fn pop_stdin() {
+    pop(&STDIN);
+}
+
+/// Whether input currently comes from the keyboard.
+pub fn stdin_is_terminal() -> bool {
+    is_empty(&STDIN)
+}
+
+/// Read up to `buf.len()` bytes of redirected input.
+///
+/// Returns 0 at the end of the input and when reading from the keyboard,
+/// which can't be done from synchronous code such as WASM host calls.
+pub fn read(buf: &mut [u8]) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut stdin = STDIN.lock();
+        match innermost(&mut stdin) {
+            Some(input) => {
+                let rest = &input.data.as_bytes()[input.cursor..];
+                let len = rest.len().min(buf.len());
+                buf[..len].copy_from_slice(&rest[..len]);
+                input.cursor += len;
+                len
+            }
+            None => 0,
+        }
+    })
+}
+
+/// Read the next line, without the trailing newline, from redirected input
+/// or from the keyboard. Returns `None` at the end of redirected input.
+pub async fn read_line() -> Option<String> {
+    let line = interrupts::without_interrupts(|| {
+        let mut stdin = STDIN.lock();
+        innermost(&mut stdin).map(|input| {
+            let rest = &input.data[input.cursor..];
+            if rest.is_empty() {
+                return None;
+            }
+            let len = rest.find('\n').map(|i| i + 1).unwrap_or(rest.len());
+            let line = String::from(rest[..len].trim_end_matches('\n'));
+            input.cursor += len;
+            Some(line)
+        })
+    });
+    match line {
+        Some(line) => line,
+        None => Some(keyboard::read_line().await),
+    }
+}
+
+/// Read all remaining redirected input.
+pub fn read_to_string() -> String {
+    interrupts::without_interrupts(|| {
+        let mut stdin = STDIN.lock();
+        match innermost(&mut stdin) {
+            Some(input) => {
+                let rest = String::from(&input.data[input.cursor..]);
+                input.cursor = input.data.len();
+                rest
+            }
+            None => String::new(),
+        }
+    })
+}
@@ -7,16 +7,34 @@
 
 extern crate alloc;
 
+pub mod acpi;
+pub mod ahci;
 pub mod allocator;
+pub mod apic;
 pub mod ata;
+pub mod backtrace;
+pub mod block;
+pub mod e1000;
 pub mod fat;
+pub mod fs;
+pub mod gdb;
 pub mod gdt;
+pub mod history;
 pub mod interrupts;
+pub mod io;
+pub mod logger;
 pub mod memory;
+pub mod net;
+pub mod pci;
+pub mod power;
+pub mod rtc;
 pub mod serial;
+pub mod shell;
 pub mod simplefs;
 pub mod task;
+pub mod time;
 pub mod vga_buffer;
+pub mod virtio;
 pub mod wasm;
 
 use core::panic::PanicInfo;
@@ -29,6 +47,8 @@ pub fn init() {
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
+    time::init();
+    rtc::init();
 }
 pub fn hlt_loop() -> ! {
     loop {
@@ -71,9 +91,17 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    // like before
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     init();
+    // many unit tests allocate
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
     test_main();
     hlt_loop();
 }
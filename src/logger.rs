@@ -0,0 +1,450 @@
+//! The backend for the `log` crate's macros. Records are filtered by level
+//! per module, kept in a ring buffer for `dmesg` and copied to the sinks:
+//! the screen, the serial port and a log file. Records for the file are
+//! kept in a ring of their own until the `log` task writes them out.
+//!
+//! Logging doesn't allocate, so the macros can be used in interrupt
+//! handlers.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use log::{LevelFilter, Log, Metadata, Record};
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts;
+
+use crate::shell::command::{self, Builtin, Handler};
+use crate::task::timer;
+use crate::time::{self, Duration};
+use crate::vga_buffer::WRITER;
+use crate::{fs, print, println};
+
+/// Bytes of log text kept for `dmesg`.
+const RING_SIZE: usize = 64 * 1024;
+/// Bytes of log text waiting to be written to the log file.
+const FILE_RING_SIZE: usize = 16 * 1024;
+
+/// Where the file sink writes.
+pub const LOG_FILE: &str = "kernel.log";
+/// The log file is cut to its newest lines when it grows beyond this, so
+/// it can't fill up the disk.
+const LOG_FILE_SIZE: usize = 64 * 1024;
+
+/// How often records are appended to the log file. Every write rewrites the
+/// whole disk image, so they are batched.
+const FILE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Module paths are shown and matched without this.
+const CRATE_PREFIX: &str = "blog_os::";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Screen,
+    Serial,
+    File,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 3] = [Sink::Screen, Sink::Serial, Sink::File];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Sink::Screen => "screen",
+            Sink::Serial => "serial",
+            Sink::File => "file",
+        }
+    }
+
+    fn level(self) -> &'static AtomicUsize {
+        match self {
+            Sink::Screen => &SCREEN_LEVEL,
+            Sink::Serial => &SERIAL_LEVEL,
+            Sink::File => &FILE_LEVEL,
+        }
+    }
+}
+
+impl FromStr for Sink {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Sink, ()> {
+        Sink::ALL
+            .iter()
+            .copied()
+            .find(|sink| sink.name() == s)
+            .ok_or(())
+    }
+}
+
+/// Text written to the log, overwriting the oldest once full.
+struct Ring<const N: usize> {
+    data: [u8; N],
+    /// Bytes written since boot; the next one goes at `written % N`.
+    written: u64,
+}
+
+impl<const N: usize> Ring<N> {
+    /// The text written since `written` was `from`, as far as it is still
+    /// there, starting at a whole line.
+    fn read_from(&self, from: u64) -> String {
+        let start = from.max(self.written.saturating_sub(N as u64));
+        let mut bytes: Vec<u8> = (start..self.written)
+            .map(|i| self.data[(i % N as u64) as usize])
+            .collect();
+        if start > from {
+            // the start of the first line was overwritten
+            let line_end = bytes.iter().position(|&byte| byte == b'\n');
+            bytes.drain(..line_end.map_or(bytes.len(), |end| end + 1));
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl<const N: usize> fmt::Write for Ring<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.data[(self.written % N as u64) as usize] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring<RING_SIZE>> = Mutex::new(Ring {
+    data: [0; RING_SIZE],
+    written: 0,
+});
+/// Records at or above the file sink's level.
+static FILE_RING: Mutex<Ring<FILE_RING_SIZE>> = Mutex::new(Ring {
+    data: [0; FILE_RING_SIZE],
+    written: 0,
+});
+
+/// Where `dmesg -c` cleared the ring.
+static CLEARED: AtomicU64 = AtomicU64::new(0);
+/// How much of the file ring is in the log file.
+static FILE_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// Levels are stored as `LevelFilter as usize`.
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static SCREEN_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Warn as usize);
+static SERIAL_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static FILE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+
+/// Levels of modules that don't use the default, like `ata` or `net::stack`.
+/// A module also covers the modules inside it.
+static MODULE_LEVELS: RwLock<Vec<(String, LevelFilter)>> = RwLock::new(Vec::new());
+
+fn level_filter(value: usize) -> LevelFilter {
+    match value {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// The module path of `target` without the crate name.
+fn module(target: &str) -> &str {
+    target.strip_prefix(CRATE_PREFIX).unwrap_or(target)
+}
+
+/// The level of `target`, from the longest module that contains it.
+fn target_level(target: &str) -> LevelFilter {
+    let target = module(target);
+    MODULE_LEVELS
+        .read()
+        .iter()
+        .filter(|(module, _)| {
+            target == module
+                || target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.starts_with("::"))
+        })
+        .max_by_key(|(module, _)| module.len())
+        .map(|&(_, level)| level)
+        .unwrap_or_else(|| level_filter(DEFAULT_LEVEL.load(Ordering::Relaxed)))
+}
+
+/// A record as a line: time since boot, level, module and message.
+struct Line<'a>(&'a Record<'a>);
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let uptime = time::uptime();
+        writeln!(
+            f,
+            "[{:5}.{:06}] {:5} {}: {}",
+            uptime.as_secs(),
+            uptime.subsec_micros(),
+            self.0.level(),
+            module(self.0.target()),
+            self.0.args()
+        )
+    }
+}
+
+/// Writes straight to the screen, whatever the running task's console and
+/// output capture.
+struct Screen;
+
+impl fmt::Write for Screen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        WRITER.lock().write_string(s);
+        Ok(())
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= target_level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = Line(record);
+        interrupts::without_interrupts(|| {
+            let _ = write!(RING.lock(), "{}", line);
+            if record.level() <= level_filter(SCREEN_LEVEL.load(Ordering::Relaxed)) {
+                let _ = write!(Screen, "{}", line);
+            }
+            if record.level() <= level_filter(FILE_LEVEL.load(Ordering::Relaxed)) {
+                let _ = write!(FILE_RING.lock(), "{}", line);
+            }
+        });
+        if record.level() <= level_filter(SERIAL_LEVEL.load(Ordering::Relaxed)) {
+            crate::serial::_print(format_args!("{}", line));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+pub fn level(sink: Sink) -> LevelFilter {
+    level_filter(sink.level().load(Ordering::Relaxed))
+}
+
+pub fn set_level(sink: Sink, level: LevelFilter) {
+    sink.level().store(level as usize, Ordering::Relaxed);
+}
+
+pub fn default_level() -> LevelFilter {
+    level_filter(DEFAULT_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_default_level(level: LevelFilter) {
+    DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Set the level of `module` and the modules inside it, or go back to the
+/// default with `None`.
+pub fn set_module_level(module: &str, level: Option<LevelFilter>) {
+    interrupts::without_interrupts(|| {
+        let mut levels = MODULE_LEVELS.write();
+        levels.retain(|(name, _)| name != module);
+        if let Some(level) = level {
+            levels.push((String::from(module), level));
+        }
+    });
+    update_max_level();
+}
+
+/// Let the `log` macros skip records that no module's level lets through
+/// before they get to the logger. Every record that passes goes to the
+/// ring buffer, so the sink levels don't matter here.
+fn update_max_level() {
+    let modules = MODULE_LEVELS.read().iter().map(|&(_, level)| level).max();
+    log::set_max_level(default_level().max(modules.unwrap_or(LevelFilter::Off)));
+}
+
+pub fn module_levels() -> Vec<(String, LevelFilter)> {
+    MODULE_LEVELS.read().clone()
+}
+
+/// The log since boot or since it was last cleared, as far as the ring
+/// buffer holds it.
+pub fn messages() -> String {
+    let from = CLEARED.load(Ordering::Relaxed);
+    interrupts::without_interrupts(|| RING.lock().read_from(from))
+}
+
+pub fn clear() {
+    let written = interrupts::without_interrupts(|| RING.lock().written);
+    CLEARED.store(written, Ordering::Relaxed);
+}
+
+/// Append the records logged for the file sink since the last call to the
+/// log file.
+fn write_file() {
+    let (text, written) = interrupts::without_interrupts(|| {
+        let ring = FILE_RING.lock();
+        (
+            ring.read_from(FILE_WRITTEN.load(Ordering::Relaxed)),
+            ring.written,
+        )
+    });
+    FILE_WRITTEN.store(written, Ordering::Relaxed);
+    if text.is_empty() {
+        return;
+    }
+    let mut data = fs::read(LOG_FILE).unwrap_or_default();
+    data.extend_from_slice(text.as_bytes());
+    trim(&mut data, LOG_FILE_SIZE);
+    // a failed write would only be logged again
+    let _ = fs::write(LOG_FILE, data);
+}
+
+/// Drop whole lines from the start of `data` until it is at most `size`
+/// bytes long.
+fn trim(data: &mut Vec<u8>, size: usize) {
+    if data.len() <= size {
+        return;
+    }
+    let cut = data.len() - size;
+    let start = data[cut..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(data.len(), |end| cut + end + 1);
+    data.drain(..start);
+}
+
+/// The `log` task: write to the log file from time to time.
+pub async fn run() {
+    loop {
+        timer::sleep(FILE_INTERVAL).await;
+        write_file();
+    }
+}
+
+/// Install the logger. Records logged before this are lost.
+pub fn init() {
+    log::set_logger(&LOGGER).expect("logger already set");
+    update_max_level();
+    command::register(Builtin {
+        name: "dmesg",
+        usage: "dmesg [-c]",
+        help: "Show the kernel log.\n\
+               -c  clear it afterwards",
+        handler: Handler::Sync(dmesg),
+    });
+    command::register(Builtin {
+        name: "log",
+        usage: "log [[MODULE] LEVEL | -s SINK LEVEL]",
+        help: "Show the log levels, or set the default level, the level of a\n\
+               module like ata or net::stack (default to undo), or the level\n\
+               of the screen, serial or file sink. The file sink writes to\n\
+               kernel.log, keeping its last 64K. Levels are off, error, warn,\n\
+               info, debug and trace.",
+        handler: Handler::Sync(log_command),
+    });
+}
+
+fn dmesg(args: &[String]) -> i32 {
+    match args.get(1).map(String::as_str) {
+        None | Some("-c") => {}
+        Some(_) => {
+            println!("Usage: dmesg [-c]");
+            return 2;
+        }
+    }
+    print!("{}", messages());
+    if args.len() > 1 {
+        clear();
+    }
+    0
+}
+
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    LevelFilter::from_str(level).ok()
+}
+
+fn log_command(args: &[String]) -> i32 {
+    let args: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => {
+            println!("default: {}", default_level());
+            for (module, level) in module_levels() {
+                println!("{}: {}", module, level);
+            }
+            for sink in Sink::ALL {
+                println!("{} sink: {}", sink.name(), level(sink));
+            }
+            return 0;
+        }
+        [level] => match parse_level(level) {
+            Some(level) => set_default_level(level),
+            None => {
+                println!("log: Bad level {}", level);
+                return 2;
+            }
+        },
+        ["-s", sink, level] => match (sink.parse::<Sink>(), parse_level(level)) {
+            (Ok(sink), Some(level)) => set_level(sink, level),
+            (Err(_), _) => {
+                println!("log: No sink {}", sink);
+                return 2;
+            }
+            (_, None) => {
+                println!("log: Bad level {}", level);
+                return 2;
+            }
+        },
+        [module, "default"] => set_module_level(module, None),
+        [module, level] => match parse_level(level) {
+            Some(level) => set_module_level(module, Some(level)),
+            None => {
+                println!("log: Bad level {}", level);
+                return 2;
+            }
+        },
+        _ => {
+            println!("Usage: log [[MODULE] LEVEL | -s SINK LEVEL]");
+            return 2;
+        }
+    }
+    0
+}
+
+#[test_case]
+fn test_ring_read_from() {
+    let start = RING.lock().written;
+    write!(RING.lock(), "first\nsecond\n").unwrap();
+    assert_eq!(RING.lock().read_from(start), "first\nsecond\n");
+    assert_eq!(RING.lock().read_from(start + 6), "second\n");
+    // wrap around, losing the start of the first line
+    let line = "x".repeat(RING_SIZE / 2);
+    writeln!(RING.lock(), "{}", line).unwrap();
+    writeln!(RING.lock(), "{}", line).unwrap();
+    assert_eq!(RING.lock().read_from(start), alloc::format!("{}\n", line));
+}
+
+#[test_case]
+fn test_trim() {
+    let mut data = b"one\ntwo\nthree\n".to_vec();
+    trim(&mut data, 13);
+    assert_eq!(data, b"two\nthree\n");
+    trim(&mut data, 10);
+    assert_eq!(data, b"two\nthree\n");
+    trim(&mut data, 3);
+    assert_eq!(data, b"");
+}
+
+#[test_case]
+fn test_max_level_follows_levels() {
+    let before = log::max_level();
+    set_module_level("logger::test", Some(LevelFilter::Trace));
+    assert_eq!(log::max_level(), LevelFilter::Trace);
+    set_module_level("logger::test", None);
+    assert_eq!(log::max_level(), before);
+}
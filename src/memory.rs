@@ -1,10 +1,23 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// The page table and frame allocator, once handed over with `install`.
+    static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+    static ref FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+}
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -12,6 +25,7 @@ use x86_64::{
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -34,6 +48,90 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
+/// Make the page table and frame allocator available to drivers that need
+/// to map memory after boot.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// The virtual address at which the bootloader mapped `addr`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Make sure `size` bytes of device memory at `addr` are mapped, uncached,
+/// at `phys_to_virt(addr)` and return that address.
+///
+/// The bootloader only maps physical memory up to the end of RAM, so
+/// registers of devices above that need mapping first. Pages that are
+/// already mapped are left alone.
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let virt = phys_to_virt(addr);
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MapToError::FrameAllocationFailed),
+    };
+    let start = Page::<Size4KiB>::containing_address(virt);
+    let end = Page::<Size4KiB>::containing_address(virt + size.max(1) - 1u64);
+    for page in Page::range_inclusive(start, end) {
+        if mapper.translate_addr(page.start_address()).is_some() {
+            continue;
+        }
+        let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+        let frame =
+            PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64() - offset));
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(virt)
+}
+
+/// Allocate `pages` zeroed, physically contiguous frames for a device to
+/// access directly and return the address of the first. They are reached
+/// through `phys_to_virt` and never freed.
+///
+/// The frame allocator hands out frames in address order, so runs are
+/// usually contiguous; frames that break a run are skipped.
+pub fn allocate_dma(pages: usize) -> Option<PhysAddr> {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut()?;
+    let mut start: PhysFrame = frame_allocator.allocate_frame()?;
+    let mut count = 1;
+    while count < pages {
+        let frame = frame_allocator.allocate_frame()?;
+        if frame == start + count as u64 {
+            count += 1;
+        } else {
+            start = frame;
+            count = 1;
+        }
+    }
+    let addr = start.start_address();
+    unsafe { core::ptr::write_bytes(phys_to_virt(addr).as_mut_ptr::<u8>(), 0, pages * 4096) };
+    Some(addr)
+}
+
+/// Whether `addr` is mapped, so that accessing it won't page fault.
+///
+/// This walks the active page table without taking the mapper's lock, so it
+/// can be used from exception handlers that may have interrupted its owner.
+/// Before `init` nothing counts as mapped.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    let offset = match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => return false,
+        offset => VirtAddr::new(offset),
+    };
+    // only reads the tables, so aliasing the mapper's reference is harmless
+    let table = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    table.translate_addr(addr).is_some()
+}
+
 pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
     translate_addr_inner(addr, physical_memory_offset)
 }
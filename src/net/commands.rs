@@ -0,0 +1,343 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::pin::pin;
+use core::sync::atomic::{AtomicU16, Ordering};
+use futures_util::future::{select, Either, LocalBoxFuture};
+
+use super::fetch::{self, Url};
+use super::socket::{IcmpSocket, IpEndpoint, Ipv4Address, TcpListener, TcpStream, UdpSocket};
+use super::{list, stack, NetError};
+use crate::shell::command::{self, Builtin, Handler};
+use crate::task::timer;
+use crate::time::{Duration, Instant};
+use crate::{fs, io, print, println};
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+const PING_DATA: [u8; 56] = [0x42; 56];
+
+/// Typed on a line of its own, ends an `nc` session from the keyboard.
+const NC_ESCAPE: &str = "~.";
+
+static NEXT_PING_IDENT: AtomicU16 = AtomicU16::new(1);
+
+pub fn register() {
+    command::register(Builtin {
+        name: "ifconfig",
+        usage: "ifconfig",
+        help: "Show the network interfaces and the address DHCP configured.",
+        handler: Handler::Sync(ifconfig),
+    });
+    command::register(Builtin {
+        name: "ping",
+        usage: "ping [-c COUNT] ADDRESS",
+        help: "Send ICMP echo requests, 4 unless COUNT is given, and show the\n\
+               round trip times of the replies.",
+        handler: Handler::Async(ping),
+    });
+    command::register(Builtin {
+        name: "nc",
+        usage: "nc [-u] ADDRESS PORT | nc [-u] -l PORT",
+        help: "Connect to a TCP port, or with -l wait for a connection, then\n\
+               send input lines and print what arrives. -u uses UDP instead.\n\
+               Type ~. on a line of its own to quit.",
+        handler: Handler::Async(nc),
+    });
+    command::register(Builtin {
+        name: "fetch",
+        usage: "fetch URL [FILE]",
+        help: "Download a file with TFTP (tftp://ADDRESS/FILE) or HTTP\n\
+               (http://ADDRESS[:PORT]/PATH), saving it as FILE or under the\n\
+               last part of the URL.",
+        handler: Handler::Async(fetch),
+    });
+}
+
+fn ifconfig(_args: &[String]) -> i32 {
+    let interfaces = list();
+    if interfaces.is_empty() {
+        println!("No network interfaces");
+        return 1;
+    }
+    for (index, interface) in interfaces.into_iter().enumerate() {
+        println!(
+            "{}: {} (link {})",
+            interface.name,
+            interface.model,
+            if interface.link_up { "up" } else { "down" }
+        );
+        println!("    ether {}  mtu {}", interface.mac_address, interface.mtu);
+        // the stack only runs on the first interface
+        if index == 0 {
+            match stack::config() {
+                Some(config) => {
+                    print!("    inet {}", config.address);
+                    if let Some(router) = config.router {
+                        print!("  gateway {}", router);
+                    }
+                    if let Some(dns_server) = config.dns_server {
+                        print!("  dns {}", dns_server);
+                    }
+                    println!();
+                }
+                None => println!("    inet not configured"),
+            }
+        }
+        println!(
+            "    RX packets {}  bytes {}",
+            interface.stats.rx_packets, interface.stats.rx_bytes
+        );
+        println!(
+            "    TX packets {}  bytes {}",
+            interface.stats.tx_packets, interface.stats.tx_bytes
+        );
+    }
+    0
+}
+
+fn ping(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        let (count, address) = match &args[1..] {
+            [address] => (4, address),
+            [flag, count, address] if flag == "-c" => match count.parse::<u16>() {
+                Ok(count) => (count, address),
+                Err(_) => {
+                    println!("ping: Bad count {}", count);
+                    return 2;
+                }
+            },
+            _ => {
+                println!("Usage: ping [-c COUNT] ADDRESS");
+                return 2;
+            }
+        };
+        let address = match address.parse::<Ipv4Address>() {
+            Ok(address) => address,
+            Err(_) => {
+                println!("ping: Bad address {}", address);
+                return 2;
+            }
+        };
+        let socket = match IcmpSocket::bind(NEXT_PING_IDENT.fetch_add(1, Ordering::Relaxed)) {
+            Ok(socket) => socket,
+            Err(err) => {
+                println!("ping: {}", err);
+                return 1;
+            }
+        };
+
+        println!("PING {}: {} data bytes", address, PING_DATA.len());
+        let mut received = 0;
+        for seq_no in 0..count {
+            let start = Instant::now();
+            if let Err(err) = socket.send_echo_request(address, seq_no, &PING_DATA).await {
+                println!("ping: {}", err);
+                return 1;
+            }
+            let reply = async {
+                loop {
+                    match socket.recv_echo_reply().await {
+                        // a late reply to an earlier request
+                        Ok((_, reply_seq_no, _)) if reply_seq_no != seq_no => continue,
+                        result => return result,
+                    }
+                }
+            };
+            match timer::timeout(PING_TIMEOUT, reply).await {
+                Ok(Ok((from, _, len))) => {
+                    received += 1;
+                    println!(
+                        "{} bytes from {}: icmp_seq={} time={:.3} ms",
+                        len + 8,
+                        from,
+                        seq_no,
+                        start.elapsed().as_secs_f64() * 1000.0
+                    );
+                }
+                Ok(Err(err)) => println!("ping: {}", err),
+                Err(_) => println!("Request timeout for icmp_seq {}", seq_no),
+            }
+            if seq_no + 1 < count {
+                timer::sleep(PING_INTERVAL.saturating_sub(start.elapsed())).await;
+            }
+        }
+        println!("{} packets transmitted, {} received", count, received);
+        if received > 0 {
+            0
+        } else {
+            1
+        }
+    })
+}
+
+fn nc(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        let mut args = &args[1..];
+        let udp = args.first().map(String::as_str) == Some("-u");
+        if udp {
+            args = &args[1..];
+        }
+        let (listen, address, port) = match args {
+            [flag, port] if flag == "-l" => (true, None, port),
+            [address, port] => (false, Some(address), port),
+            _ => {
+                println!("Usage: nc [-u] ADDRESS PORT | nc [-u] -l PORT");
+                return 2;
+            }
+        };
+        let port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                println!("nc: Bad port {}", port);
+                return 2;
+            }
+        };
+        let remote = match address {
+            None => None,
+            Some(address) => match address.parse::<Ipv4Address>() {
+                Ok(address) => Some(IpEndpoint::new(address.into(), port)),
+                Err(_) => {
+                    println!("nc: Bad address {}", address);
+                    return 2;
+                }
+            },
+        };
+
+        let result = match (udp, listen, remote) {
+            (false, false, Some(remote)) => match TcpStream::connect(remote).await {
+                Ok(stream) => tcp_session(stream).await,
+                Err(err) => Err(err),
+            },
+            (false, _, _) => match TcpListener::bind(port) {
+                Ok(mut listener) => match listener.accept().await {
+                    Ok(stream) => {
+                        if let Some(remote) = stream.remote_endpoint() {
+                            println!("Connection from {}", remote);
+                        }
+                        tcp_session(stream).await
+                    }
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            },
+            (true, _, remote) => match UdpSocket::bind(if listen { port } else { 0 }) {
+                Ok(socket) => udp_session(socket, remote).await,
+                Err(err) => Err(err),
+            },
+        };
+        match result {
+            Ok(()) => 0,
+            Err(err) => {
+                println!("nc: {}", err);
+                1
+            }
+        }
+    })
+}
+
+/// What happened first while waiting for both input and the network.
+enum Event<T> {
+    Line(Option<String>),
+    Received(Result<T, NetError>),
+}
+
+async fn tcp_session(mut stream: TcpStream) -> Result<(), NetError> {
+    let mut buf = [0; 1024];
+    let mut line = Some(Box::pin(io::read_line()));
+    loop {
+        let event = match line.as_mut() {
+            Some(line) => match select(line.as_mut(), pin!(stream.read(&mut buf))).await {
+                Either::Left((line, _)) => Event::Line(line),
+                Either::Right((result, _)) => Event::Received(result),
+            },
+            None => Event::Received(stream.read(&mut buf).await),
+        };
+        match event {
+            Event::Line(Some(text)) if text == NC_ESCAPE => return Ok(()),
+            Event::Line(Some(mut text)) => {
+                text.push('\n');
+                stream.write_all(text.as_bytes()).await?;
+                line = Some(Box::pin(io::read_line()));
+            }
+            Event::Line(None) => {
+                // end of the input; wait for the other side to finish
+                stream.shutdown();
+                line = None;
+            }
+            Event::Received(Ok(0)) => return Ok(()),
+            Event::Received(Ok(len)) => print!("{}", String::from_utf8_lossy(&buf[..len])),
+            Event::Received(Err(err)) => return Err(err),
+        }
+    }
+}
+
+/// Send lines to `remote`, or when listening to whoever sent the first
+/// datagram, and print the datagrams that arrive.
+async fn udp_session(socket: UdpSocket, mut remote: Option<IpEndpoint>) -> Result<(), NetError> {
+    let mut buf = [0; 2048];
+    let mut line = Box::pin(io::read_line());
+    loop {
+        let event = match select(line.as_mut(), pin!(socket.recv_from(&mut buf))).await {
+            Either::Left((line, _)) => Event::Line(line),
+            Either::Right((result, _)) => Event::Received(result),
+        };
+        match event {
+            Event::Line(None) => return Ok(()),
+            Event::Line(Some(text)) if text == NC_ESCAPE => return Ok(()),
+            Event::Line(Some(mut text)) => {
+                match remote {
+                    Some(remote) => {
+                        text.push('\n');
+                        socket.send_to(text.as_bytes(), remote).await?;
+                    }
+                    None => println!("nc: Nobody to send to yet"),
+                }
+                line = Box::pin(io::read_line());
+            }
+            Event::Received(Ok((len, from))) => {
+                remote.get_or_insert(from);
+                print!("{}", String::from_utf8_lossy(&buf[..len]));
+            }
+            Event::Received(Err(err)) => return Err(err),
+        }
+    }
+}
+
+fn fetch(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        let (url, name) = match &args[1..] {
+            [url] => (url, None),
+            [url, name] => (url, Some(name.as_str())),
+            _ => {
+                println!("Usage: fetch URL [FILE]");
+                return 2;
+            }
+        };
+        let name = match Url::parse(url).map(|url| name.or(url.file_name())) {
+            Ok(Some(name)) => name,
+            Ok(None) => {
+                println!("fetch: No file name in {}, give one", url);
+                return 2;
+            }
+            Err(err) => {
+                println!("fetch: {}", err);
+                return 2;
+            }
+        };
+        let start = Instant::now();
+        let data = match fetch::fetch(url).await {
+            Ok(data) => data,
+            Err(err) => {
+                println!("fetch: {}", err);
+                return 1;
+            }
+        };
+        let (len, seconds) = (data.len(), start.elapsed().as_secs_f64());
+        if let Err(err) = fs::write(name, data) {
+            println!("fetch: Could not write {} ({:?})", name, err);
+            return 1;
+        }
+        println!("{}: {} bytes in {:.2}s", name, len, seconds);
+        0
+    })
+}
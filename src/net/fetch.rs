@@ -0,0 +1,335 @@
+//! Downloading files with TFTP and HTTP, for getting programs onto the
+//! machine without rebuilding the disk image.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::socket::{IpEndpoint, Ipv4Address, TcpStream, UdpSocket};
+use super::NetError;
+use crate::task::timer;
+use crate::time::Duration;
+
+const TFTP_PORT: u16 = 69;
+const HTTP_PORT: u16 = 80;
+
+/// TFTP opcodes
+const TFTP_RRQ: u16 = 1;
+const TFTP_DATA: u16 = 3;
+const TFTP_ACK: u16 = 4;
+const TFTP_ERROR: u16 = 5;
+
+/// Without the blksize option every block but the last is this long.
+const TFTP_BLOCK_SIZE: usize = 512;
+
+/// How long to wait for a packet before sending the last one again.
+const TFTP_TIMEOUT: Duration = Duration::from_secs(1);
+const TFTP_RETRIES: u32 = 5;
+
+/// How long an HTTP server may stay silent.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Downloads are kept in memory, so stop before they take up all of it.
+pub const MAX_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    /// Not a `tftp://` or `http://` URL with an IPv4 address.
+    BadUrl,
+    Net(NetError),
+    /// The TFTP server sent an error.
+    Tftp(String),
+    /// The HTTP server answered with a status other than 2xx.
+    Http(u16),
+    /// The server's answer made no sense.
+    BadResponse,
+    /// The file is bigger than `MAX_SIZE`.
+    TooLarge,
+}
+
+impl From<NetError> for FetchError {
+    fn from(err: NetError) -> FetchError {
+        FetchError::Net(err)
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::BadUrl => f.write_str("Bad URL"),
+            FetchError::Net(err) => write!(f, "{}", err),
+            FetchError::Tftp(message) => write!(f, "TFTP error: {}", message),
+            FetchError::Http(status) => write!(f, "HTTP status {}", status),
+            FetchError::BadResponse => f.write_str("Bad response"),
+            FetchError::TooLarge => f.write_str("File too large"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Tftp,
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url<'a> {
+    pub scheme: Scheme,
+    /// The host as written, for the HTTP `Host` header.
+    pub host: &'a str,
+    pub server: IpEndpoint,
+    /// Starts with `/` for HTTP. TFTP file names are given without it.
+    pub path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    /// Parse `tftp://ADDRESS[:PORT]/FILE` or `http://ADDRESS[:PORT][/PATH]`.
+    /// Host names aren't supported since there is no DNS client.
+    pub fn parse(url: &'a str) -> Result<Url<'a>, FetchError> {
+        let (scheme, rest) = match url.split_once("://") {
+            Some(("tftp", rest)) => (Scheme::Tftp, rest),
+            Some(("http", rest)) => (Scheme::Http, rest),
+            _ => return Err(FetchError::BadUrl),
+        };
+        let (host, path) = match (scheme, rest.find('/')) {
+            (Scheme::Tftp, Some(slash)) => (&rest[..slash], &rest[slash + 1..]),
+            (Scheme::Http, Some(slash)) => (&rest[..slash], &rest[slash..]),
+            (Scheme::Http, None) => (rest, "/"),
+            (Scheme::Tftp, None) => return Err(FetchError::BadUrl),
+        };
+        let (address, port) = match host.split_once(':') {
+            Some((address, port)) => (address, port.parse().map_err(|_| FetchError::BadUrl)?),
+            None => match scheme {
+                Scheme::Tftp => (host, TFTP_PORT),
+                Scheme::Http => (host, HTTP_PORT),
+            },
+        };
+        let address: Ipv4Address = address.parse().map_err(|_| FetchError::BadUrl)?;
+        if path.is_empty() {
+            return Err(FetchError::BadUrl);
+        }
+        Ok(Url {
+            scheme,
+            host,
+            server: IpEndpoint::new(address.into(), port),
+            path,
+        })
+    }
+
+    /// The last part of the path, as a default name for the download.
+    pub fn file_name(&self) -> Option<&'a str> {
+        self.path.rsplit('/').next().filter(|name| !name.is_empty())
+    }
+}
+
+/// Download what `url` points to.
+pub async fn fetch(url: &str) -> Result<Vec<u8>, FetchError> {
+    let url = Url::parse(url)?;
+    match url.scheme {
+        Scheme::Tftp => tftp_get(url.server, url.path).await,
+        Scheme::Http => http_get(url.server, url.host, url.path).await,
+    }
+}
+
+fn u16_at(packet: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([packet[offset], packet[offset + 1]])
+}
+
+/// Read `file` from a TFTP server in octet mode.
+pub async fn tftp_get(server: IpEndpoint, file: &str) -> Result<Vec<u8>, FetchError> {
+    let socket = UdpSocket::bind(0)?;
+    let mut request = Vec::new();
+    request.extend_from_slice(&TFTP_RRQ.to_be_bytes());
+    request.extend_from_slice(file.as_bytes());
+    request.extend_from_slice(b"\0octet\0");
+
+    // the server answers from a port of its own, which the ACKs go to
+    let mut peer = None;
+    let mut last_packet = (request, server);
+    let mut data = Vec::new();
+    let mut block: u16 = 1;
+    let mut buf = [0; 4 + TFTP_BLOCK_SIZE];
+    let mut retries = 0;
+    socket.send_to(&last_packet.0, last_packet.1).await?;
+    loop {
+        let (len, from) = match timer::timeout(TFTP_TIMEOUT, socket.recv_from(&mut buf)).await {
+            Ok(received) => received?,
+            Err(_) if retries < TFTP_RETRIES => {
+                retries += 1;
+                socket.send_to(&last_packet.0, last_packet.1).await?;
+                continue;
+            }
+            Err(_) => return Err(NetError::TimedOut.into()),
+        };
+        if len < 4 || from.addr != server.addr || matches!(peer, Some(peer) if peer != from) {
+            continue;
+        }
+        let packet = &buf[..len];
+        match u16_at(packet, 0) {
+            TFTP_DATA => {
+                peer = Some(from);
+                let number = u16_at(packet, 2);
+                if number == block {
+                    if data.len() + len - 4 > MAX_SIZE {
+                        return Err(FetchError::TooLarge);
+                    }
+                    data.extend_from_slice(&packet[4..]);
+                    block = block.wrapping_add(1);
+                    retries = 0;
+                } else if number != block.wrapping_sub(1) {
+                    continue;
+                }
+                // a repeated block means our ACK was lost, so send it again
+                let mut ack = Vec::new();
+                ack.extend_from_slice(&TFTP_ACK.to_be_bytes());
+                ack.extend_from_slice(&number.to_be_bytes());
+                socket.send_to(&ack, from).await?;
+                last_packet = (ack, from);
+                if len - 4 < TFTP_BLOCK_SIZE {
+                    return Ok(data);
+                }
+            }
+            TFTP_ERROR => {
+                let message = packet[4..].split(|&byte| byte == 0).next().unwrap_or(&[]);
+                return Err(FetchError::Tftp(
+                    String::from_utf8_lossy(message).to_string(),
+                ));
+            }
+            _ => return Err(FetchError::BadResponse),
+        }
+    }
+}
+
+/// GET `path` from an HTTP server and return the body.
+pub async fn http_get(server: IpEndpoint, host: &str, path: &str) -> Result<Vec<u8>, FetchError> {
+    let mut stream = TcpStream::connect(server).await?;
+    let request = alloc::format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: blog_os\r\nConnection: close\r\n\r\n",
+        path,
+        host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // the server closes the connection after the response
+    let mut response = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let len = timer::timeout(HTTP_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| NetError::TimedOut)??;
+        if len == 0 {
+            break;
+        }
+        // leave room for the headers
+        if response.len() + len > MAX_SIZE + buf.len() {
+            return Err(FetchError::TooLarge);
+        }
+        response.extend_from_slice(&buf[..len]);
+    }
+    parse_response(&response)
+}
+
+/// The body of a complete HTTP/1.x response, decoding chunked transfer
+/// encoding and checking the status.
+fn parse_response(response: &[u8]) -> Result<Vec<u8>, FetchError> {
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or(FetchError::BadResponse)?;
+    let header =
+        core::str::from_utf8(&response[..header_end]).map_err(|_| FetchError::BadResponse)?;
+    let body = &response[header_end + 4..];
+    let mut lines = header.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.strip_prefix("HTTP/1."))
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(FetchError::BadResponse)?;
+    if !(200..300).contains(&status) {
+        return Err(FetchError::Http(status));
+    }
+    let mut chunked = false;
+    let mut content_length = None;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(FetchError::BadResponse)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(
+                value
+                    .parse::<usize>()
+                    .map_err(|_| FetchError::BadResponse)?,
+            );
+        }
+    }
+    if chunked {
+        return decode_chunked(body);
+    }
+    match content_length {
+        Some(length) if length > body.len() => Err(FetchError::BadResponse),
+        Some(length) => Ok(body[..length].to_vec()),
+        None => Ok(body.to_vec()),
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, FetchError> {
+    let mut data = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or(FetchError::BadResponse)?;
+        let size = core::str::from_utf8(&body[..line_end])
+            .ok()
+            // chunk extensions follow a semicolon
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or(FetchError::BadResponse)?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(data);
+        }
+        let end = size.checked_add(2).ok_or(FetchError::BadResponse)?;
+        if body.len() < end {
+            return Err(FetchError::BadResponse);
+        }
+        data.extend_from_slice(&body[..size]);
+        body = &body[end..];
+    }
+}
+
+#[test_case]
+fn test_parse_url() {
+    let url = Url::parse("tftp://10.0.2.2/prog.wasm").unwrap();
+    assert_eq!(url.scheme, Scheme::Tftp);
+    assert_eq!(url.server.port, TFTP_PORT);
+    assert_eq!(url.path, "prog.wasm");
+    assert_eq!(url.file_name(), Some("prog.wasm"));
+
+    let url = Url::parse("http://10.0.2.2:8000/bin/prog.wasm").unwrap();
+    assert_eq!(url.scheme, Scheme::Http);
+    assert_eq!(url.host, "10.0.2.2:8000");
+    assert_eq!(url.server.port, 8000);
+    assert_eq!(url.path, "/bin/prog.wasm");
+    assert_eq!(url.file_name(), Some("prog.wasm"));
+
+    assert_eq!(Url::parse("http://10.0.2.2").unwrap().file_name(), None);
+    assert_eq!(Url::parse("ftp://10.0.2.2/a"), Err(FetchError::BadUrl));
+    assert_eq!(Url::parse("http://example.com/"), Err(FetchError::BadUrl));
+}
+
+#[test_case]
+fn test_parse_response() {
+    let response = b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+    assert_eq!(parse_response(response), Ok(b"hello".to_vec()));
+    let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     3\r\nhel\r\n2;x=y\r\nlo\r\n0\r\n\r\n";
+    assert_eq!(parse_response(response), Ok(b"hello".to_vec()));
+    let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     ffffffffffffffff\r\n";
+    assert_eq!(parse_response(response), Err(FetchError::BadResponse));
+    let response = b"HTTP/1.0 404 File not found\r\n\r\n";
+    assert_eq!(parse_response(response), Err(FetchError::Http(404)));
+}
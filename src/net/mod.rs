@@ -0,0 +1,193 @@
+//! Network interfaces and the TCP/IP stack on top of them.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+mod commands;
+pub mod fetch;
+pub mod socket;
+pub mod stack;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// No interface with that index.
+    NotFound,
+    /// The transmit ring is full.
+    Busy,
+    /// The frame or datagram doesn't fit in the buffer.
+    TooLong,
+    /// DHCP hasn't configured an address yet, or there is no interface.
+    NotConfigured,
+    /// The address or port can't be used.
+    Unaddressable,
+    /// The other side refused or reset the connection.
+    Refused,
+    /// The connection is closed for this operation.
+    Closed,
+    TimedOut,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NetError::NotFound => "No such interface",
+            NetError::Busy => "Transmit queue full",
+            NetError::TooLong => "Message too long",
+            NetError::NotConfigured => "Network not configured",
+            NetError::Unaddressable => "Address not usable",
+            NetError::Refused => "Connection refused",
+            NetError::Closed => "Connection closed",
+            NetError::TimedOut => "Timed out",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+}
+
+/// An Ethernet interface.
+pub trait NetworkDevice: Send {
+    /// Short name, like `eth0`.
+    fn name(&self) -> &str;
+    fn model(&self) -> &str;
+    fn mac_address(&self) -> MacAddress;
+    fn link_up(&self) -> bool;
+    fn mtu(&self) -> usize {
+        1500
+    }
+    /// Queue an Ethernet frame, without the CRC, for sending.
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError>;
+    /// Take a received frame, if there is one.
+    fn receive(&mut self) -> Option<Vec<u8>>;
+    fn stats(&self) -> Stats;
+}
+
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub model: String,
+    pub mac_address: MacAddress,
+    pub link_up: bool,
+    pub mtu: usize,
+    pub stats: Stats,
+}
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<Box<dyn NetworkDevice>>> = Mutex::new(Vec::new());
+}
+
+/// Woken by drivers when frames arrive.
+static RECEIVE_WAKER: AtomicWaker = AtomicWaker::new();
+
+pub fn register(device: Box<dyn NetworkDevice>) {
+    log::info!(
+        "{}: {}, MAC {}",
+        device.name(),
+        device.model(),
+        device.mac_address()
+    );
+    DEVICES.lock().push(device);
+}
+
+pub fn list() -> Vec<InterfaceInfo> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|device| InterfaceInfo {
+            name: String::from(device.name()),
+            model: String::from(device.model()),
+            mac_address: device.mac_address(),
+            link_up: device.link_up(),
+            mtu: device.mtu(),
+            stats: device.stats(),
+        })
+        .collect()
+}
+
+pub fn transmit(interface: usize, frame: &[u8]) -> Result<(), NetError> {
+    DEVICES
+        .lock()
+        .get_mut(interface)
+        .ok_or(NetError::NotFound)?
+        .transmit(frame)
+}
+
+/// A frame received on `interface`, if one is waiting.
+pub fn try_receive(interface: usize) -> Option<Vec<u8>> {
+    // interrupt handlers wake the receiver, but never take the lock
+    DEVICES.lock().get_mut(interface)?.receive()
+}
+
+/// Called from interrupt handlers when frames have arrived.
+pub(crate) fn wake_receiver() {
+    RECEIVE_WAKER.wake();
+    stack::notify();
+}
+
+struct Receive {
+    interface: usize,
+}
+
+impl Future for Receive {
+    type Output = Vec<u8>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Vec<u8>> {
+        if let Some(frame) = try_receive(self.interface) {
+            return Poll::Ready(frame);
+        }
+        RECEIVE_WAKER.register(cx.waker());
+        // a frame may have arrived before the waker was registered
+        match try_receive(self.interface) {
+            Some(frame) => Poll::Ready(frame),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Wait for the next frame on `interface`. Only one task should be
+/// receiving at a time, and frames taken here never reach the stack.
+pub fn receive(interface: usize) -> impl Future<Output = Vec<u8>> {
+    Receive { interface }
+}
+
+/// Register the network commands and bring up the stack on the first
+/// interface. Call after the network drivers.
+pub fn init() {
+    commands::register();
+    stack::init();
+}
+
+#[test_case]
+fn test_mac_address_display() {
+    use alloc::string::ToString;
+
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    assert_eq!(mac.to_string(), "52:54:00:12:34:56");
+}
@@ -0,0 +1,368 @@
+//! Async sockets on top of the stack. They are polled like any other
+//! future; `stack::block_on` runs them from synchronous code.
+
+use alloc::vec;
+use core::future::poll_fn;
+use core::task::Poll;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::{icmp, tcp, udp, AnySocket};
+use smoltcp::wire::{Icmpv4Message, Icmpv4Packet};
+
+pub use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+use super::stack;
+use super::NetError;
+use crate::task::timer;
+use crate::time::Duration;
+
+/// How long `TcpStream::connect` waits for an answer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TCP_BUFFER_SIZE: usize = 16 * 1024;
+const UDP_PACKETS: usize = 16;
+const UDP_BUFFER_SIZE: usize = 16 * 1024;
+const ICMP_PACKETS: usize = 4;
+const ICMP_BUFFER_SIZE: usize = 4 * 1024;
+
+fn add<T: AnySocket<'static>>(socket: T) -> Result<SocketHandle, NetError> {
+    if stack::config().is_none() {
+        return Err(NetError::NotConfigured);
+    }
+    stack::add_socket(socket).ok_or(NetError::NotConfigured)
+}
+
+/// Run `f` on the socket, failing if the stack went away.
+fn poll_socket<T: AnySocket<'static>, R>(
+    handle: SocketHandle,
+    f: impl FnOnce(&mut T) -> Poll<Result<R, NetError>>,
+) -> Poll<Result<R, NetError>> {
+    stack::with_socket(handle, f).unwrap_or(Poll::Ready(Err(NetError::NotConfigured)))
+}
+
+pub struct UdpSocket {
+    handle: SocketHandle,
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind to `port`, or to a free one if it is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let port = if port == 0 {
+            stack::ephemeral_port()
+        } else {
+            port
+        };
+        let buffer = || {
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                vec![0; UDP_BUFFER_SIZE],
+            )
+        };
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        socket.bind(port).map_err(|_| NetError::Unaddressable)?;
+        Ok(UdpSocket {
+            handle: add(socket)?,
+            port,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn send_to(&self, data: &[u8], remote: IpEndpoint) -> Result<(), NetError> {
+        poll_fn(|cx| {
+            poll_socket(self.handle, |socket: &mut udp::Socket| {
+                match socket.send_slice(data, remote) {
+                    Ok(()) => Poll::Ready(Ok(())),
+                    Err(udp::SendError::BufferFull) => {
+                        socket.register_send_waker(cx.waker());
+                        Poll::Pending
+                    }
+                    Err(udp::SendError::Unaddressable) => Poll::Ready(Err(NetError::Unaddressable)),
+                }
+            })
+        })
+        .await?;
+        stack::notify();
+        Ok(())
+    }
+
+    /// Receive a datagram into `buf`, returning its length and sender.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, IpEndpoint), NetError> {
+        poll_fn(|cx| {
+            poll_socket(self.handle, |socket: &mut udp::Socket| {
+                match socket.recv_slice(buf) {
+                    Ok((len, metadata)) => Poll::Ready(Ok((len, metadata.endpoint))),
+                    Err(udp::RecvError::Truncated) => Poll::Ready(Err(NetError::TooLong)),
+                    Err(udp::RecvError::Exhausted) => {
+                        socket.register_recv_waker(cx.waker());
+                        Poll::Pending
+                    }
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        stack::remove_socket(self.handle);
+    }
+}
+
+pub struct TcpStream {
+    handle: SocketHandle,
+}
+
+fn tcp_socket() -> tcp::Socket<'static> {
+    tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    )
+}
+
+impl TcpStream {
+    pub async fn connect(remote: IpEndpoint) -> Result<TcpStream, NetError> {
+        let stream = TcpStream {
+            handle: add(tcp_socket())?,
+        };
+        let handle = stream.handle;
+        stack::with(|stack| {
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            socket.connect(stack.iface.context(), remote, stack::ephemeral_port())
+        })
+        .ok_or(NetError::NotConfigured)?
+        .map_err(|_| NetError::Unaddressable)?;
+        stack::notify();
+
+        let established = poll_fn(|cx| {
+            poll_socket(handle, |socket: &mut tcp::Socket| match socket.state() {
+                tcp::State::SynSent | tcp::State::SynReceived => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                tcp::State::Closed => Poll::Ready(Err(NetError::Refused)),
+                _ => Poll::Ready(Ok(())),
+            })
+        });
+        timer::timeout(CONNECT_TIMEOUT, established)
+            .await
+            .map_err(|_| NetError::TimedOut)??;
+        Ok(stream)
+    }
+
+    pub fn remote_endpoint(&self) -> Option<IpEndpoint> {
+        stack::with_socket(self.handle, |socket: &mut tcp::Socket| {
+            socket.remote_endpoint()
+        })
+        .flatten()
+    }
+
+    /// Read what has arrived into `buf`, waiting if nothing has. Returns 0
+    /// once the other side has closed the connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        let len = poll_fn(|cx| {
+            poll_socket(self.handle, |socket: &mut tcp::Socket| {
+                match socket.recv_slice(buf) {
+                    Ok(0) if socket.may_recv() => {
+                        socket.register_recv_waker(cx.waker());
+                        Poll::Pending
+                    }
+                    Ok(len) => Poll::Ready(Ok(len)),
+                    Err(tcp::RecvError::Finished) => Poll::Ready(Ok(0)),
+                    Err(tcp::RecvError::InvalidState) => Poll::Ready(Err(NetError::Closed)),
+                }
+            })
+        })
+        .await?;
+        // let the window update go out
+        stack::notify();
+        Ok(len)
+    }
+
+    /// Queue as much of `data` as fits, waiting for room if none does.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, NetError> {
+        let len = poll_fn(|cx| {
+            poll_socket(self.handle, |socket: &mut tcp::Socket| {
+                if !socket.may_send() {
+                    return Poll::Ready(Err(NetError::Closed));
+                }
+                match socket.send_slice(data) {
+                    Ok(0) if !data.is_empty() => {
+                        socket.register_send_waker(cx.waker());
+                        Poll::Pending
+                    }
+                    Ok(len) => Poll::Ready(Ok(len)),
+                    Err(tcp::SendError::InvalidState) => Poll::Ready(Err(NetError::Closed)),
+                }
+            })
+        })
+        .await?;
+        stack::notify();
+        Ok(len)
+    }
+
+    pub async fn write_all(&mut self, mut data: &[u8]) -> Result<(), NetError> {
+        while !data.is_empty() {
+            let len = self.write(data).await?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Tell the other side nothing more will be sent. Reading still works
+    /// until it closes its side too.
+    pub fn shutdown(&mut self) {
+        stack::with_socket(self.handle, |socket: &mut tcp::Socket| socket.close());
+        stack::notify();
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        // queued data is still sent before the connection is closed
+        stack::close_socket(self.handle);
+    }
+}
+
+pub struct TcpListener {
+    handle: SocketHandle,
+    port: u16,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        Ok(TcpListener {
+            handle: TcpListener::listen(port)?,
+            port,
+        })
+    }
+
+    fn listen(port: u16) -> Result<SocketHandle, NetError> {
+        let mut socket = tcp_socket();
+        socket.listen(port).map_err(|_| NetError::Unaddressable)?;
+        add(socket)
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for a connection. Another one can come in as soon as this
+    /// returns.
+    pub async fn accept(&mut self) -> Result<TcpStream, NetError> {
+        let handle = self.handle;
+        poll_fn(|cx| {
+            poll_socket(handle, |socket: &mut tcp::Socket| match socket.state() {
+                tcp::State::Listen | tcp::State::SynReceived => {
+                    socket.register_recv_waker(cx.waker());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(Ok(())),
+            })
+        })
+        .await?;
+        self.handle = TcpListener::listen(self.port)?;
+        Ok(TcpStream { handle })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        stack::remove_socket(self.handle);
+    }
+}
+
+/// An ICMP socket for sending echo requests and getting their replies.
+pub struct IcmpSocket {
+    handle: SocketHandle,
+    ident: u16,
+}
+
+impl IcmpSocket {
+    pub fn bind(ident: u16) -> Result<IcmpSocket, NetError> {
+        let buffer = || {
+            icmp::PacketBuffer::new(
+                vec![icmp::PacketMetadata::EMPTY; ICMP_PACKETS],
+                vec![0; ICMP_BUFFER_SIZE],
+            )
+        };
+        let mut socket = icmp::Socket::new(buffer(), buffer());
+        socket
+            .bind(icmp::Endpoint::Ident(ident))
+            .map_err(|_| NetError::Unaddressable)?;
+        Ok(IcmpSocket {
+            handle: add(socket)?,
+            ident,
+        })
+    }
+
+    pub async fn send_echo_request(
+        &self,
+        address: Ipv4Address,
+        seq_no: u16,
+        data: &[u8],
+    ) -> Result<(), NetError> {
+        let mut packet = vec![0; 8 + data.len()];
+        let mut request = Icmpv4Packet::new_unchecked(&mut packet);
+        request.set_msg_type(Icmpv4Message::EchoRequest);
+        request.set_msg_code(0);
+        request.set_echo_ident(self.ident);
+        request.set_echo_seq_no(seq_no);
+        request.data_mut().copy_from_slice(data);
+        request.fill_checksum();
+        poll_fn(|cx| {
+            poll_socket(self.handle, |socket: &mut icmp::Socket| {
+                match socket.send_slice(&packet, IpAddress::Ipv4(address)) {
+                    Ok(()) => Poll::Ready(Ok(())),
+                    Err(icmp::SendError::BufferFull) => {
+                        socket.register_send_waker(cx.waker());
+                        Poll::Pending
+                    }
+                    Err(icmp::SendError::Unaddressable) => {
+                        Poll::Ready(Err(NetError::Unaddressable))
+                    }
+                }
+            })
+        })
+        .await?;
+        stack::notify();
+        Ok(())
+    }
+
+    /// Wait for an echo reply, returning its sender, sequence number and
+    /// data length.
+    pub async fn recv_echo_reply(&self) -> Result<(IpAddress, u16, usize), NetError> {
+        let mut packet = vec![0; ICMP_BUFFER_SIZE];
+        loop {
+            let (len, from) = poll_fn(|cx| {
+                poll_socket(self.handle, |socket: &mut icmp::Socket| {
+                    match socket.recv_slice(&mut packet) {
+                        Ok(received) => Poll::Ready(Ok(received)),
+                        Err(icmp::RecvError::Truncated) => Poll::Ready(Err(NetError::TooLong)),
+                        Err(icmp::RecvError::Exhausted) => {
+                            socket.register_recv_waker(cx.waker());
+                            Poll::Pending
+                        }
+                    }
+                })
+            })
+            .await?;
+            let reply = match Icmpv4Packet::new_checked(&packet[..len]) {
+                Ok(reply) => reply,
+                Err(_) => continue,
+            };
+            if reply.msg_type() == Icmpv4Message::EchoReply && reply.echo_ident() == self.ident {
+                return Ok((from, reply.echo_seq_no(), reply.data().len()));
+            }
+        }
+    }
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        stack::remove_socket(self.handle);
+    }
+}
@@ -0,0 +1,305 @@
+//! The TCP/IP stack: smoltcp on the first interface, configured by DHCP and
+//! polled by the `net` task.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::{noop_waker_ref, AtomicWaker};
+use lazy_static::lazy_static;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::{dhcpv4, tcp, AnySocket};
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+use spin::Mutex;
+
+use crate::task::timer;
+use crate::time::{self, Duration};
+
+/// Longest wait between polls, in case an interrupt is missed or the card
+/// has none.
+const MAX_POLL_DELAY: Duration = Duration::from_millis(20);
+
+/// Ethernet header and payload
+const MAX_FRAME_SIZE: usize = 1514;
+
+const EPHEMERAL_PORTS: core::ops::Range<u16> = 49152..65535;
+
+/// The address configuration handed out by DHCP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Cidr,
+    pub router: Option<Ipv4Address>,
+    pub dns_server: Option<Ipv4Address>,
+}
+
+pub(super) struct Stack {
+    pub(super) iface: Interface,
+    device: Port,
+    pub(super) sockets: SocketSet<'static>,
+    dhcp: SocketHandle,
+    config: Option<Ipv4Config>,
+    /// TCP sockets that were dropped while still sending, to be removed
+    /// once they are closed.
+    closing: Vec<SocketHandle>,
+}
+
+lazy_static! {
+    static ref STACK: Mutex<Option<Stack>> = Mutex::new(None);
+}
+
+/// Wakes the `net` task.
+static WAKER: AtomicWaker = AtomicWaker::new();
+static NOTIFIED: AtomicBool = AtomicBool::new(false);
+static NEXT_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORTS.start);
+
+/// A network interface as a smoltcp device.
+struct Port {
+    interface: usize,
+}
+
+struct RxToken(Vec<u8>);
+
+struct TxToken {
+    interface: usize,
+}
+
+impl phy::Device for Port {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken;
+
+    fn receive(&mut self, _timestamp: smoltcp::time::Instant) -> Option<(RxToken, TxToken)> {
+        let frame = super::try_receive(self.interface)?;
+        Some((
+            RxToken(frame),
+            TxToken {
+                interface: self.interface,
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<TxToken> {
+        Some(TxToken {
+            interface: self.interface,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = MAX_FRAME_SIZE;
+        capabilities.max_burst_size = Some(1);
+        capabilities
+    }
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        // a full ring loses the frame; TCP sends it again
+        let _ = super::transmit(self.interface, &frame);
+        result
+    }
+}
+
+fn now() -> smoltcp::time::Instant {
+    smoltcp::time::Instant::from_micros(time::uptime().as_micros() as i64)
+}
+
+impl Stack {
+    fn poll(&mut self) {
+        self.iface.poll(now(), &mut self.device, &mut self.sockets);
+        self.update_config();
+        let sockets = &mut self.sockets;
+        self.closing.retain(|&handle| {
+            let closed = matches!(
+                sockets.get::<tcp::Socket>(handle).state(),
+                tcp::State::Closed | tcp::State::TimeWait
+            );
+            if closed {
+                sockets.remove(handle);
+            }
+            !closed
+        });
+    }
+
+    /// Apply what the DHCP client learned.
+    fn update_config(&mut self) {
+        let config = match self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp).poll() {
+            None => return,
+            Some(dhcpv4::Event::Configured(config)) => Some(Ipv4Config {
+                address: config.address,
+                router: config.router,
+                dns_server: config.dns_servers.first().copied(),
+            }),
+            Some(dhcpv4::Event::Deconfigured) => None,
+        };
+        self.iface.update_ip_addrs(|addresses| {
+            addresses.clear();
+            if let Some(config) = config {
+                addresses.push(IpCidr::Ipv4(config.address)).ok();
+            }
+        });
+        match config.and_then(|config| config.router) {
+            Some(router) => {
+                self.iface.routes_mut().add_default_ipv4_route(router).ok();
+            }
+            None => {
+                self.iface.routes_mut().remove_default_ipv4_route();
+            }
+        }
+        match config {
+            Some(config) => log::info!(
+                "DHCP: address {}, gateway {:?}, DNS {:?}",
+                config.address,
+                config.router,
+                config.dns_server
+            ),
+            None => log::info!("DHCP: lost the address"),
+        }
+        self.config = config;
+    }
+}
+
+/// Set up the stack on the first network interface, if there is one.
+pub fn init() -> bool {
+    let mac = match super::list().first() {
+        Some(interface) => interface.mac_address,
+        None => return false,
+    };
+    let mut device = Port { interface: 0 };
+    let mut config = Config::new(HardwareAddress::Ethernet(EthernetAddress(mac.0)));
+    config.random_seed = unsafe { core::arch::x86_64::_rdtsc() };
+    let iface = Interface::new(config, &mut device, now());
+    let mut sockets = SocketSet::new(Vec::new());
+    let dhcp = sockets.add(dhcpv4::Socket::new());
+    *STACK.lock() = Some(Stack {
+        iface,
+        device,
+        sockets,
+        dhcp,
+        config: None,
+        closing: Vec::new(),
+    });
+    true
+}
+
+/// Run `f` on the stack, if there is one.
+pub(super) fn with<R>(f: impl FnOnce(&mut Stack) -> R) -> Option<R> {
+    STACK.lock().as_mut().map(f)
+}
+
+pub(super) fn with_socket<T: AnySocket<'static>, R>(
+    handle: SocketHandle,
+    f: impl FnOnce(&mut T) -> R,
+) -> Option<R> {
+    with(|stack| f(stack.sockets.get_mut::<T>(handle)))
+}
+
+pub(super) fn add_socket<T: AnySocket<'static>>(socket: T) -> Option<SocketHandle> {
+    with(|stack| stack.sockets.add(socket))
+}
+
+pub(super) fn remove_socket(handle: SocketHandle) {
+    with(|stack| stack.sockets.remove(handle));
+}
+
+/// Close a TCP socket gracefully, removing it once it's done.
+pub(super) fn close_socket(handle: SocketHandle) {
+    with(|stack| {
+        stack.sockets.get_mut::<tcp::Socket>(handle).close();
+        stack.closing.push(handle);
+    });
+    notify();
+}
+
+pub(super) fn ephemeral_port() -> u16 {
+    let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+    if !EPHEMERAL_PORTS.contains(&port) {
+        NEXT_PORT.store(EPHEMERAL_PORTS.start + 1, Ordering::Relaxed);
+        return EPHEMERAL_PORTS.start;
+    }
+    port
+}
+
+/// The address configuration, once DHCP has completed.
+pub fn config() -> Option<Ipv4Config> {
+    with(|stack| stack.config).flatten()
+}
+
+/// Process received frames and send what the sockets have queued.
+pub fn poll() {
+    with(Stack::poll);
+}
+
+/// Have the `net` task poll the stack soon, e.g. after queueing data.
+/// Safe to call from interrupt handlers.
+pub fn notify() {
+    NOTIFIED.store(true, Ordering::Release);
+    WAKER.wake();
+}
+
+struct Notified;
+
+impl Future for Notified {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if NOTIFIED.swap(false, Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        WAKER.register(cx.waker());
+        if NOTIFIED.swap(false, Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// The `net` task: poll the stack whenever frames arrive, sockets have
+/// something to send or smoltcp's timers are due.
+pub async fn run() {
+    if with(|_| ()).is_none() {
+        return;
+    }
+    loop {
+        poll();
+        let delay = with(|stack| stack.iface.poll_delay(now(), &stack.sockets))
+            .flatten()
+            .map(|delay| Duration::from_micros(delay.total_micros()))
+            .unwrap_or(MAX_POLL_DELAY)
+            .min(MAX_POLL_DELAY);
+        let _ = timer::timeout(delay, Notified).await;
+    }
+}
+
+/// Run `future` to completion outside the executor, polling the stack
+/// while it waits. For code that can't yield, like WASM host calls.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(noop_waker_ref());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        poll();
+        // until the next frame or timer tick
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,511 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::shell::command::{self, Builtin, Handler};
+use crate::{acpi, memory, println};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Offsets in the configuration space header
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Header type bit for devices with more than one function.
+const MULTIFUNCTION: u8 = 0x80;
+
+/// Capability IDs
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// How configuration space is reached.
+enum Access {
+    /// Through the address and data ports, limited to 256 bytes per function.
+    Legacy,
+    /// Memory mapped (ECAM), from the ACPI MCFG table.
+    Ecam {
+        /// Where the region is mapped
+        base: VirtAddr,
+        start_bus: u8,
+        end_bus: u8,
+    },
+}
+
+lazy_static! {
+    static ref ACCESS: Access = find_ecam().unwrap_or(Access::Legacy);
+    static ref DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+    static ref DRIVERS: Mutex<Vec<Driver>> = Mutex::new(Vec::new());
+}
+
+/// The first ECAM region of segment 0 in the MCFG, mapped whole so that
+/// config space accesses don't have to map anything.
+fn find_ecam() -> Option<Access> {
+    let mcfg = acpi::find_table(b"MCFG")?;
+    // 8 reserved bytes, then 16-byte entries
+    let entry = mcfg
+        .body()
+        .get(8..)?
+        .chunks_exact(16)
+        .find(|entry| u16::from_le_bytes(entry[8..10].try_into().unwrap()) == 0)?;
+    let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+    let (start_bus, end_bus) = (entry[10], entry[11]);
+    // 1 MiB of config space per bus
+    let size = ((end_bus.checked_sub(start_bus)? as u64) + 1) << 20;
+    let base = memory::map_mmio(PhysAddr::new(base), size).ok()?;
+    Some(Access::Ecam {
+        base,
+        start_bus,
+        end_bus,
+    })
+}
+
+/// A pointer to the configuration space of `address` in the ECAM region.
+fn ecam_register(address: PciAddress, offset: u16) -> Option<*mut u32> {
+    match *ACCESS {
+        Access::Ecam {
+            base,
+            start_bus,
+            end_bus,
+        } if (start_bus..=end_bus).contains(&address.bus) => {
+            let function = base.as_u64()
+                + (((address.bus - start_bus) as u64) << 20
+                    | (address.device as u64) << 15
+                    | (address.function as u64) << 12);
+            Some((function + (offset & 0xffc) as u64) as *mut u32)
+        }
+        _ => None,
+    }
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset & 0xfc) as u32
+}
+
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    if let Some(register) = ecam_register(address, offset) {
+        return unsafe { register.read_volatile() };
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    })
+}
+
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    if let Some(register) = ecam_register(address, offset) {
+        return unsafe { register.write_volatile(value) };
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+        Port::<u32>::new(CONFIG_DATA).write(value);
+    })
+}
+
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    (read_u32(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    (read_u32(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+pub fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    let shift = (offset & 2) * 8;
+    let old = read_u32(address, offset) & !(0xffff << shift);
+    write_u32(address, offset, old | (value as u32) << shift);
+}
+
+/// A base address register, decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    None,
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the capability in configuration space.
+    pub offset: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Bar; 6],
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub capabilities: Vec<Capability>,
+    /// Name of the driver that claimed the device.
+    pub driver: Option<&'static str>,
+}
+
+impl Device {
+    pub fn command(&self) -> u16 {
+        read_u16(self.address, COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        write_u16(self.address, COMMAND, command);
+    }
+
+    /// Let the device respond to its I/O and memory BARs and do DMA.
+    pub fn enable(&self) {
+        self.set_command(self.command() | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().copied().find(|cap| cap.id == id)
+    }
+}
+
+/// Size and decode the six BARs of a type 0 header.
+fn read_bars(address: PciAddress) -> [Bar; 6] {
+    let mut bars = [Bar::None; 6];
+    let command = read_u16(address, COMMAND);
+    // stop decoding while the BARs hold all ones
+    write_u16(address, COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+    let mut i = 0;
+    while i < 6 {
+        let offset = BAR0 + i as u16 * 4;
+        let value = read_u32(address, offset);
+        write_u32(address, offset, !0);
+        let mask = read_u32(address, offset);
+        write_u32(address, offset, value);
+
+        if value & 1 == 1 {
+            let size = !(mask & !0x3) as u16;
+            if mask != 0 {
+                bars[i] = Bar::Io {
+                    port: (value & !0x3) as u16,
+                    size: size.wrapping_add(1),
+                };
+            }
+            i += 1;
+            continue;
+        }
+        let is_64 = (value >> 1) & 0x3 == 0x2;
+        let mut address_value = (value & !0xf) as u64;
+        let mut mask_value = (mask & !0xf) as u64;
+        if is_64 && i < 5 {
+            let high = read_u32(address, offset + 4);
+            write_u32(address, offset + 4, !0);
+            let high_mask = read_u32(address, offset + 4);
+            write_u32(address, offset + 4, high);
+            address_value |= (high as u64) << 32;
+            mask_value |= (high_mask as u64) << 32;
+        } else {
+            mask_value |= 0xffff_ffff_0000_0000;
+        }
+        if mask_value & 0xffff_ffff != 0 {
+            bars[i] = Bar::Memory {
+                address: address_value,
+                size: (!mask_value).wrapping_add(1),
+                prefetchable: value & 0x8 != 0,
+            };
+        }
+        i += if is_64 { 2 } else { 1 };
+    }
+    write_u16(address, COMMAND, command);
+    bars
+}
+
+fn read_capabilities(address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if read_u16(address, STATUS) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    let mut offset = (read_u8(address, CAPABILITIES_POINTER) & 0xfc) as u16;
+    // the list lives in the 192 bytes after the header, which bounds its
+    // length even if it loops
+    while offset != 0 && capabilities.len() < 48 {
+        capabilities.push(Capability {
+            id: read_u8(address, offset),
+            offset,
+        });
+        offset = (read_u8(address, offset + 1) & 0xfc) as u16;
+    }
+    capabilities
+}
+
+fn read_device(address: PciAddress) -> Option<Device> {
+    let id = read_u32(address, VENDOR_ID);
+    if id & 0xffff == 0xffff {
+        return None;
+    }
+    let class = read_u32(address, REVISION);
+    let header_type = read_u8(address, HEADER_TYPE);
+    let interrupt = read_u32(address, INTERRUPT_LINE);
+    Some(Device {
+        address,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type,
+        // only general devices have six BARs
+        bars: if header_type & 0x7f == 0 {
+            read_bars(address)
+        } else {
+            [Bar::None; 6]
+        },
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+        capabilities: read_capabilities(address),
+        driver: None,
+    })
+}
+
+/// Every function on every bus.
+fn scan() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = PciAddress {
+                bus,
+                device,
+                function: 0,
+            };
+            let header = match read_device(first) {
+                Some(header) => header,
+                None => continue,
+            };
+            let functions = if header.header_type & MULTIFUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+            devices.push(header);
+            for function in 1..functions {
+                devices.extend(read_device(PciAddress {
+                    bus,
+                    device,
+                    function,
+                }));
+            }
+        }
+    }
+    devices
+}
+
+/// Which devices a driver handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    Id { vendor: u16, device: u16 },
+    Class { class: u8, subclass: u8 },
+}
+
+impl Match {
+    fn matches(&self, device: &Device) -> bool {
+        match *self {
+            Match::Id { vendor, device: id } => {
+                device.vendor_id == vendor && device.device_id == id
+            }
+            Match::Class { class, subclass } => {
+                device.class == class && device.subclass == subclass
+            }
+        }
+    }
+}
+
+/// A driver for PCI devices. `probe` is called for every matching device
+/// that isn't claimed yet, and returns whether it took the device.
+#[derive(Clone, Copy)]
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    pub probe: fn(&Device) -> bool,
+}
+
+/// Offer the unclaimed devices matching `driver` to it.
+fn bind(driver: &Driver) {
+    let candidates: Vec<Device> = DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.driver.is_none())
+        .filter(|device| driver.matches.iter().any(|m| m.matches(device)))
+        .cloned()
+        .collect();
+    // probing may take a while and use the PCI functions, so don't hold
+    // the lock
+    for device in candidates {
+        if (driver.probe)(&device) {
+            log::info!("{} bound to {}", device.address, driver.name);
+            let mut devices = DEVICES.lock();
+            if let Some(claimed) = devices.iter_mut().find(|d| d.address == device.address) {
+                claimed.driver = Some(driver.name);
+            }
+        }
+    }
+}
+
+/// Add a driver, binding it to any matching devices found so far.
+pub fn register_driver(driver: Driver) {
+    DRIVERS.lock().push(driver);
+    bind(&driver);
+}
+
+/// All devices found by `init`.
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+/// Enumerate the bus and bind the registered drivers.
+///
+/// Needs `memory::install` to have been called when the machine has ECAM.
+pub fn init() {
+    *DEVICES.lock() = scan();
+    let drivers = DRIVERS.lock().clone();
+    for driver in &drivers {
+        bind(driver);
+    }
+    command::register(Builtin {
+        name: "lspci",
+        usage: "lspci [-v]",
+        help: "List PCI devices. With -v, also show their BARs and\n\
+               capabilities.",
+        handler: Handler::Sync(lspci),
+    });
+}
+
+/// A name for a class code.
+pub fn class_name(class: u8, subclass: u8) -> String {
+    let name = match (class, subclass) {
+        (0x01, 0x00) => "SCSI controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        _ => return format!("Class {:02x}{:02x}", class, subclass),
+    };
+    String::from(name)
+}
+
+fn lspci(args: &[String]) -> i32 {
+    let verbose = args.get(1).map(String::as_str) == Some("-v");
+    for device in devices() {
+        println!(
+            "{} {:04x}:{:04x} {}{}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            class_name(device.class, device.subclass),
+            device
+                .driver
+                .map(|name| format!(" [{}]", name))
+                .unwrap_or_default()
+        );
+        if !verbose {
+            continue;
+        }
+        for (i, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Bar::None => {}
+                Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                } => println!(
+                    "    BAR{}: memory at {:#x} ({} KiB{})",
+                    i,
+                    address,
+                    size / 1024,
+                    if *prefetchable { ", prefetchable" } else { "" }
+                ),
+                Bar::Io { port, size } => {
+                    println!("    BAR{}: I/O ports at {:#x} ({} bytes)", i, port, size)
+                }
+            }
+        }
+        if device.interrupt_pin != 0 {
+            let pin = match device.interrupt_pin {
+                pin @ 1..=4 => (b'A' + pin - 1) as char,
+                _ => '?',
+            };
+            println!("    IRQ {}, pin {}", device.interrupt_line, pin);
+        }
+        for cap in &device.capabilities {
+            println!("    capability {:#04x} at {:#04x}", cap.id, cap.offset);
+        }
+    }
+    0
+}
+
+#[test_case]
+fn test_legacy_address() {
+    let address = PciAddress {
+        bus: 1,
+        device: 2,
+        function: 3,
+    };
+    assert_eq!(legacy_address(address, 0x10), 0x8001_1310);
+    assert_eq!(address.to_string(), "01:02.3");
+}
@@ -0,0 +1,138 @@
+use core::hint::spin_loop;
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::PhysAddr;
+
+use crate::acpi::{self, Fadt};
+use crate::time::{Duration, Instant};
+use crate::{block, history, hlt_loop, memory, println};
+
+/// SCI_EN in PM1 control: the chipset is in ACPI mode.
+const PM1_SCI_EN: u16 = 1 << 0;
+/// SLP_EN in PM1 control: enter the sleep state in SLP_TYP.
+const PM1_SLP_EN: u16 = 1 << 13;
+
+const KEYBOARD_CONTROLLER: u16 = 0x64;
+/// Input buffer full
+const KEYBOARD_STATUS_INPUT_FULL: u8 = 0x02;
+/// Pulse the CPU reset line
+const KEYBOARD_RESET: u8 = 0xfe;
+
+/// How long each way of rebooting or powering off gets to work.
+const GRACE: Duration = Duration::from_millis(500);
+
+fn wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        spin_loop();
+    }
+}
+
+/// Make sure everything written to the disks has reached them.
+fn sync() {
+    history::flush();
+    block::flush();
+}
+
+/// Switch the chipset to ACPI mode if the firmware hasn't done it yet.
+fn enable_acpi(fadt: &Fadt) {
+    let mut control = Port::<u16>::new(fadt.pm1a_control as u16);
+    if unsafe { control.read() } & PM1_SCI_EN != 0 || fadt.smi_command == 0 {
+        return;
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    let start = Instant::now();
+    while unsafe { control.read() } & PM1_SCI_EN == 0 && start.elapsed() < GRACE {
+        spin_loop();
+    }
+}
+
+/// Enter ACPI sleep state S5 (soft off), returning if that isn't possible.
+fn acpi_power_off() {
+    let fadt = match acpi::fadt() {
+        Some(fadt) if fadt.pm1a_control != 0 => fadt,
+        _ => return,
+    };
+    let sleep_type = match acpi::dsdt().and_then(|dsdt| acpi::parse_s5(dsdt.body())) {
+        Some(sleep_type) => sleep_type,
+        None => return,
+    };
+    enable_acpi(&fadt);
+    unsafe {
+        Port::<u16>::new(fadt.pm1a_control as u16).write(sleep_type.a << 10 | PM1_SLP_EN);
+        if fadt.pm1b_control != 0 {
+            Port::<u16>::new(fadt.pm1b_control as u16).write(sleep_type.b << 10 | PM1_SLP_EN);
+        }
+    }
+    wait(GRACE);
+}
+
+/// Flush the disks and turn the machine off.
+pub fn shutdown() -> ! {
+    sync();
+    interrupts::disable();
+    acpi_power_off();
+    // emulators that don't implement S5 properly
+    unsafe {
+        Port::<u16>::new(0x604).write(0x2000); // QEMU
+        Port::<u16>::new(0xb004).write(0x2000); // Bochs and older QEMU
+    }
+    wait(GRACE);
+    println!("It is now safe to turn off your computer.");
+    hlt_loop();
+}
+
+/// Write the reset value to the ACPI reset register, if there is one.
+fn acpi_reset() {
+    let fadt = match acpi::fadt() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    let register = match fadt.reset_register {
+        Some(register) => register,
+        None => return,
+    };
+    match register.space {
+        0 => {
+            if let Ok(virt) = memory::map_mmio(PhysAddr::new(register.address), 1) {
+                unsafe { virt.as_mut_ptr::<u8>().write_volatile(fadt.reset_value) };
+            }
+        }
+        1 => unsafe { Port::<u8>::new(register.address as u16).write(fadt.reset_value) },
+        _ => return,
+    }
+    wait(GRACE);
+}
+
+/// Ask the keyboard controller to pulse the reset line.
+fn keyboard_controller_reset() {
+    let mut port = Port::<u8>::new(KEYBOARD_CONTROLLER);
+    let start = Instant::now();
+    while unsafe { port.read() } & KEYBOARD_STATUS_INPUT_FULL != 0 && start.elapsed() < GRACE {
+        spin_loop();
+    }
+    unsafe { port.write(KEYBOARD_RESET) };
+    wait(GRACE);
+}
+
+/// Load an empty IDT and raise an exception, which the CPU can't handle and
+/// so resets.
+fn triple_fault() -> ! {
+    lazy_static! {
+        static ref EMPTY: InterruptDescriptorTable = InterruptDescriptorTable::new();
+    }
+    EMPTY.load();
+    x86_64::instructions::interrupts::int3();
+    hlt_loop();
+}
+
+/// Flush the disks and restart the machine.
+pub fn reboot() -> ! {
+    sync();
+    interrupts::disable();
+    acpi_reset();
+    keyboard_controller_reset();
+    triple_fault();
+}
@@ -0,0 +1,275 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::time::{self, Duration};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Setting this bit in the address port keeps NMIs disabled while the
+/// CMOS is being accessed. The address is written again without it
+/// afterwards to enable them again.
+const NMI_DISABLE: u8 = 0x80;
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+enum Register {
+    Second = 0x00,
+    Minute = 0x02,
+    Hour = 0x04,
+    Day = 0x07,
+    Month = 0x08,
+    Year = 0x09,
+    StatusA = 0x0a,
+    StatusB = 0x0b,
+    StatusC = 0x0c,
+    /// Not standardised, but present in QEMU and most PCs.
+    Century = 0x32,
+}
+
+/// Update in progress
+const STATUS_A_UIP: u8 = 0x80;
+/// Periodic interrupt enable
+const STATUS_B_PIE: u8 = 0x40;
+/// Values are binary instead of BCD
+const STATUS_B_BINARY: u8 = 0x04;
+/// Hours are 0-23 instead of 1-12 with the PM flag
+const STATUS_B_24_HOUR: u8 = 0x02;
+const HOUR_PM: u8 = 0x80;
+/// A periodic interrupt happened
+const STATUS_C_PERIODIC: u8 = 0x40;
+
+/// Unix time in seconds at boot, worked out from the RTC in `init`.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Periodic interrupts since they were enabled.
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn read_register(register: Register) -> u8 {
+    let mut address = Port::<u8>::new(CMOS_ADDRESS);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        address.write(NMI_DISABLE | register as u8);
+        let value = data.read();
+        address.write(register as u8);
+        value
+    }
+}
+
+fn write_register(register: Register, value: u8) {
+    let mut address = Port::<u8>::new(CMOS_ADDRESS);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        address.write(NMI_DISABLE | register as u8);
+        data.write(value);
+        address.write(register as u8);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// A calendar date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn to_unix(&self) -> u64 {
+        // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let (year, month) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        (days * 86400 + seconds).max(0) as u64
+    }
+
+    pub fn from_unix(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64 + 719_468;
+        let seconds = timestamp % 86400;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The raw registers, read between two updates.
+fn read_raw() -> [u8; 7] {
+    let registers = [
+        Register::Second,
+        Register::Minute,
+        Register::Hour,
+        Register::Day,
+        Register::Month,
+        Register::Year,
+        Register::Century,
+    ];
+    let read = || {
+        while read_register(Register::StatusA) & STATUS_A_UIP != 0 {
+            core::hint::spin_loop();
+        }
+        registers.map(read_register)
+    };
+    // an update may still start while reading, so read until two agree
+    let mut last = read();
+    loop {
+        let values = read();
+        if values == last {
+            return values;
+        }
+        last = values;
+    }
+}
+
+/// Read the date and time from the CMOS clock, which is assumed to be
+/// set to UTC.
+pub fn read() -> DateTime {
+    let [second, minute, hour, day, month, year, century] =
+        interrupts::without_interrupts(read_raw);
+    let status_b = interrupts::without_interrupts(|| read_register(Register::StatusB));
+
+    let convert = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let pm = hour & HOUR_PM != 0;
+    let mut hour = convert(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match convert(century) {
+        century @ 19..=99 => century as u16,
+        // no century register
+        _ => 20,
+    };
+    DateTime {
+        year: century * 100 + convert(year) as u16,
+        month: convert(month),
+        day: convert(day),
+        hour,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+/// Read the clock once so that `now` doesn't have to touch the hardware.
+pub fn init() {
+    let boot = read().to_unix().saturating_sub(time::uptime().as_secs());
+    BOOT_TIME.store(boot, Ordering::Relaxed);
+}
+
+/// Time since 1970-01-01 00:00:00 UTC.
+pub fn unix_time() -> Duration {
+    Duration::from_secs(BOOT_TIME.load(Ordering::Relaxed)) + time::uptime()
+}
+
+/// The current date and time in UTC.
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_time().as_secs())
+}
+
+/// Make the RTC interrupt on IRQ 8 at 32768 >> (`rate` - 1) Hz, for `rate`
+/// from 3 (8192 Hz) to 15 (2 Hz).
+pub fn enable_periodic_interrupt(rate: u8) {
+    let rate = rate.clamp(3, 15);
+    interrupts::without_interrupts(|| {
+        let status_a = read_register(Register::StatusA);
+        write_register(Register::StatusA, (status_a & 0xf0) | rate);
+        let status_b = read_register(Register::StatusB);
+        write_register(Register::StatusB, status_b | STATUS_B_PIE);
+        // the interrupt isn't raised again until status C has been read
+        read_register(Register::StatusC);
+    });
+    crate::interrupts::enable_irq(8);
+}
+
+pub fn disable_periodic_interrupt() {
+    interrupts::without_interrupts(|| {
+        let status_b = read_register(Register::StatusB);
+        write_register(Register::StatusB, status_b & !STATUS_B_PIE);
+    });
+}
+
+/// Periodic interrupts since they were first enabled.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Called by the IRQ 8 handler.
+pub(crate) fn handle_interrupt() {
+    // reading status C acknowledges the interrupt
+    if read_register(Register::StatusC) & STATUS_C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_unix_time_conversion() {
+    use alloc::string::ToString;
+
+    let epoch = DateTime::from_unix(0);
+    assert_eq!(epoch.to_string(), "1970-01-01 00:00:00");
+    let date = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 45,
+        second: 30,
+    };
+    assert_eq!(date.to_unix(), 1_709_214_330);
+    assert_eq!(DateTime::from_unix(1_709_214_330), date);
+}
+
+#[test_case]
+fn test_from_bcd() {
+    assert_eq!(from_bcd(0x59), 59);
+    assert_eq!(from_bcd(0x00), 0);
+}
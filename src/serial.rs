@@ -1,10 +1,23 @@
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts;
+use crate::task::keyboard::{Key, BACKSPACE, DELETE};
+use crate::task::timer;
+use crate::time::Duration;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -40,3 +53,199 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+const COM1: u16 = 0x3F8;
+const COM1_IRQ: u8 = 4;
+
+/// UART registers, as offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const LINE_STATUS: u16 = 5;
+
+/// Interrupt when a byte has been received
+const IER_RECEIVED: u8 = 0x01;
+const LSR_DATA_READY: u8 = 0x01;
+
+/// How long to wait for the rest of an escape sequence before taking ESC
+/// as a key of its own.
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(50);
+
+lazy_static! {
+    /// Bytes received on COM1, filled by the interrupt handler.
+    static ref RECEIVE_QUEUE: ArrayQueue<u8> = ArrayQueue::new(256);
+}
+
+static RECEIVE_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Whether the last byte was a carriage return, so that the line feed of
+/// a CRLF isn't taken as a second Enter.
+static AFTER_CR: AtomicBool = AtomicBool::new(false);
+
+/// Called on IRQ 4. Must not block or allocate.
+fn handle_interrupt() {
+    let mut line_status = Port::<u8>::new(COM1 + LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1 + DATA);
+    unsafe {
+        // reading the data register acknowledges the interrupt
+        while line_status.read() & LSR_DATA_READY != 0 {
+            // bytes that don't fit are dropped
+            let _ = RECEIVE_QUEUE.push(data.read());
+        }
+    }
+    RECEIVE_WAKER.wake();
+}
+
+/// Start receiving on COM1. Call after the interrupt controllers are set
+/// up.
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+    lazy_static::initialize(&RECEIVE_QUEUE);
+    if interrupts::set_irq_handler(COM1_IRQ, handle_interrupt) {
+        unsafe { Port::<u8>::new(COM1 + INTERRUPT_ENABLE).write(IER_RECEIVED) };
+    }
+}
+
+/// The bytes received on COM1.
+pub struct InputStream;
+
+impl Stream for InputStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        // fast path
+        if let Some(byte) = RECEIVE_QUEUE.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        RECEIVE_WAKER.register(cx.waker());
+        match RECEIVE_QUEUE.pop() {
+            Some(byte) => {
+                RECEIVE_WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The next byte of an escape sequence, if it follows soon enough.
+async fn escape_byte(input: &mut InputStream) -> Option<u8> {
+    timer::timeout(ESCAPE_TIMEOUT, input.next())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Decode the rest of an escape sequence sent by an ANSI terminal.
+async fn read_escape(input: &mut InputStream) -> Key {
+    match escape_byte(input).await {
+        Some(b'[') | Some(b'O') => {}
+        // a lone ESC, or Alt with a key, which is dropped
+        _ => return Key::Escape,
+    }
+    let mut parameter = 0;
+    // modifiers like Ctrl follow a semicolon and are ignored
+    let mut modifiers = false;
+    loop {
+        match escape_byte(input).await {
+            Some(digit @ b'0'..=b'9') if !modifiers => {
+                parameter = parameter * 10 + u32::from(digit - b'0')
+            }
+            Some(b'0'..=b'9') => {}
+            Some(b';') => modifiers = true,
+            Some(b'A') => return Key::Up,
+            Some(b'B') => return Key::Down,
+            Some(b'C') => return Key::Right,
+            Some(b'D') => return Key::Left,
+            Some(b'H') => return Key::Home,
+            Some(b'F') => return Key::End,
+            Some(b'~') => {
+                return match parameter {
+                    1 | 7 => Key::Home,
+                    3 => Key::Char(DELETE),
+                    4 | 8 => Key::End,
+                    5 => Key::PageUp,
+                    6 => Key::PageDown,
+                    _ => Key::Escape,
+                }
+            }
+            _ => return Key::Escape,
+        }
+    }
+}
+
+/// Wait for the next key typed on the serial terminal.
+pub async fn read_key() -> Key {
+    let mut input = InputStream;
+    loop {
+        let byte = match input.next().await {
+            Some(byte) => byte,
+            None => continue,
+        };
+        let after_cr = AFTER_CR.swap(byte == b'\r', Ordering::Relaxed);
+        return match byte {
+            b'\r' => Key::Char('\n'),
+            b'\n' if after_cr => continue,
+            0x08 | 0x7f => Key::Char(BACKSPACE),
+            0x1b => read_escape(&mut input).await,
+            byte if byte.is_ascii() => Key::Char(char::from(byte)),
+            // the screen only shows ASCII
+            _ => continue,
+        };
+    }
+}
+
+/// Writes screen output to the serial terminal, turning the escapes the
+/// VGA writer understands into ANSI ones.
+struct Terminal<'a> {
+    port: &'a mut SerialPort,
+    escape: bool,
+}
+
+impl Terminal<'_> {
+    fn send_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.port.send(byte);
+        }
+    }
+}
+
+impl fmt::Write for Terminal<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.escape {
+                self.escape = false;
+                match byte {
+                    b'<' => self.send_str("\x1b[D"),
+                    b'>' => self.send_str("\x1b[C"),
+                    b'c' => self.send_str("\x1b[2J\x1b[H"),
+                    // input mode only matters on the screen
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                0x1b => self.escape = true,
+                b'\n' => self.send_str("\r\n"),
+                // the VGA writer erases the character before the cursor
+                0x08 => self.send_str("\x1b[D \x1b[D"),
+                byte => self.port.send(byte),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print to the serial terminal what `print!` would show on the screen.
+pub(crate) fn write_terminal(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut port = SERIAL1.lock();
+        let _ = Terminal {
+            port: &mut port,
+            escape: false,
+        }
+        .write_fmt(args);
+    });
+}
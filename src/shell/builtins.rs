@@ -0,0 +1,340 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use futures_util::future::LocalBoxFuture;
+
+use super::command::{self, Builtin, Handler};
+use crate::acpi::{self, MadtEntry};
+use crate::task::timer;
+use crate::time::{self, Duration, Instant};
+use crate::wasm::wasm_runner;
+use crate::{fs, history, io, power, println, rtc};
+
+/// Register the commands built into the shell.
+pub fn register() {
+    command::register(Builtin {
+        name: "help",
+        usage: "help [COMMAND]",
+        help: "List commands, or show the help of COMMAND.",
+        handler: Handler::Sync(help),
+    });
+    command::register(Builtin {
+        name: "ls",
+        usage: "ls",
+        help: "List files.",
+        handler: Handler::Sync(ls),
+    });
+    command::register(Builtin {
+        name: "echo",
+        usage: "echo [WORD]...",
+        help: "Print the words separated by spaces.",
+        handler: Handler::Sync(echo),
+    });
+    command::register(Builtin {
+        name: "xyzzy",
+        usage: "xyzzy",
+        help: "Nothing happens.",
+        handler: Handler::Sync(xyzzy),
+    });
+    command::register(Builtin {
+        name: "history",
+        usage: "history [-c | -w]",
+        help: "List previous command lines.\n\
+               -c  clear the history\n\
+               -w  save the history to .history and keep it up to date",
+        handler: Handler::Sync(history),
+    });
+    command::register(Builtin {
+        name: "run",
+        usage: "run PROGRAM",
+        help: "Run a WebAssembly program. PROGRAM may leave out the .wasm\n\
+               extension or be any prefix matching a single program.",
+        handler: Handler::Sync(run),
+    });
+    command::register(Builtin {
+        name: "sleep",
+        usage: "sleep SECONDS",
+        help: "Wait for a number of seconds, which may have a fraction.",
+        handler: Handler::Async(sleep),
+    });
+    command::register(Builtin {
+        name: "uptime",
+        usage: "uptime",
+        help: "Show how long the kernel has been running and its clock rates.",
+        handler: Handler::Sync(uptime),
+    });
+    command::register(Builtin {
+        name: "time",
+        usage: "time COMMAND [ARG]...",
+        help: "Run a command and show how long it took.",
+        handler: Handler::Async(time),
+    });
+    command::register(Builtin {
+        name: "date",
+        usage: "date [+%s]",
+        help: "Show the date and time in UTC, or with +%s the seconds since\n\
+               1970-01-01.",
+        handler: Handler::Sync(date),
+    });
+    command::register(Builtin {
+        name: "shutdown",
+        usage: "shutdown",
+        help: "Flush the disks and power off.",
+        handler: Handler::Sync(|_| power::shutdown()),
+    });
+    command::register(Builtin {
+        name: "reboot",
+        usage: "reboot",
+        help: "Flush the disks and restart.",
+        handler: Handler::Sync(|_| power::reboot()),
+    });
+    command::register(Builtin {
+        name: "acpi",
+        usage: "acpi",
+        help: "List the ACPI tables and what the kernel found in them.",
+        handler: Handler::Sync(acpi),
+    });
+}
+
+fn help(args: &[String]) -> i32 {
+    match args.get(1) {
+        Some(name) => match command::get(name) {
+            Some(command) => {
+                println!("Usage: {}\n\n{}", command.usage(), command.help());
+                0
+            }
+            None => {
+                println!("help: No command {}", name);
+                1
+            }
+        },
+        None => {
+            for command in command::all() {
+                let summary = command.help().lines().next().unwrap_or("");
+                println!("  {:12}{}", command.name(), summary);
+            }
+            println!("\nScripts can also use set, unset, source, if/else/end and for/end.");
+            println!("End a command line with & to run it in the background.");
+            0
+        }
+    }
+}
+
+fn ls(_args: &[String]) -> i32 {
+    for name in fs::list() {
+        println!("{}", name)
+    }
+    0
+}
+
+fn echo(args: &[String]) -> i32 {
+    println!("{}", args[1..].join(" "));
+    0
+}
+
+fn xyzzy(_args: &[String]) -> i32 {
+    println!("Nothing happens.");
+    0
+}
+
+fn sleep(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        // rejects negative, infinite and too large values as well
+        let duration = args
+            .get(1)
+            .and_then(|arg| arg.parse::<f64>().ok())
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+        let duration = match duration {
+            Some(duration) => duration,
+            None => {
+                println!("Usage: sleep SECONDS");
+                return 2;
+            }
+        };
+        timer::sleep(duration).await;
+        0
+    })
+}
+
+fn uptime(_args: &[String]) -> i32 {
+    let seconds = time::uptime().as_secs_f64();
+    let minutes = (seconds / 60.0) as u64;
+    println!(
+        "up {}:{:02}:{:06.3}",
+        minutes / 60,
+        minutes % 60,
+        seconds - (minutes * 60) as f64
+    );
+    println!("timer: {} Hz", time::frequency());
+    match time::tsc_frequency() {
+        Some(frequency) => println!("TSC:   {:.1} MHz", frequency as f64 / 1e6),
+        None => println!("TSC:   not calibrated"),
+    }
+    0
+}
+
+fn date(args: &[String]) -> i32 {
+    match args.get(1).map(String::as_str) {
+        None => println!("{} UTC", rtc::now()),
+        Some("+%s") => println!("{}", rtc::unix_time().as_secs()),
+        Some(_) => {
+            println!("Usage: date [+%s]");
+            return 2;
+        }
+    }
+    0
+}
+
+fn acpi(_args: &[String]) -> i32 {
+    let tables = acpi::tables();
+    if tables.is_empty() {
+        println!("No ACPI tables found");
+        return 1;
+    }
+    for table in tables {
+        println!(
+            "{} rev {} {:6} at {:#010x}, {} bytes",
+            table.signature(),
+            table.revision(),
+            table.oem_id(),
+            table.address,
+            table.data.len()
+        );
+    }
+    if let Some(madt) = acpi::madt() {
+        let cpus = madt
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry, MadtEntry::LocalApic { flags, .. } if flags & 1 != 0))
+            .count();
+        let io_apics = madt
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry, MadtEntry::IoApic { .. }))
+            .count();
+        println!(
+            "MADT: {} CPUs, {} I/O APICs, local APIC at {:#x}",
+            cpus, io_apics, madt.local_apic_address
+        );
+    }
+    if let Some(fadt) = acpi::fadt() {
+        let s5 = acpi::dsdt().and_then(|dsdt| acpi::parse_s5(dsdt.body()));
+        println!(
+            "FADT: PM1a control {:#x}, reset register {}, S5 {}",
+            fadt.pm1a_control,
+            if fadt.reset_register.is_some() {
+                "yes"
+            } else {
+                "no"
+            },
+            if s5.is_some() { "yes" } else { "no" }
+        );
+    }
+    if let Some(hpet) = acpi::hpet() {
+        println!(
+            "HPET: at {:#x}, minimum tick {}",
+            hpet.address.address, hpet.minimum_tick
+        );
+    }
+    0
+}
+
+fn time(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        if args.len() < 2 {
+            println!("Usage: time COMMAND [ARG]...");
+            return 2;
+        }
+        let start = Instant::now();
+        let status = super::run_command(&args[1..]).await;
+        println!("real {:.6}s", start.elapsed().as_secs_f64());
+        status
+    })
+}
+
+fn history(args: &[String]) -> i32 {
+    match args.get(1).map(String::as_str) {
+        None => {
+            for (i, entry) in history::entries().iter().enumerate() {
+                println!("{:4}  {}", i + 1, entry)
+            }
+            0
+        }
+        Some("-c") => {
+            history::clear();
+            0
+        }
+        Some("-w") => match history::save() {
+            Ok(()) => 0,
+            Err(err) => {
+                println!("history: Could not save ({:?})", err);
+                1
+            }
+        },
+        Some(_) => {
+            println!("Usage: history [-c | -w]");
+            2
+        }
+    }
+}
+
+/// Find the `.wasm` file among `programs` for `name`, which can leave out
+/// the extension or be any prefix that matches a single program. Otherwise
+/// the candidates are returned.
+fn find_program(programs: Vec<String>, name: &str) -> Result<String, Vec<String>> {
+    if let Some(program) = programs
+        .iter()
+        .find(|program| *program == name || program.strip_suffix(".wasm") == Some(name))
+    {
+        return Ok(program.clone());
+    }
+    let mut candidates: Vec<String> = programs
+        .into_iter()
+        .filter(|program| program.starts_with(name))
+        .collect();
+    if candidates.len() == 1 {
+        Ok(candidates.remove(0))
+    } else {
+        Err(candidates)
+    }
+}
+
+fn run(args: &[String]) -> i32 {
+    if args.len() < 2 {
+        println!("Usage: run PROGRAM");
+        return 2;
+    }
+    // the disk is read once for both the names and the binary
+    let programs: Vec<(String, Vec<u8>)> = fs::read_all()
+        .into_iter()
+        .filter(|file| file.0.ends_with(".wasm"))
+        .collect();
+    let names = programs.iter().map(|file| file.0.clone()).collect();
+    let wasm = match find_program(names, &args[1]) {
+        Ok(name) => programs
+            .into_iter()
+            .find(|file| file.0 == name)
+            .map(|file| file.1)
+            .unwrap_or_default(),
+        Err(candidates) if candidates.is_empty() => {
+            println!("Program not found.");
+            return 1;
+        }
+        Err(candidates) => {
+            println!("{}: Ambiguous, could be {}", args[1], candidates.join(", "));
+            return 1;
+        }
+    };
+    match wasm_runner(wasm) {
+        // don't mix the exit code into piped output
+        Ok(code) if !io::stdout_is_terminal() => code,
+        Ok(code) => {
+            println!("Program finished with exit code {}.", code);
+            code
+        }
+        Err(err) => {
+            println!("Program failed: {}", err);
+            1
+        }
+    }
+}
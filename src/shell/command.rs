@@ -0,0 +1,81 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use futures_util::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// A command that can be run from the shell.
+pub trait Command: Send + Sync {
+    /// The name the command is invoked by.
+    fn name(&self) -> &'static str;
+
+    /// A synopsis of the arguments, e.g. `run PROGRAM`.
+    fn usage(&self) -> &'static str;
+
+    /// What the command does, shown by `help`. The first line is used as a
+    /// summary in the command list.
+    fn help(&self) -> &'static str;
+
+    /// Run the command with `args`, which include the command name, and
+    /// return its exit status.
+    fn run<'a>(&'a self, args: &'a [String]) -> LocalBoxFuture<'a, i32>;
+}
+
+/// How a `Builtin` is run.
+#[derive(Clone, Copy)]
+pub enum Handler {
+    Sync(fn(&[String]) -> i32),
+    Async(for<'a> fn(&'a [String]) -> LocalBoxFuture<'a, i32>),
+}
+
+/// A command implemented by a plain function.
+pub struct Builtin {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub handler: Handler,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn usage(&self) -> &'static str {
+        self.usage
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn run<'a>(&'a self, args: &'a [String]) -> LocalBoxFuture<'a, i32> {
+        match self.handler {
+            Handler::Sync(handler) => Box::pin(async move { handler(args) }),
+            Handler::Async(handler) => handler(args),
+        }
+    }
+}
+
+lazy_static! {
+    static ref COMMANDS: Mutex<BTreeMap<&'static str, Arc<dyn Command>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Make `command` available in the shell, replacing any command with the
+/// same name.
+pub fn register(command: impl Command + 'static) {
+    COMMANDS.lock().insert(command.name(), Arc::new(command));
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn Command>> {
+    COMMANDS.lock().get(name).cloned()
+}
+
+/// All registered commands, sorted by name.
+pub fn all() -> Vec<Arc<dyn Command>> {
+    COMMANDS.lock().values().cloned().collect()
+}
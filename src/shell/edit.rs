@@ -0,0 +1,240 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use futures_util::future::LocalBoxFuture;
+
+use super::command::{self, Builtin, Handler};
+use super::screen::{FullScreen, TEXT_ROWS};
+use crate::fs::{self, FsError};
+use crate::io::{self, Console};
+use crate::println;
+use crate::task::keyboard::{self, Key, BACKSPACE, DELETE};
+use crate::vga_buffer::BUFFER_WIDTH;
+
+const CTRL_O: char = '\x0f';
+const CTRL_Q: char = '\x11';
+const CTRL_S: char = '\x13';
+const CTRL_X: char = '\x18';
+
+pub fn register() {
+    command::register(Builtin {
+        name: "edit",
+        usage: "edit FILE",
+        help: "Edit a text file on the whole screen, creating it if needed.\n\
+               Ctrl-S (or Ctrl-O) saves and Ctrl-Q (or Ctrl-X) quits. Serial\n\
+               terminals often keep Ctrl-S and Ctrl-Q for flow control.",
+        handler: Handler::Async(edit),
+    });
+}
+
+fn edit(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        let name = match args.get(1) {
+            Some(name) => name,
+            None => {
+                println!("Usage: edit FILE");
+                return 2;
+            }
+        };
+        let mut editor = match fs::read(name) {
+            Ok(data) => Editor::new(name, &String::from_utf8_lossy(&data)),
+            Err(FsError::NotFound) => Editor::new(name, ""),
+            Err(err) => {
+                println!("{}: Could not read file ({:?})", name, err);
+                return 1;
+            }
+        };
+        editor.run().await;
+        0
+    })
+}
+
+/// A nano-style editor for one file.
+struct Editor<'a> {
+    name: &'a str,
+    lines: Vec<Vec<char>>,
+    /// Cursor position in `lines`
+    row: usize,
+    col: usize,
+    /// First line and column on screen
+    top: usize,
+    left: usize,
+    modified: bool,
+    /// Set after a first Ctrl-Q with unsaved changes
+    confirm_quit: bool,
+    /// Names of the save and quit keys shown to the user
+    keys: (&'static str, &'static str),
+    message: String,
+}
+
+impl<'a> Editor<'a> {
+    fn new(name: &'a str, text: &str) -> Self {
+        // Ctrl-S and Ctrl-Q are XOFF and XON on serial terminals
+        let keys = match io::console() {
+            Console::Screen => ("^S", "^Q"),
+            Console::Serial => ("^O", "^X"),
+        };
+        let mut lines: Vec<Vec<char>> = text.lines().map(|line| line.chars().collect()).collect();
+        if lines.is_empty() {
+            lines.push(Vec::new());
+        }
+        Editor {
+            name,
+            lines,
+            row: 0,
+            col: 0,
+            top: 0,
+            left: 0,
+            modified: false,
+            confirm_quit: false,
+            keys,
+            message: format!("{} save  {} quit", keys.0, keys.1),
+        }
+    }
+
+    async fn run(&mut self) {
+        let screen = FullScreen::new();
+        loop {
+            self.scroll();
+            self.draw(&screen);
+            let key = keyboard::read_key().await;
+            if key == Key::Char(CTRL_Q) || key == Key::Char(CTRL_X) {
+                if !self.modified || self.confirm_quit {
+                    break;
+                }
+                self.confirm_quit = true;
+                self.message = format!("Unsaved changes! Press {} again to quit.", self.keys.1);
+                continue;
+            }
+            self.confirm_quit = false;
+            self.key(key);
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        for line in &self.lines {
+            text.extend(line.iter());
+            text.push('\n');
+        }
+        text
+    }
+
+    fn save(&mut self) {
+        match fs::write(self.name, self.text().into_bytes()) {
+            Ok(()) => {
+                self.modified = false;
+                self.message = format!("Saved {} lines", self.lines.len());
+            }
+            Err(err) => self.message = format!("Could not save ({:?})", err),
+        }
+    }
+
+    fn key(&mut self, key: Key) {
+        let len = self.lines[self.row].len();
+        match key {
+            Key::Up => self.row = self.row.saturating_sub(1),
+            Key::Down => self.row = (self.row + 1).min(self.lines.len() - 1),
+            Key::PageUp => self.row = self.row.saturating_sub(TEXT_ROWS),
+            Key::PageDown => self.row = (self.row + TEXT_ROWS).min(self.lines.len() - 1),
+            Key::Home => self.col = 0,
+            Key::End => self.col = len,
+            Key::Left if self.col > 0 => self.col = self.col.min(len) - 1,
+            Key::Left if self.row > 0 => {
+                self.row -= 1;
+                self.col = self.lines[self.row].len();
+            }
+            Key::Right if self.col < len => self.col += 1,
+            Key::Right if self.row + 1 < self.lines.len() => {
+                self.row += 1;
+                self.col = 0;
+            }
+            Key::Char(CTRL_S) | Key::Char(CTRL_O) => self.save(),
+            Key::Char('\n') => {
+                let col = self.col.min(len);
+                let rest = self.lines[self.row].split_off(col);
+                self.row += 1;
+                self.col = 0;
+                self.lines.insert(self.row, rest);
+                self.modified = true;
+            }
+            Key::Char(BACKSPACE) => {
+                let col = self.col.min(len);
+                if col > 0 {
+                    self.lines[self.row].remove(col - 1);
+                    self.col = col - 1;
+                    self.modified = true;
+                } else if self.row > 0 {
+                    let line = self.lines.remove(self.row);
+                    self.row -= 1;
+                    self.col = self.lines[self.row].len();
+                    self.lines[self.row].extend(line);
+                    self.modified = true;
+                }
+            }
+            Key::Char(DELETE) => {
+                let col = self.col.min(len);
+                if col < len {
+                    self.lines[self.row].remove(col);
+                    self.modified = true;
+                } else if self.row + 1 < self.lines.len() {
+                    let line = self.lines.remove(self.row + 1);
+                    self.lines[self.row].extend(line);
+                    self.modified = true;
+                }
+            }
+            Key::Char('\t') => {
+                for _ in 0..4 {
+                    self.insert(' ');
+                }
+            }
+            Key::Char(character) if character.is_ascii() && !character.is_ascii_control() => {
+                self.insert(character)
+            }
+            _ => {}
+        }
+        // keep the cursor inside the line it moved to
+        self.col = self.col.min(self.lines[self.row].len());
+    }
+
+    fn insert(&mut self, character: char) {
+        let col = self.col.min(self.lines[self.row].len());
+        self.lines[self.row].insert(col, character);
+        self.col = col + 1;
+        self.modified = true;
+    }
+
+    /// Scroll so that the cursor is on screen.
+    fn scroll(&mut self) {
+        if self.row < self.top {
+            self.top = self.row;
+        } else if self.row >= self.top + TEXT_ROWS {
+            self.top = self.row + 1 - TEXT_ROWS;
+        }
+        if self.col < self.left {
+            self.left = self.col;
+        } else if self.col >= self.left + BUFFER_WIDTH {
+            self.left = self.col + 1 - BUFFER_WIDTH;
+        }
+    }
+
+    fn draw(&self, screen: &FullScreen) {
+        for row in 0..TEXT_ROWS {
+            let text: String = match self.lines.get(self.top + row) {
+                Some(line) => line.iter().skip(self.left).collect(),
+                None => String::from("~"),
+            };
+            screen.draw_row(row, &text);
+        }
+        screen.draw_status(&format!(
+            " {}{}  Ln {}, Col {}  {}",
+            self.name,
+            if self.modified { " [modified]" } else { "" },
+            self.row + 1,
+            self.col + 1,
+            self.message
+        ));
+        screen.set_cursor(self.row - self.top, self.col - self.left);
+    }
+}
@@ -0,0 +1,212 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use futures_util::future::LocalBoxFuture;
+
+use super::command::{self, Builtin, Handler};
+use super::pager::Pager;
+use crate::{fs, io, println};
+
+/// Register the commands for looking inside files.
+pub fn register() {
+    command::register(Builtin {
+        name: "cat",
+        usage: "cat [FILE]...",
+        help: "Print files, or standard input if none are given.",
+        handler: Handler::Async(cat),
+    });
+    command::register(Builtin {
+        name: "hexdump",
+        usage: "hexdump [-C] [FILE]",
+        help: "Show the bytes of a file, or standard input, as hex and ASCII\n\
+               with their offsets (the -C format is the only one).",
+        handler: Handler::Async(hexdump),
+    });
+    command::register(Builtin {
+        name: "stat",
+        usage: "stat FILE...",
+        help: "Show the size, type and CRC-32 checksum of files.",
+        handler: Handler::Sync(stat),
+    });
+    command::register(Builtin {
+        name: "wc",
+        usage: "wc [FILE]...",
+        help: "Count the lines, words and bytes in files, or standard input\n\
+               if none are given.",
+        handler: Handler::Sync(wc),
+    });
+}
+
+/// The contents of each named file, or of standard input if there are no
+/// names. Missing files are reported and skipped.
+fn inputs(names: &[String]) -> (Vec<(String, Vec<u8>)>, i32) {
+    if names.is_empty() {
+        return (
+            Vec::from([(String::new(), io::read_to_string().into_bytes())]),
+            0,
+        );
+    }
+    let mut status = 0;
+    let mut files = Vec::new();
+    for name in names {
+        match fs::read(name) {
+            Ok(data) => files.push((name.clone(), data)),
+            Err(_) => {
+                println!("{}: No such file", name);
+                status = 1;
+            }
+        }
+    }
+    (files, status)
+}
+
+fn cat(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        let (files, status) = inputs(&args[1..]);
+        let mut pager = Pager::new();
+        for (_, data) in files {
+            for line in String::from_utf8_lossy(&data).lines() {
+                pager.line(line).await;
+            }
+        }
+        status
+    })
+}
+
+fn hexdump(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        let names: Vec<String> = args[1..]
+            .iter()
+            .filter(|arg| *arg != "-C")
+            .cloned()
+            .collect();
+        if names.len() > 1 {
+            println!("Usage: hexdump [-C] [FILE]");
+            return 2;
+        }
+        let (files, status) = inputs(&names);
+        let mut pager = Pager::new();
+        for (_, data) in files {
+            for (i, chunk) in data.chunks(16).enumerate() {
+                pager.line(&hexdump_line(i * 16, chunk)).await;
+            }
+            pager.line(&format!("{:08x}", data.len())).await;
+        }
+        status
+    })
+}
+
+/// One line of `hexdump -C` output for up to 16 bytes at `offset`.
+fn hexdump_line(offset: usize, chunk: &[u8]) -> String {
+    let mut line = format!("{:08x} ", offset);
+    for i in 0..16 {
+        if i == 8 {
+            line.push(' ');
+        }
+        match chunk.get(i) {
+            Some(byte) => line.push_str(&format!(" {:02x}", byte)),
+            None => line.push_str("   "),
+        }
+    }
+    line.push_str("  |");
+    for &byte in chunk {
+        line.push(match byte {
+            0x20..=0x7e => byte as char,
+            _ => '.',
+        });
+    }
+    line.push('|');
+    line
+}
+
+fn stat(args: &[String]) -> i32 {
+    if args.len() < 2 {
+        println!("Usage: stat FILE...");
+        return 2;
+    }
+    let (files, status) = inputs(&args[1..]);
+    for (name, data) in files {
+        println!("  File: {}", name);
+        println!("  Size: {}", data.len());
+        println!("  Type: {}", file_type(&data));
+        println!("CRC-32: {:08x}", crc32(&data));
+        if let Some(time) = fs::modified(&name) {
+            println!("Modify: {} UTC", time);
+        }
+    }
+    status
+}
+
+fn wc(args: &[String]) -> i32 {
+    let (files, status) = inputs(&args[1..]);
+    let mut total = (0, 0, 0);
+    let count = files.len();
+    for (name, data) in files {
+        let text = String::from_utf8_lossy(&data);
+        let lines = data.iter().filter(|&&byte| byte == b'\n').count();
+        let words = text.split_whitespace().count();
+        println!("{:7} {:7} {:7} {}", lines, words, data.len(), name);
+        total = (total.0 + lines, total.1 + words, total.2 + data.len());
+    }
+    if count > 1 {
+        println!("{:7} {:7} {:7} total", total.0, total.1, total.2);
+    }
+    status
+}
+
+/// A description of what `data` contains.
+pub fn file_type(data: &[u8]) -> &'static str {
+    if data.is_empty() {
+        "empty"
+    } else if data.starts_with(b"\0asm") {
+        "WebAssembly module"
+    } else if data
+        .iter()
+        .all(|&byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace() || byte >= 0x80)
+        && core::str::from_utf8(data).is_ok()
+    {
+        "text"
+    } else {
+        "data"
+    }
+}
+
+/// The CRC-32 (IEEE 802.3) checksum of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[test_case]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+}
+
+#[test_case]
+fn test_hexdump_line() {
+    assert_eq!(
+        hexdump_line(16, b"\0asm\x01\0\0\0hello, w"),
+        "00000010  00 61 73 6d 01 00 00 00  68 65 6c 6c 6f 2c 20 77  |.asm....hello, w|"
+    );
+    assert_eq!(
+        hexdump_line(0, b"Hi\n"),
+        "00000000  48 69 0a                                          |Hi.|"
+    );
+}
+
+#[test_case]
+fn test_file_type() {
+    assert_eq!(file_type(b""), "empty");
+    assert_eq!(file_type(b"\0asm\x01\0\0\0"), "WebAssembly module");
+    assert_eq!(file_type(b"echo hello\n"), "text");
+    assert_eq!(file_type(b"\x01\x02"), "data");
+}
@@ -0,0 +1,167 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::command::{self, Builtin, Handler};
+use crate::println;
+use crate::task::{self, JoinHandle, TaskId, TaskState};
+
+/// Exit status of a job that was killed, as in other shells.
+pub const KILLED: i32 = 143;
+
+/// A command line running in the background.
+struct Job {
+    command: String,
+    handle: JoinHandle<i32>,
+}
+
+lazy_static! {
+    /// Background jobs that haven't been waited for or reported yet.
+    static ref JOBS: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+}
+
+pub fn register() {
+    command::register(Builtin {
+        name: "jobs",
+        usage: "jobs [-a]",
+        help: "List background jobs, started by ending a command line with &.\n\
+               With -a, list every task in the kernel instead.",
+        handler: Handler::Sync(jobs),
+    });
+    command::register(Builtin {
+        name: "kill",
+        usage: "kill ID...",
+        help: "Stop jobs or other tasks by their ID. Tasks the kernel started\n\
+               at boot, like the shells, can't be stopped.",
+        handler: Handler::Sync(kill),
+    });
+    command::register(Builtin {
+        name: "wait",
+        usage: "wait [ID]...",
+        help: "Wait for background jobs to finish, or for all of them if no\n\
+               IDs are given. The exit status is that of the last job.",
+        handler: Handler::Async(wait),
+    });
+}
+
+/// Keep track of a command line spawned in the background and print its ID.
+pub fn add(command: &str, handle: JoinHandle<i32>) {
+    println!("[{}] {}", handle.id(), command);
+    JOBS.lock().push(Job {
+        command: String::from(command),
+        handle,
+    });
+}
+
+/// Print and forget jobs that have finished since the last call.
+pub fn notify() {
+    for (id, command, status) in take_finished() {
+        println!("[{}] {:<12} {}", id, status_text(status), command);
+    }
+}
+
+fn take_finished() -> Vec<(TaskId, String, i32)> {
+    let mut jobs = JOBS.lock();
+    let mut finished = Vec::new();
+    let mut i = 0;
+    while i < jobs.len() {
+        if jobs[i].handle.is_finished() {
+            let job = jobs.remove(i);
+            let id = job.handle.id();
+            let status = job.handle.now_or_never().flatten().unwrap_or(KILLED);
+            finished.push((id, job.command, status));
+        } else {
+            i += 1;
+        }
+    }
+    finished
+}
+
+fn status_text(status: i32) -> String {
+    match status {
+        0 => String::from("Done"),
+        KILLED => String::from("Killed"),
+        status => format!("Exit {}", status),
+    }
+}
+
+fn jobs(args: &[String]) -> i32 {
+    if args.get(1).map(String::as_str) == Some("-a") {
+        for info in task::list() {
+            let state = match info.state {
+                TaskState::Running => "Running",
+                TaskState::Waiting => "Waiting",
+            };
+            println!("[{}] {:<12} {}", info.id, state, info.name);
+        }
+        return 0;
+    }
+    for job in JOBS.lock().iter() {
+        if !job.handle.is_finished() {
+            println!("[{}] {:<12} {}", job.handle.id(), "Running", job.command);
+        }
+    }
+    notify();
+    0
+}
+
+fn kill(args: &[String]) -> i32 {
+    if args.len() < 2 {
+        println!("Usage: kill ID...");
+        return 2;
+    }
+    let mut status = 0;
+    for arg in &args[1..] {
+        // commands run in tasks of their own, so the shell is a kernel task
+        match arg.parse::<TaskId>().ok().and_then(task::info) {
+            Some(info) if info.kernel => {
+                println!("{}: Can't kill kernel task {}", arg, info.name);
+                status = 1;
+            }
+            Some(info) if task::cancel(info.id) => {}
+            _ => {
+                println!("{}: No such task", arg);
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+fn wait(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        let mut ids = Vec::new();
+        for arg in &args[1..] {
+            match arg.parse::<TaskId>() {
+                Ok(id) => ids.push(id),
+                Err(_) => {
+                    println!("Usage: wait [ID]...");
+                    return 2;
+                }
+            }
+        }
+        if ids.is_empty() {
+            ids = JOBS.lock().iter().map(|job| job.handle.id()).collect();
+        }
+        let mut status = 0;
+        for id in ids {
+            let job = {
+                let mut jobs = JOBS.lock();
+                match jobs.iter().position(|job| job.handle.id() == id) {
+                    Some(i) => jobs.remove(i),
+                    None => {
+                        println!("{}: No such job", id);
+                        status = 127;
+                        continue;
+                    }
+                }
+            };
+            status = job.handle.await.unwrap_or(KILLED);
+        }
+        status
+    })
+}
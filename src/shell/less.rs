@@ -0,0 +1,92 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use futures_util::future::LocalBoxFuture;
+
+use super::command::{self, Builtin, Handler};
+use super::screen::{FullScreen, TEXT_ROWS};
+use crate::task::keyboard::{self, Key};
+use crate::vga_buffer::BUFFER_WIDTH;
+use crate::{fs, io, println};
+
+pub fn register() {
+    command::register(Builtin {
+        name: "less",
+        usage: "less [FILE]",
+        help: "Page through a file, or standard input, on the whole screen.\n\
+               Up/Down scroll a line, PageUp/PageDown or b/Space a page,\n\
+               Left/Right scroll sideways, Home/End or g/G jump to the start\n\
+               or end, and q quits.",
+        handler: Handler::Async(less),
+    });
+}
+
+fn less(args: &[String]) -> LocalBoxFuture<'_, i32> {
+    Box::pin(async move {
+        let (name, data) = match args.get(1) {
+            Some(name) => match fs::read(name) {
+                Ok(data) => (name.as_str(), data),
+                Err(_) => {
+                    println!("{}: No such file", name);
+                    return 1;
+                }
+            },
+            None => ("(stdin)", io::read_to_string().into_bytes()),
+        };
+        let text = String::from_utf8_lossy(&data);
+        let lines: Vec<&str> = text.lines().collect();
+
+        if !io::stdout_is_terminal() {
+            for line in lines {
+                println!("{}", line);
+            }
+            return 0;
+        }
+        view(name, &lines).await;
+        0
+    })
+}
+
+/// Show `lines` on the whole screen until `q` is pressed.
+async fn view(name: &str, lines: &[&str]) {
+    let screen = FullScreen::new();
+    let last_top = lines.len().saturating_sub(TEXT_ROWS);
+    let mut top = 0;
+    let mut left = 0;
+
+    loop {
+        for row in 0..TEXT_ROWS {
+            let line = lines.get(top + row).copied().unwrap_or("~");
+            screen.draw_row(row, line.get(left..).unwrap_or(""));
+        }
+        let bottom = (top + TEXT_ROWS).min(lines.len());
+        let percent = if lines.is_empty() {
+            100
+        } else {
+            bottom * 100 / lines.len()
+        };
+        screen.draw_status(&format!(
+            " {}  lines {}-{}/{} ({}%)  q:quit",
+            name,
+            top + 1,
+            bottom,
+            lines.len(),
+            percent
+        ));
+        screen.set_cursor(TEXT_ROWS, 0);
+
+        match keyboard::read_key().await {
+            Key::Char('q') | Key::Char('Q') | Key::Escape => break,
+            Key::Up | Key::Char('k') => top = top.saturating_sub(1),
+            Key::Down | Key::Char('j') | Key::Char('\n') => top = (top + 1).min(last_top),
+            Key::PageUp | Key::Char('b') => top = top.saturating_sub(TEXT_ROWS),
+            Key::PageDown | Key::Char(' ') => top = (top + TEXT_ROWS).min(last_top),
+            Key::Home | Key::Char('g') => top = 0,
+            Key::End | Key::Char('G') => top = last_top,
+            Key::Left => left = left.saturating_sub(BUFFER_WIDTH / 2),
+            Key::Right => left += BUFFER_WIDTH / 2,
+            _ => {}
+        }
+    }
+}
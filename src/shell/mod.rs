@@ -0,0 +1,314 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{future::Future, pin::Pin};
+use shlex::split;
+use spin::Once;
+
+use crate::io::Console;
+use crate::task::{self, keyboard};
+use crate::vga_buffer::enable_cursor;
+use crate::{fs, history, io, print, println};
+
+pub mod builtins;
+pub mod command;
+pub mod edit;
+pub mod files;
+pub mod jobs;
+pub mod less;
+pub mod pager;
+pub mod parse;
+pub mod screen;
+
+pub use parse::{
+    block_depth, parse_pipeline, parse_script, strip_comment, Environment, Pipeline, Redirect,
+    Statement,
+};
+
+/// Script run when the shell starts.
+const AUTOEXEC: &str = "autoexec.sh";
+
+/// How deeply `source` may nest.
+const MAX_SOURCE_DEPTH: usize = 16;
+
+/// The interactive shell on the screen: runs `autoexec.sh`, then reads and
+/// runs command lines forever.
+pub async fn run() {
+    // Clear screen
+    print!("\x1bc");
+    println!("\n    blog_os shell\n");
+    enable_cursor();
+    register_commands();
+
+    let mut env = Environment::new();
+    if fs::read(AUTOEXEC).is_ok() {
+        source(&mut env, AUTOEXEC).await;
+    }
+    read_commands(&mut env).await
+}
+
+/// A second shell on the serial port, so the kernel can be driven from the
+/// host with QEMU's `-serial stdio`. It doesn't run `autoexec.sh`.
+pub async fn run_serial() {
+    io::set_console(Console::Serial);
+    register_commands();
+    println!("\n    blog_os shell on serial\n");
+    read_commands(&mut Environment::new()).await
+}
+
+/// Register the commands built into the shell, once for all shells.
+fn register_commands() {
+    static REGISTERED: Once = Once::new();
+    REGISTERED.call_once(|| {
+        builtins::register();
+        files::register();
+        jobs::register();
+        less::register();
+        edit::register();
+        keyboard::set_completer(complete);
+    });
+}
+
+/// Read and run command lines forever.
+async fn read_commands(env: &mut Environment) {
+    loop {
+        jobs::notify();
+        let mut script = keyboard::prompt(">").await;
+        history::push(&script);
+        // keep reading until all `if` and `for` blocks are closed
+        let mut depth = block_depth(&script);
+        while depth > 0 {
+            let line = keyboard::prompt("..").await;
+            history::push(&line);
+            depth += block_depth(&line);
+            script.push('\n');
+            script.push_str(&line);
+        }
+        match parse_script(script.lines()) {
+            Ok(statements) => run_statements(env, &statements).await,
+            Err(err) => {
+                println!("Syntax error: {}", err);
+                env.status = 2;
+            }
+        }
+    }
+}
+
+/// Run script statements, updating the exit status in `env`.
+fn run_statements<'a>(
+    env: &'a mut Environment,
+    statements: &'a [Statement],
+) -> Pin<Box<dyn Future<Output = ()> + 'a>> {
+    Box::pin(async move {
+        for statement in statements {
+            match statement {
+                Statement::Line(line) => env.status = run_line(env, line).await,
+                Statement::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    env.status = run_line(env, condition).await;
+                    if env.status == 0 {
+                        run_statements(env, then).await;
+                    } else {
+                        run_statements(env, otherwise).await;
+                    }
+                }
+                Statement::For {
+                    variable,
+                    words,
+                    body,
+                } => {
+                    for word in split(&env.expand(words)).unwrap_or_default() {
+                        env.variables.insert(variable.clone(), word);
+                        run_statements(env, body).await;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Expand variables in `line` and run it, returning its exit status. A line
+/// ending with `&` is run as a background job.
+async fn run_line(env: &mut Environment, line: &str) -> i32 {
+    let mut words = match split(&env.expand(line)) {
+        Some(words) => words,
+        None => {
+            println!("{}: Invalid command!", line);
+            return 2;
+        }
+    };
+    if words.len() > 1 && words.last().map(String::as_str) == Some("&") {
+        words.pop();
+        let command = words.join(" ");
+        let handle = task::spawn(&command, async move {
+            // leave the keyboard to the foreground
+            io::push_stdin(String::new());
+            execute(words).await
+        });
+        jobs::add(&command, handle);
+        return 0;
+    }
+    match words.first().map(String::as_str) {
+        Some("set") => {
+            match words.get(1) {
+                Some(name) => {
+                    env.variables.insert(name.clone(), words[2..].join(" "));
+                }
+                None => {
+                    for (name, value) in env.variables.iter() {
+                        println!("{}={}", name, value)
+                    }
+                }
+            }
+            0
+        }
+        Some("unset") => {
+            for name in &words[1..] {
+                env.variables.remove(name);
+            }
+            0
+        }
+        Some("source") => match words.get(1) {
+            Some(name) => source(env, name).await,
+            None => {
+                println!("Usage: source FILE");
+                2
+            }
+        },
+        _ => run_foreground(words).await,
+    }
+}
+
+/// Run a command line in a task of its own, so that `kill` can end it
+/// without ending the shell.
+async fn run_foreground(words: Vec<String>) -> i32 {
+    let command = words.join(" ");
+    task::spawn(&command, execute(words))
+        .await
+        .unwrap_or(jobs::KILLED)
+}
+
+/// Run the script file `name`, returning the status of its last command.
+async fn source(env: &mut Environment, name: &str) -> i32 {
+    let data = match fs::read(name) {
+        Ok(data) => data,
+        Err(_) => {
+            println!("{}: No such file", name);
+            return 1;
+        }
+    };
+    if env.depth == MAX_SOURCE_DEPTH {
+        println!("{}: Too many nested scripts", name);
+        return 1;
+    }
+    let text = String::from_utf8_lossy(&data);
+    match parse_script(text.lines()) {
+        Ok(statements) => {
+            env.depth += 1;
+            run_statements(env, &statements).await;
+            env.depth -= 1;
+            env.status
+        }
+        Err(err) => {
+            println!("{}: Syntax error: {}", name, err);
+            2
+        }
+    }
+}
+
+/// Run a pipeline, connecting the output of each command to the input of the
+/// next and applying `<`, `>` and `>>` redirections.
+///
+/// Pipelines are fully buffered: each command runs to completion with its
+/// output collected in memory before the next one starts, so commands that
+/// never finish, like `nc`, block the rest of the pipeline.
+async fn execute(words: Vec<String>) -> i32 {
+    let pipeline = match parse_pipeline(words) {
+        Ok(pipeline) => pipeline,
+        Err(err) => {
+            println!("Syntax error: {}", err);
+            return 2;
+        }
+    };
+
+    let mut input = match &pipeline.stdin {
+        Some(name) => match fs::read(name) {
+            Ok(data) => Some(String::from_utf8_lossy(&data).into_owned()),
+            Err(_) => {
+                println!("{}: No such file", name);
+                return 1;
+            }
+        },
+        None => None,
+    };
+
+    let mut status = 0;
+    let count = pipeline.commands.len();
+    for (i, command) in pipeline.commands.iter().enumerate() {
+        let redirected_input = input.is_some();
+        if let Some(data) = input.take() {
+            io::push_stdin(data);
+        }
+        let capture = i + 1 < count || pipeline.stdout.is_some();
+        if capture {
+            io::push_stdout();
+        }
+
+        status = run_command(command).await;
+
+        if capture {
+            input = Some(io::pop_stdout());
+        }
+        if redirected_input {
+            io::pop_stdin();
+        }
+    }
+
+    let output = input.unwrap_or_default();
+    let result = match &pipeline.stdout {
+        Some(Redirect::Truncate(name)) => {
+            fs::write(name, output.into_bytes()).map_err(|e| (name, e))
+        }
+        Some(Redirect::Append(name)) => fs::append(name, output.as_bytes()).map_err(|e| (name, e)),
+        None => Ok(()),
+    };
+    if let Err((name, err)) = result {
+        println!("{}: Could not write file ({:?})", name, err);
+        return 1;
+    }
+    status
+}
+
+/// Complete command names for the first word and file names after that.
+fn complete(line: &str) -> Vec<String> {
+    let (command, word) = match line.rfind(' ') {
+        Some(i) => (line.split(' ').next().unwrap_or(""), &line[i + 1..]),
+        None => {
+            return command::all()
+                .iter()
+                .map(|command| command.name())
+                .filter(|name| name.starts_with(line))
+                .map(String::from)
+                .collect()
+        }
+    };
+    fs::list()
+        .into_iter()
+        .filter(|name| name.starts_with(word))
+        .filter(|name| command != "run" || name.ends_with(".wasm"))
+        .collect()
+}
+
+/// Run a single command from the registry, returning its exit status.
+async fn run_command(args: &[String]) -> i32 {
+    match command::get(&args[0]) {
+        Some(command) => command.run(args).await,
+        None => {
+            println!("{}: Unknown command!", args[0]);
+            127
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::io;
+use crate::print;
+use crate::println;
+use crate::task::keyboard::{self, Key};
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+const MORE: &str = "-- More --";
+
+/// Prints lines, pausing whenever the screen is full until a key is pressed:
+/// Space shows the next page, Enter the next line and `q` stops the output.
+///
+/// Output that doesn't go to the screen is never paused.
+pub struct Pager {
+    rows_left: usize,
+    quit: bool,
+}
+
+impl Pager {
+    pub fn new() -> Self {
+        Pager {
+            rows_left: BUFFER_HEIGHT - 1,
+            quit: false,
+        }
+    }
+
+    /// Whether `q` was pressed; later lines are dropped.
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    pub async fn line(&mut self, line: &str) {
+        if self.quit {
+            return;
+        }
+        if !io::stdout_is_terminal() {
+            println!("{}", line);
+            return;
+        }
+        let rows = (line.len() / BUFFER_WIDTH + 1).min(BUFFER_HEIGHT - 1);
+        while self.rows_left < rows {
+            print!("\x1bi{}", MORE);
+            let key = keyboard::read_key().await;
+            // erase the prompt
+            print!("{}\x1bi", "\u{8}".repeat(MORE.len()));
+            match key {
+                Key::Char('q') | Key::Char('Q') | Key::Escape => {
+                    self.quit = true;
+                    return;
+                }
+                Key::Char('\n') | Key::Down => self.rows_left += 1,
+                _ => self.rows_left = BUFFER_HEIGHT - 1,
+            }
+        }
+        self.rows_left -= rows;
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,332 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Where the output of the last command of a pipeline goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    Truncate(String),
+    Append(String),
+}
+
+/// A line such as `run gen | run sort > /tmp/out`, split into commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    pub commands: Vec<Vec<String>>,
+    pub stdin: Option<String>,
+    pub stdout: Option<Redirect>,
+}
+
+/// Split the words of a command line at `|`, `<`, `>` and `>>`.
+///
+/// Operators must be separate words, so `a|b` is a single argument.
+pub fn parse_pipeline(words: Vec<String>) -> Result<Pipeline, &'static str> {
+    let mut pipeline = Pipeline {
+        commands: Vec::new(),
+        stdin: None,
+        stdout: None,
+    };
+    let mut command = Vec::new();
+    let mut words = words.into_iter();
+
+    while let Some(word) = words.next() {
+        match word.as_str() {
+            "|" => {
+                if command.is_empty() || pipeline.stdout.is_some() {
+                    return Err("empty command in pipeline");
+                }
+                pipeline.commands.push(core::mem::take(&mut command));
+            }
+            "<" => {
+                if !pipeline.commands.is_empty() {
+                    return Err("only the first command can read from a file");
+                }
+                pipeline.stdin = Some(words.next().ok_or("missing file name after `<`")?);
+            }
+            ">" => {
+                let name = words.next().ok_or("missing file name after `>`")?;
+                pipeline.stdout = Some(Redirect::Truncate(name));
+            }
+            ">>" => {
+                let name = words.next().ok_or("missing file name after `>>`")?;
+                pipeline.stdout = Some(Redirect::Append(name));
+            }
+            _ => command.push(word),
+        }
+    }
+
+    if command.is_empty() {
+        if !pipeline.commands.is_empty() {
+            return Err("empty command in pipeline");
+        }
+    } else {
+        pipeline.commands.push(command);
+    }
+    Ok(pipeline)
+}
+
+/// Shell variables and the exit status of the last command.
+pub struct Environment {
+    pub variables: BTreeMap<String, String>,
+    pub status: i32,
+    /// How many scripts are being sourced, to stop scripts sourcing
+    /// themselves from running out of stack.
+    pub depth: usize,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Environment {
+            variables: BTreeMap::new(),
+            status: 0,
+            depth: 0,
+        }
+    }
+
+    /// Replace `$NAME`, `${NAME}` and `$?` outside single quotes with their
+    /// values. Unset variables expand to nothing.
+    pub fn expand(&self, line: &str) -> String {
+        let mut expanded = String::new();
+        let mut chars = line.chars().peekable();
+        let mut quoted = false;
+        let mut double_quoted = false;
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' if !double_quoted => quoted = !quoted,
+                '"' if !quoted => double_quoted = !double_quoted,
+                _ => {}
+            }
+            if c != '$' || quoted {
+                expanded.push(c);
+                continue;
+            }
+            let mut name = String::new();
+            match chars.peek() {
+                Some('?') => {
+                    chars.next();
+                    expanded.push_str(&format!("{}", self.status));
+                    continue;
+                }
+                Some('{') => {
+                    chars.next();
+                    while let Some(c) = chars.next() {
+                        if c == '}' {
+                            break;
+                        }
+                        name.push(c);
+                    }
+                }
+                _ => {
+                    while let Some(&c) = chars.peek() {
+                        if !(c.is_ascii_alphanumeric() || c == '_') {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                }
+            }
+            if name.is_empty() {
+                expanded.push('$');
+            } else if let Some(value) = self.variables.get(&name) {
+                expanded.push_str(value);
+            }
+        }
+        expanded
+    }
+}
+
+/// Remove a `#` comment, which starts at the beginning of a word outside
+/// quotes, from `line`.
+pub fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut word_start = true;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') if word_start => return &line[..i],
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => {}
+        }
+        word_start = c.is_whitespace();
+    }
+    line
+}
+
+/// A parsed script statement. Lines are kept as text so that variables are
+/// expanded when they run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    /// A pipeline, or `set`/`unset`/`source`
+    Line(String),
+    /// `if <line>`, the statements to run when it succeeds, and those after
+    /// `else`, up to `end`
+    If {
+        condition: String,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+    },
+    /// `for <variable> in <words>`, repeating the body up to `end`
+    For {
+        variable: String,
+        words: String,
+        body: Vec<Statement>,
+    },
+}
+
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+/// How many blocks `line` opens (1) or closes (-1), to tell when an
+/// interactive `if` or `for` is complete.
+pub fn block_depth(line: &str) -> i32 {
+    match first_word(strip_comment(line)) {
+        "if" | "for" => 1,
+        "end" => -1,
+        _ => 0,
+    }
+}
+
+/// Parse the lines of a script, dropping comments and blank lines.
+pub fn parse_script<'a>(
+    lines: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<Statement>, String> {
+    let mut lines = lines.into_iter().enumerate();
+    let (statements, terminator) = parse_block(&mut lines)?;
+    match terminator {
+        None => Ok(statements),
+        Some((number, keyword)) => Err(format!("line {}: unexpected `{}`", number + 1, keyword)),
+    }
+}
+
+/// Parse statements up to a closing `else` or `end`, which is returned with
+/// its line number, or to the end of the input.
+fn parse_block<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+) -> Result<(Vec<Statement>, Option<(usize, &'static str)>), String> {
+    let mut statements = Vec::new();
+
+    while let Some((number, line)) = lines.next() {
+        let line = strip_comment(line).trim();
+        let rest = line[first_word(line).len()..].trim();
+        match first_word(line) {
+            "" => {}
+            "else" => return Ok((statements, Some((number, "else")))),
+            "end" => return Ok((statements, Some((number, "end")))),
+            "if" => {
+                if rest.is_empty() {
+                    return Err(format!("line {}: `if` without a condition", number + 1));
+                }
+                let (then, terminator) = parse_block(lines)?;
+                let otherwise = match terminator {
+                    Some((_, "else")) => match parse_block(lines)? {
+                        (otherwise, Some((_, "end"))) => otherwise,
+                        _ => return Err(format!("line {}: `if` without `end`", number + 1)),
+                    },
+                    Some((_, "end")) => Vec::new(),
+                    _ => return Err(format!("line {}: `if` without `end`", number + 1)),
+                };
+                statements.push(Statement::If {
+                    condition: String::from(rest),
+                    then,
+                    otherwise,
+                });
+            }
+            "for" => {
+                let variable = first_word(rest);
+                let words = rest[variable.len()..].trim_start();
+                if variable.is_empty() || first_word(words) != "in" {
+                    return Err(format!("line {}: expected `for NAME in WORDS`", number + 1));
+                }
+                let body = match parse_block(lines)? {
+                    (body, Some((_, "end"))) => body,
+                    _ => return Err(format!("line {}: `for` without `end`", number + 1)),
+                };
+                statements.push(Statement::For {
+                    variable: String::from(variable),
+                    words: String::from(words[2..].trim()),
+                    body,
+                });
+            }
+            _ => statements.push(Statement::Line(String::from(line))),
+        }
+    }
+    Ok((statements, None))
+}
+
+#[cfg(test)]
+fn words(line: &str) -> Vec<String> {
+    line.split_whitespace().map(String::from).collect()
+}
+
+#[test_case]
+fn test_parse_pipeline() {
+    let pipeline = parse_pipeline(words("run gen | run sort > /tmp/out")).unwrap();
+    assert_eq!(pipeline.commands, [words("run gen"), words("run sort")]);
+    assert_eq!(pipeline.stdin, None);
+    assert_eq!(
+        pipeline.stdout,
+        Some(Redirect::Truncate(String::from("/tmp/out")))
+    );
+
+    let pipeline = parse_pipeline(words("wc < in >> out")).unwrap();
+    assert_eq!(pipeline.commands, [words("wc")]);
+    assert_eq!(pipeline.stdin, Some(String::from("in")));
+    assert_eq!(pipeline.stdout, Some(Redirect::Append(String::from("out"))));
+}
+
+#[test_case]
+fn test_parse_pipeline_errors() {
+    assert!(parse_pipeline(words("ls |")).is_err());
+    assert!(parse_pipeline(words("| ls")).is_err());
+    assert!(parse_pipeline(words("ls >")).is_err());
+    assert!(parse_pipeline(words("ls > out | wc")).is_err());
+    assert!(parse_pipeline(words("ls | wc < in")).is_err());
+}
+
+#[test_case]
+fn test_expand_variables() {
+    let mut env = Environment::new();
+    env.variables
+        .insert(String::from("NAME"), String::from("hello"));
+    env.status = 3;
+    assert_eq!(env.expand("run $NAME.wasm"), "run hello.wasm");
+    assert_eq!(env.expand("echo ${NAME}x $? $UNSET"), "echo hellox 3 ");
+    assert_eq!(
+        env.expand("echo '$NAME' \"$NAME\" $"),
+        "echo '$NAME' \"hello\" $"
+    );
+}
+
+#[test_case]
+fn test_strip_comment() {
+    assert_eq!(strip_comment("# comment"), "");
+    assert_eq!(strip_comment("echo a # b"), "echo a ");
+    assert_eq!(strip_comment("echo a#b '# c'"), "echo a#b '# c'");
+}
+
+#[test_case]
+fn test_parse_script() {
+    let script =
+        "# greet\nfor X in a b\n  if run $X\n    echo yes\n  else\n    echo no\n  end\nend\n";
+    let statements = parse_script(script.lines()).unwrap();
+    assert_eq!(
+        statements,
+        [Statement::For {
+            variable: String::from("X"),
+            words: String::from("a b"),
+            body: Vec::from([Statement::If {
+                condition: String::from("run $X"),
+                then: Vec::from([Statement::Line(String::from("echo yes"))]),
+                otherwise: Vec::from([Statement::Line(String::from("echo no"))]),
+            }]),
+        }]
+    );
+
+    assert!(parse_script("if ls".lines()).is_err());
+    assert!(parse_script("end".lines()).is_err());
+    assert!(parse_script("for X a b\nend".lines()).is_err());
+}
@@ -0,0 +1,84 @@
+use alloc::string::String;
+use x86_64::instructions::interrupts;
+
+use crate::io::{self, Console};
+use crate::serial_print;
+use crate::vga_buffer::{SavedScreen, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+
+/// Rows available to a full-screen program above its status line.
+pub const TEXT_ROWS: usize = BUFFER_HEIGHT - 1;
+
+/// Exclusive use of the screen by a full-screen program such as `less` or
+/// `edit`. The previous contents are restored when it is dropped.
+///
+/// On the serial console the terminal is driven with ANSI escape codes and
+/// assumed to be at least as big as the VGA screen.
+pub struct FullScreen {
+    /// `None` on the serial console, where the terminal keeps the contents
+    saved: Option<SavedScreen>,
+}
+
+impl FullScreen {
+    pub fn new() -> Self {
+        if io::console() == Console::Serial {
+            // switch to the alternate screen and clear it
+            serial_print!("\x1b[?1049h\x1b[2J");
+            return FullScreen { saved: None };
+        }
+        let saved = interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let saved = writer.save();
+            writer.clear_screen();
+            saved
+        });
+        FullScreen { saved: Some(saved) }
+    }
+
+    /// Show `text` on one of the `TEXT_ROWS` rows.
+    pub fn draw_row(&self, row: usize, text: &str) {
+        match self.saved {
+            Some(_) => interrupts::without_interrupts(|| WRITER.lock().write_row(row, text, false)),
+            None => serial_print!("\x1b[{};1H{}\x1b[K", row + 1, printable(text)),
+        }
+    }
+
+    /// Show `text` in inverted colors on the bottom row.
+    pub fn draw_status(&self, text: &str) {
+        match self.saved {
+            Some(_) => {
+                interrupts::without_interrupts(|| WRITER.lock().write_row(TEXT_ROWS, text, true))
+            }
+            None => serial_print!(
+                "\x1b[{};1H\x1b[7m{:width$}\x1b[0m",
+                TEXT_ROWS + 1,
+                printable(text),
+                width = BUFFER_WIDTH
+            ),
+        }
+    }
+
+    pub fn set_cursor(&self, row: usize, col: usize) {
+        match self.saved {
+            Some(_) => interrupts::without_interrupts(|| WRITER.lock().set_position(row, col)),
+            None => serial_print!("\x1b[{};{}H", row + 1, col + 1),
+        }
+    }
+}
+
+/// The part of `text` that fits on a row, with anything that isn't
+/// printable ASCII replaced like on the VGA screen.
+fn printable(text: &str) -> String {
+    text.chars()
+        .take(BUFFER_WIDTH)
+        .map(|c| if (' '..='~').contains(&c) { c } else { '?' })
+        .collect()
+}
+
+impl Drop for FullScreen {
+    fn drop(&mut self) {
+        match &self.saved {
+            Some(saved) => interrupts::without_interrupts(|| WRITER.lock().restore(saved)),
+            None => serial_print!("\x1b[?1049l"),
+        }
+    }
+}
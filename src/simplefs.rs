@@ -18,7 +18,7 @@ pub fn unpack(fs: Vec<u8>) -> Vec<(String, Vec<u8>)> {
                 cursor += 1;
             }
         }
-        if cursor + 4 > fs.len() {
+        if filename.is_empty() || cursor + 4 > fs.len() {
             break;
         }
         let file_len = u32::from_le_bytes(fs[cursor..cursor + 4].try_into().unwrap()) as usize;
@@ -36,6 +36,36 @@ pub fn unpack(fs: Vec<u8>) -> Vec<(String, Vec<u8>)> {
     files
 }
 
+/// Whether `fs` holds a well-formed image: entries with printable names and
+/// lengths that fit, followed by a zero byte or the end. A zeroed disk is an
+/// empty image.
+pub fn is_valid(fs: &[u8]) -> bool {
+    let mut cursor = 0;
+    while cursor < fs.len() && fs[cursor] != 0 {
+        let name_len = match fs[cursor..].iter().position(|&byte| byte == 0) {
+            Some(len) => len,
+            None => return false,
+        };
+        if !fs[cursor..cursor + name_len]
+            .iter()
+            .all(|&byte| (0x20..0x7f).contains(&byte))
+        {
+            return false;
+        }
+        cursor += name_len + 1;
+        let file_len = match fs.get(cursor..cursor + 4) {
+            Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()) as usize,
+            None => return false,
+        };
+        cursor += 4;
+        if file_len > fs.len() - cursor {
+            return false;
+        }
+        cursor += file_len;
+    }
+    true
+}
+
 pub fn pack(files: Vec<(String, Vec<u8>)>) -> Vec<u8> {
     let mut fs = vec![];
 
@@ -48,3 +78,15 @@ pub fn pack(files: Vec<(String, Vec<u8>)>) -> Vec<u8> {
     }
     fs
 }
+
+#[test_case]
+fn test_is_valid() {
+    let mut image = pack(vec![(String::from("a.txt"), b"hello".to_vec())]);
+    image.push(0);
+    assert!(is_valid(&image));
+    assert!(is_valid(&[0; 16]));
+    // a length reaching past the end
+    assert!(!is_valid(&image[..image.len() - 2]));
+    // not a file name
+    assert!(!is_valid(b"\x7fELF\x02\x01\x01\0\0\0\0\0"));
+}
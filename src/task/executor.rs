@@ -1,16 +1,33 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use super::{Task, TaskId, TaskState, CANCEL_QUEUE, SPAWN_QUEUE};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 
+/// Set when a woken task didn't fit in the task queue. The executor then
+/// looks for woken tasks among all of them.
+static OVERFLOWED: AtomicBool = AtomicBool::new(false);
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
+    /// Tasks woken by wakers, which may run in interrupt handlers and so
+    /// can't allocate
     task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    /// Tasks to poll that the executor found itself: new ones, and woken
+    /// ones that didn't fit in `task_queue`
+    ready: VecDeque<TaskId>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
 }
 
 struct TaskWaker {
     task_id: TaskId,
+    /// Whether the task is waiting to be polled, so that it is queued only
+    /// once however often it is woken
+    queued: AtomicBool,
     task_queue: Arc<ArrayQueue<TaskId>>,
 }
 
@@ -19,6 +36,7 @@ impl Executor {
         Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
+            ready: VecDeque::new(),
             waker_cache: BTreeMap::new(),
         }
     }
@@ -33,57 +51,111 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.ready.is_empty()
+            && self.task_queue.is_empty()
+            && !OVERFLOWED.load(Ordering::Relaxed)
+        {
             enable_and_hlt();
         } else {
             interrupts::enable();
         }
     }
     pub fn spawn(&mut self, task: Task) {
+        super::register(&task, true);
+        self.insert(task);
+    }
+    fn insert(&mut self, task: Task) {
         let task_id = task.id;
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        let waker = TaskWaker::new(task_id, self.task_queue.clone());
+        waker.queued.store(true, Ordering::Relaxed);
+        self.waker_cache.insert(task_id, waker);
+        self.ready.push_back(task_id);
+    }
+    /// Start tasks spawned with `task::spawn` and drop cancelled ones.
+    fn update_tasks(&mut self) {
+        loop {
+            // a `while let` would keep the queue locked for the whole body
+            let next = SPAWN_QUEUE.0.lock().pop_front();
+            let Some(task) = next else { break };
+            self.insert(task);
+        }
+        while let Some(task_id) = CANCEL_QUEUE.pop() {
+            // dropping the future runs its destructors, which may use the
+            // task list, so do it before unregistering
+            drop(self.tasks.remove(&task_id));
+            self.waker_cache.remove(&task_id);
+            super::unregister(task_id);
+        }
     }
     fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+        loop {
+            self.update_tasks();
+
+            // destructure `self` to avoid borrow checker errors
+            let Self {
+                tasks,
+                task_queue,
+                ready,
+                waker_cache,
+            } = self;
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
+            if OVERFLOWED.swap(false, Ordering::Relaxed) {
+                // some wakes only set `queued`; find those tasks
+                ready.extend(
+                    waker_cache
+                        .values()
+                        .filter(|waker| waker.queued.load(Ordering::Relaxed))
+                        .map(|waker| waker.task_id),
+                );
+            }
+            let task_id = match ready.pop_front().or_else(|| task_queue.pop()) {
+                Some(task_id) => task_id,
+                None => break,
+            };
+            let (task, waker) = match (tasks.get_mut(&task_id), waker_cache.get(&task_id)) {
+                (Some(task), Some(waker)) => (task, waker),
+                _ => continue, // task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
+            // an id can be queued twice after an overflow; poll it once
+            if !waker.queued.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            let waker = Waker::from(waker.clone());
+            let mut context = Context::from_waker(&waker);
+            super::set_state(task_id, TaskState::Running);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    super::unregister(task_id);
                 }
-                Poll::Pending => {}
+                Poll::Pending => super::set_state(task_id, TaskState::Waiting),
             }
         }
     }
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
+            queued: AtomicBool::new(false),
             task_queue,
-        }))
+        })
     }
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        if self.queued.swap(true, Ordering::Relaxed) {
+            return; // already waiting to be polled
+        }
+        // wakers are called from interrupt handlers, which can't allocate to
+        // grow the queue; the executor finds the task by its flag instead
+        if self.task_queue.push(self.task_id).is_err() {
+            OVERFLOWED.store(true, Ordering::Relaxed);
+        }
     }
 }
 
@@ -1,4 +1,6 @@
-use crate::{print, serial_print};
+use crate::io::{self, Console};
+use crate::{history, print, serial, serial_print};
+use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
@@ -10,6 +12,7 @@ use crossbeam_queue::ArrayQueue;
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
+use log::warn;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
 use spin::Mutex;
 
@@ -31,37 +34,68 @@ use crate::println;
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            warn!("scancode queue full; dropping keyboard input");
         } else {
             WAKER.wake();
         }
     } else {
-        println!("WARNING: scancode queue uninitialized");
+        warn!("scancode queue uninitialized");
     }
 }
 
+/// Keys without a character of their own are sent through the input stream
+/// as `\x1b` followed by one of these.
+const ESC_LEFT: char = '<';
+const ESC_RIGHT: char = '>';
+const ESC_UP: char = '^';
+const ESC_DOWN: char = 'v';
+const ESC_HOME: char = 'H';
+const ESC_END: char = 'E';
+const ESC_PAGE_UP: char = 'P';
+const ESC_PAGE_DOWN: char = 'N';
+const ESC_ESCAPE: char = '\x1b';
+
+pub const BACKSPACE: char = '\u{8}';
+pub const DELETE: char = '\x7f';
+const CTRL_G: char = '\x07';
+const CTRL_R: char = '\x12';
+const TAB: char = '\t';
+
+/// Returns the candidates for completing the last word of the given text,
+/// which is the part of the line before the cursor.
+pub type Completer = fn(&str) -> Vec<String>;
+
+static COMPLETER: Mutex<Option<Completer>> = Mutex::new(None);
+
+/// Set the function `read_line` calls to complete words when Tab is pressed.
+pub fn set_completer(completer: Completer) {
+    *COMPLETER.lock() = Some(completer);
+}
+
 pub async fn save_keypresses() {
     let mut scancodes = ScancodeStream::new();
     let mut keyboard = Keyboard::new(
         ScancodeSet1::new(),
         layouts::Us104Key,
-        HandleControl::Ignore,
+        HandleControl::MapLettersToUnicode,
     );
 
     while let Some(scancode) = scancodes.next().await {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 match key {
+                    DecodedKey::Unicode('\x1b') => push_escape(ESC_ESCAPE),
                     DecodedKey::Unicode(character) => push_char(character),
                     DecodedKey::RawKey(key) => match key {
-                        KeyCode::ArrowLeft => {
-                            push_char('\x1b');
-                            push_char('<')
-                        }
-                        KeyCode::ArrowRight => {
-                            push_char('\x1b');
-                            push_char('>')
-                        }
+                        KeyCode::ArrowLeft => push_escape(ESC_LEFT),
+                        KeyCode::ArrowRight => push_escape(ESC_RIGHT),
+                        KeyCode::ArrowUp => push_escape(ESC_UP),
+                        KeyCode::ArrowDown => push_escape(ESC_DOWN),
+                        KeyCode::Home => push_escape(ESC_HOME),
+                        KeyCode::End => push_escape(ESC_END),
+                        KeyCode::PageUp => push_escape(ESC_PAGE_UP),
+                        KeyCode::PageDown => push_escape(ESC_PAGE_DOWN),
+                        KeyCode::Delete => push_char(DELETE),
                         _ => {}
                     },
                 }
@@ -73,113 +107,312 @@ pub async fn save_keypresses() {
 fn push_char(character: char) {
     let queue = STDIN_QUEUE.lock();
     if let Err(_) = queue.push(character) {
-        println!("WARNING: character queue full; dropping keyboard input");
+        warn!("character queue full; dropping keyboard input");
     } else {
         STDIN_WAKER.wake();
     }
 }
 
-pub async fn read_line() -> String {
-    let mut characters = InputStream {};
-    let mut line = String::new();
-    let mut pos: usize = 0;
-    let mut esc = false;
-    print!("\x1bi");
+fn push_escape(code: char) {
+    push_char('\x1b');
+    push_char(code);
+}
 
+/// A key press read from the input stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Escape,
+}
+
+/// Wait for the next key press, or key typed on the serial terminal if
+/// that is the running task's console.
+pub async fn read_key() -> Key {
+    if io::console() == Console::Serial {
+        return serial::read_key().await;
+    }
+    let mut characters = InputStream {};
     loop {
-        if let Some(character) = characters.next().await {
-            if !esc && character != '\n' {
-                if pos > 0 && pos < line.len() {
-                    // When inserting a character, temporarily create a new line with the inserted character
-                    let mut temp_line = line.clone();
-                    temp_line.insert(temp_line.len() - pos, character as char);
-
-                    // Clear the current line and redraw it with the new character inserted
-                    print!(
-                        "{}{}{}{}{}",
-                        "\x1b<".repeat(line.len()), // Move cursor to start
-                        " ".repeat(line.len()),     // Clear the line
-                        "\x1b<".repeat(line.len()), // Move cursor back to start
-                        temp_line, // Print the new line with the inserted character
-                        "\x1b<".repeat(pos)  // Move the cursor back to the correct position
-                    );
-                } else {
-                    print!("{}", character); // Normal printing if at the end of the line
-                }
+        match characters.next().await {
+            Some('\x1b') => {}
+            Some(character) => return Key::Char(character),
+            None => continue,
+        }
+        if let Some(code) = characters.next().await {
+            return match code {
+                ESC_LEFT => Key::Left,
+                ESC_RIGHT => Key::Right,
+                ESC_UP => Key::Up,
+                ESC_DOWN => Key::Down,
+                ESC_HOME => Key::Home,
+                ESC_END => Key::End,
+                ESC_PAGE_UP => Key::PageUp,
+                ESC_PAGE_DOWN => Key::PageDown,
+                _ => Key::Escape,
+            };
+        }
+    }
+}
+
+/// State of an incremental history search started with Ctrl-R.
+struct Search {
+    query: String,
+    /// History index of the current match, 0 being the newest entry
+    found: Option<usize>,
+}
+
+/// The line being edited by `read_line` and what is shown of it on screen.
+struct LineEditor<'a> {
+    prompt: &'a str,
+    line: String,
+    cursor: usize,
+    shown: usize,
+    shown_cursor: usize,
+    /// History index of the entry being edited, 0 being the newest entry
+    history_index: Option<usize>,
+    /// The line typed before browsing the history
+    draft: String,
+    search: Option<Search>,
+}
+
+impl<'a> LineEditor<'a> {
+    fn new(prompt: &'a str) -> Self {
+        LineEditor {
+            prompt,
+            line: String::new(),
+            cursor: 0,
+            shown: 0,
+            shown_cursor: 0,
+            history_index: None,
+            draft: String::new(),
+            search: None,
+        }
+    }
+
+    /// Redraw the line, or the search prompt while searching.
+    fn redraw(&mut self) {
+        let text = match &self.search {
+            Some(search) => format!(
+                "(reverse-i-search)`{}': {}",
+                search.query,
+                search.found.and_then(history::get).unwrap_or_default()
+            ),
+            None => self.line.clone(),
+        };
+        let cursor = match self.search {
+            Some(_) => text.len(),
+            None => self.cursor,
+        };
+        let padding = self.shown.saturating_sub(text.len());
+        print!(
+            "{}{}{}{}",
+            "\x1b<".repeat(self.shown_cursor), // Move cursor to start
+            text,                              // Print the new line
+            " ".repeat(padding),               // Clear what is left of the old one
+            "\x1b<".repeat(text.len() + padding - cursor) // Move the cursor back to the correct position
+        );
+        self.shown = text.len();
+        self.shown_cursor = cursor;
+    }
+
+    /// Complete the word before the cursor. When there are several candidates
+    /// it is extended as far as they agree, and if that adds nothing they are
+    /// listed below the line.
+    fn complete(&mut self) {
+        let completer = match *COMPLETER.lock() {
+            Some(completer) => completer,
+            None => return,
+        };
+        let before = &self.line[..self.cursor];
+        let word_start = before.rfind(' ').map_or(0, |i| i + 1);
+        let word_len = self.cursor - word_start;
+        let candidates = completer(before);
+
+        let completion = match candidates.as_slice() {
+            [] => return,
+            [candidate] => format!("{} ", candidate),
+            _ => String::from(common_prefix(&candidates)),
+        };
+        if completion.len() > word_len && completion.is_ascii() {
+            self.line
+                .replace_range(word_start..self.cursor, &completion);
+            self.cursor = word_start + completion.len();
+        } else if candidates.len() > 1 {
+            // list the candidates and start over on a new line
+            println!("\x1bi");
+            println!("{}", candidates.join("  "));
+            print!("{}\x1bi", self.prompt);
+            self.shown = 0;
+            self.shown_cursor = 0;
+        }
+    }
+
+    fn set_line(&mut self, line: String) {
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    fn history_up(&mut self) {
+        let index = self.history_index.map_or(0, |index| index + 1);
+        if let Some(entry) = history::get(index) {
+            if self.history_index.is_none() {
+                self.draft = core::mem::take(&mut self.line);
             }
+            self.history_index = Some(index);
+            self.set_line(entry);
+        }
+    }
 
-            if esc {
-                match character {
-                    '<' => {
-                        if pos < line.len() {
-                            pos += 1;
-                            print!("\x1b<");
-
-                            // Redraw the line after moving the cursor left
-                            print!(
-                                "{}{}{}",
-                                "\x1b<".repeat(line.len()), // Move cursor to start
-                                line,                       // Redraw the line
-                                "\x1b<".repeat(pos) // Move cursor back to the correct position
-                            );
-                        }
-                    }
-                    '>' => {
-                        if pos > 0 {
-                            pos -= 1;
-                            print!("\x1b>");
-
-                            // Redraw the line after moving the cursor right
-                            print!(
-                                "{}{}{}",
-                                "\x1b<".repeat(line.len()), // Move cursor to start
-                                line,                       // Redraw the line
-                                "\x1b<".repeat(pos) // Move cursor back to the correct position
-                            );
-                        }
-                    }
-                    _ => {}
+    fn history_down(&mut self) {
+        match self.history_index {
+            Some(0) => {
+                self.history_index = None;
+                let draft = core::mem::take(&mut self.draft);
+                self.set_line(draft);
+            }
+            Some(index) => {
+                self.history_index = Some(index - 1);
+                self.set_line(history::get(index - 1).unwrap_or_default());
+            }
+            None => {}
+        }
+    }
+
+    /// Handle a key while searching the history.
+    fn search_key(&mut self, key: Key) {
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => return,
+        };
+        match key {
+            Key::Char(CTRL_R) => {
+                let from = search.found.map_or(0, |index| index + 1);
+                if let Some(index) = history::search(&search.query, from) {
+                    search.found = Some(index);
+                }
+            }
+            Key::Char(BACKSPACE) => {
+                search.query.pop();
+                search.found = history::search(&search.query, 0);
+            }
+            Key::Char(CTRL_G) | Key::Escape => self.search = None,
+            Key::Char(character) if is_printable(character) => {
+                search.query.push(character);
+                search.found = history::search(&search.query, search.found.unwrap_or(0));
+            }
+            _ => {
+                // Any other key ends the search, keeping the match
+                if let Some(index) = search.found {
+                    self.history_index = Some(index);
+                    self.set_line(history::get(index).unwrap_or_default());
                 }
-                esc = false;
-            } else {
-                match character {
-                    '\n' => {
-                        // Clear the current line and redraw it with the new character inserted
-                        println!(
-                            "{}{}",
-                            "\x1b<".repeat(line.len()), // Move cursor to start
-                            line                        // Redraw line
-                        );
-                        break;
-                    } // End of input
-                    '\u{8}' => {
-                        // Handle backspace
-                        if pos < line.len() {
-                            line.remove((line.len() - pos) - 1);
-
-                            // Clear and redraw the line after removing a character
-                            print!(
-                                "{}{}{}{}{}",
-                                "\x1b<".repeat(line.len() + 1), // Move cursor to start
-                                " ".repeat(line.len()),         // Clear the line
-                                "\x1b<".repeat(line.len() + 1), // Move cursor back to start
-                                line,                           // Redraw the line
-                                "\x1b<".repeat(pos) // Move cursor back to the correct position
-                            );
-                        }
-                        continue;
-                    }
-                    '\x1b' => esc = true, // Begin escape sequence
-                    _ => {}
+                self.search = None;
+            }
+        }
+    }
+
+    fn key(&mut self, key: Key) {
+        match key {
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up => self.history_up(),
+            Key::Down => self.history_down(),
+            Key::Char(BACKSPACE) => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
                 }
-                if !esc {
-                    line.insert(line.len() - pos, character as char);
+            }
+            Key::Char(DELETE) => {
+                if self.cursor < self.line.len() {
+                    self.line.remove(self.cursor);
                 }
             }
+            Key::Char(TAB) => self.complete(),
+            Key::Char(CTRL_R) => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                })
+            }
+            Key::Char(character) if is_printable(character) => {
+                self.line.insert(self.cursor, character);
+                self.cursor += 1;
+            }
+            _ => {}
         }
     }
+}
+
+/// Only printable ASCII can be shown on the VGA text screen.
+fn is_printable(character: char) -> bool {
+    character.is_ascii() && !character.is_ascii_control()
+}
+
+/// The longest prefix shared by all `words`.
+fn common_prefix(words: &[String]) -> &str {
+    let first = match words.first() {
+        Some(first) => first.as_str(),
+        None => return "",
+    };
+    let mut len = first.len();
+    for word in &words[1..] {
+        len = first
+            .bytes()
+            .zip(word.bytes())
+            .take(len)
+            .take_while(|(a, b)| a == b)
+            .count();
+    }
+    &first[..len]
+}
+
+/// Read a line from the keyboard, with cursor movement, history (Up/Down),
+/// incremental history search (Ctrl-R) and completion (Tab).
+pub async fn read_line() -> String {
+    prompt("").await
+}
+
+/// Print `prompt` and read a line like `read_line`. The prompt is printed
+/// again when completion candidates are listed.
+pub async fn prompt(prompt: &str) -> String {
+    let mut editor = LineEditor::new(prompt);
+    print!("{}\x1bi", prompt);
+
+    loop {
+        let key = read_key().await;
+        if key == Key::Char('\n') {
+            if let Some(Search {
+                found: Some(index), ..
+            }) = editor.search
+            {
+                editor.set_line(history::get(index).unwrap_or_default());
+            }
+            editor.search = None;
+            editor.cursor = editor.line.len();
+            editor.redraw();
+            println!();
+            break; // End of input
+        }
+        if editor.search.is_some() {
+            editor.search_key(key);
+        } else {
+            editor.key(key);
+        }
+        editor.redraw();
+    }
     print!("\x1bi"); // Reset or exit the input mode
-    line
+    editor.line
 }
 
 pub struct InputStream;
@@ -242,3 +475,12 @@ impl Stream for ScancodeStream {
         }
     }
 }
+
+#[test_case]
+fn test_common_prefix() {
+    let words = |words: &[&str]| words.iter().map(|w| String::from(*w)).collect::<Vec<_>>();
+    assert_eq!(common_prefix(&words(&["hello.wasm", "help.txt"])), "hel");
+    assert_eq!(common_prefix(&words(&["ls"])), "ls");
+    assert_eq!(common_prefix(&words(&["ls", "echo"])), "");
+    assert_eq!(common_prefix(&[]), "");
+}
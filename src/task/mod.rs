@@ -1,13 +1,23 @@
 use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
 use core::sync::atomic::{AtomicU64, Ordering};
-use core::task::{Context, Poll};
+use core::task::{Context, Poll, Waker};
 use core::{future::Future, pin::Pin};
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use spin::Mutex;
 
 pub mod executor;
 pub mod keyboard;
+pub mod timer;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
@@ -15,20 +25,242 @@ impl TaskId {
     }
 }
 
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for TaskId {
+    type Err = core::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(TaskId)
+    }
+}
+
 pub struct Task {
     id: TaskId,
+    name: String,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::named("task", future)
+    }
+
+    /// A task shown as `name` in the task list.
+    pub fn named(name: &str, future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(), // new
+            name: String::from(name),
             future: Box::pin(future),
         }
     }
 
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Being polled right now.
+    Running,
+    /// Waiting to be woken up.
+    Waiting,
+}
+
+/// A snapshot of a task that hasn't finished yet.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub state: TaskState,
+    /// Started by the kernel at boot, like the shells and the tasks serving
+    /// interrupts, rather than with `spawn`.
+    pub kernel: bool,
+}
+
+struct Entry {
+    name: String,
+    state: TaskState,
+    kernel: bool,
+    /// Woken when the task finishes or is cancelled.
+    waiters: Vec<Waker>,
+}
+
+/// Tasks spawned from inside other tasks, picked up by the executor between
+/// polls.
+struct SpawnQueue(Mutex<VecDeque<Task>>);
+
+// Tasks aren't `Send`, but there is only one CPU and tasks are never spawned
+// from interrupt handlers, so the queue is never shared between threads.
+unsafe impl Sync for SpawnQueue {}
+
+static SPAWN_QUEUE: SpawnQueue = SpawnQueue(Mutex::new(VecDeque::new()));
+
+/// The task being polled, or `u64::MAX` outside of tasks.
+static CURRENT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+lazy_static! {
+    /// All tasks that haven't finished yet.
+    static ref TASKS: Mutex<BTreeMap<TaskId, Entry>> = Mutex::new(BTreeMap::new());
+    static ref CANCEL_QUEUE: ArrayQueue<TaskId> = ArrayQueue::new(100);
+}
+
+/// Run `future` as a new task, returning a handle that can be awaited for
+/// its output.
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+{
+    let output = Arc::new(Mutex::new(None));
+    let slot = output.clone();
+    let task = Task::named(name, async move {
+        let value = future.await;
+        *slot.lock() = Some(value);
+    });
+    let id = task.id;
+    register(&task, false);
+    crate::io::inherit_console(id);
+    SPAWN_QUEUE.0.lock().push_back(task);
+    JoinHandle { id, output }
+}
+
+/// The task that is running, if any.
+pub fn current() -> Option<TaskId> {
+    match CURRENT.load(Ordering::Relaxed) {
+        u64::MAX => None,
+        id => Some(TaskId(id)),
+    }
+}
+
+/// Ask the executor to drop the task `id`, returning false if there is no
+/// such task. The task stops the next time the executor gets control.
+pub fn cancel(id: TaskId) -> bool {
+    if !TASKS.lock().contains_key(&id) {
+        return false;
+    }
+    CANCEL_QUEUE.push(id).is_ok()
+}
+
+/// All tasks that haven't finished, sorted by ID.
+pub fn list() -> Vec<TaskInfo> {
+    TASKS
+        .lock()
+        .iter()
+        .map(|(&id, entry)| entry.info(id))
+        .collect()
+}
+
+/// The task `id`, if it hasn't finished.
+pub fn info(id: TaskId) -> Option<TaskInfo> {
+    TASKS.lock().get(&id).map(|entry| entry.info(id))
+}
+
+/// Wait until the task `id` has finished or been cancelled.
+pub async fn wait(id: TaskId) {
+    core::future::poll_fn(|context| poll_finished(id, context)).await
+}
+
+fn poll_finished(id: TaskId, context: &mut Context) -> Poll<()> {
+    match TASKS.lock().get_mut(&id) {
+        Some(entry) => {
+            let waker = context.waker();
+            if !entry.waiters.iter().any(|w| w.will_wake(waker)) {
+                entry.waiters.push(waker.clone());
+            }
+            Poll::Pending
+        }
+        None => Poll::Ready(()),
+    }
+}
+
+impl Entry {
+    fn info(&self, id: TaskId) -> TaskInfo {
+        TaskInfo {
+            id,
+            name: self.name.clone(),
+            state: self.state,
+            kernel: self.kernel,
+        }
+    }
+}
+
+fn register(task: &Task, kernel: bool) {
+    TASKS.lock().insert(
+        task.id,
+        Entry {
+            name: task.name.clone(),
+            state: TaskState::Waiting,
+            kernel,
+            waiters: Vec::new(),
+        },
+    );
+}
+
+fn set_state(id: TaskId, state: TaskState) {
+    if let Some(entry) = TASKS.lock().get_mut(&id) {
+        entry.state = state;
+    }
+    match state {
+        TaskState::Running => CURRENT.store(id.0, Ordering::Relaxed),
+        TaskState::Waiting => CURRENT.store(u64::MAX, Ordering::Relaxed),
+    }
+}
+
+/// Forget a task that finished or was dropped and wake everyone waiting for
+/// it.
+fn unregister(id: TaskId) {
+    CURRENT.store(u64::MAX, Ordering::Relaxed);
+    crate::io::release(id);
+    let entry = TASKS.lock().remove(&id);
+    if let Some(entry) = entry {
+        for waker in entry.waiters {
+            waker.wake();
+        }
+    }
+}
+
+/// A handle to a spawned task. Awaiting it gives the task's output, or
+/// `None` if the task was cancelled.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    output: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Whether the task has finished or been cancelled.
+    pub fn is_finished(&self) -> bool {
+        !TASKS.lock().contains_key(&self.id)
+    }
+
+    pub fn cancel(&self) -> bool {
+        cancel(self.id)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.output.lock().take() {
+            return Poll::Ready(Some(value));
+        }
+        match poll_finished(self.id, context) {
+            // the output is stored before the task finishes
+            Poll::Ready(()) => Poll::Ready(self.output.lock().take()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::future::{select, Either};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Input clock of the programmable interval timer in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// The PIT counts down from 65536 unless programmed otherwise.
+const DEFAULT_DIVISOR: u64 = 65536;
+
+/// Number of slots in the timer wheel. Timers due further ahead than this
+/// many ticks stay in their slot for more rounds.
+const WHEEL_SIZE: usize = 256;
+
+/// Timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Length of a tick in nanoseconds.
+static TICK_NANOS: AtomicU64 = AtomicU64::new(DEFAULT_DIVISOR * 1_000_000_000 / PIT_FREQUENCY);
+
+/// The tick count and uptime in nanoseconds when the tick length last
+/// changed. Uptime is counted from there, so that a new tick length only
+/// applies to the ticks that follow.
+static BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// The earliest tick any timer is waiting for.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Wakes the timer task when a deadline has passed.
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+struct Timer {
+    id: u64,
+    /// The tick `deadline_nanos` falls on, which picks the slot
+    deadline: u64,
+    deadline_nanos: u64,
+    waker: Waker,
+}
+
+/// A hashed timer wheel: timers are kept in the slot for their deadline
+/// modulo `WHEEL_SIZE`, so each tick only has to look at one slot.
+struct Wheel {
+    slots: Vec<Vec<Timer>>,
+    /// The last tick whose slot has been processed.
+    processed: u64,
+    next_id: u64,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    slots: Vec::new(),
+    processed: 0,
+    next_id: 0,
+});
+
+impl Wheel {
+    fn slot(&mut self, deadline: u64) -> &mut Vec<Timer> {
+        if self.slots.is_empty() {
+            // the tick length is set before there is a heap to allocate on
+            self.slots = (0..WHEEL_SIZE).map(|_| Vec::new()).collect();
+        }
+        &mut self.slots[deadline as usize % WHEEL_SIZE]
+    }
+
+    fn insert(&mut self, deadline_nanos: u64, waker: Waker) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.add(Timer {
+            id,
+            deadline: nanos_to_tick(deadline_nanos),
+            deadline_nanos,
+            waker,
+        });
+        id
+    }
+
+    fn add(&mut self, timer: Timer) {
+        NEXT_DEADLINE.fetch_min(timer.deadline, Ordering::Relaxed);
+        self.slot(timer.deadline).push(timer);
+    }
+
+    fn update(&mut self, id: u64, deadline_nanos: u64, waker: &Waker) {
+        let slot = self.slot(nanos_to_tick(deadline_nanos));
+        if let Some(timer) = slot.iter_mut().find(|timer| timer.id == id) {
+            if !timer.waker.will_wake(waker) {
+                timer.waker = waker.clone();
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64, deadline_nanos: u64) {
+        self.slot(nanos_to_tick(deadline_nanos))
+            .retain(|timer| timer.id != id);
+    }
+
+    /// Move every timer to the slot of the tick its deadline now falls on,
+    /// after the tick length changed at tick `now`.
+    fn reslot(&mut self, now: u64) {
+        let timers: Vec<Timer> = self
+            .slots
+            .iter_mut()
+            .flat_map(|slot| slot.drain(..))
+            .collect();
+        NEXT_DEADLINE.store(u64::MAX, Ordering::Relaxed);
+        for mut timer in timers {
+            timer.deadline = nanos_to_tick(timer.deadline_nanos);
+            // `expire` has been past the slots up to `now`
+            if timer.deadline <= now {
+                timer.waker.wake();
+            } else {
+                self.add(timer);
+            }
+        }
+    }
+
+    /// Wake the timers that are due at `now`.
+    fn expire(&mut self, now: u64) {
+        // a full turn of the wheel visits every slot
+        let first = self.processed.max(now.saturating_sub(WHEEL_SIZE as u64)) + 1;
+        for tick in first..=now {
+            let slot = self.slot(tick);
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].deadline <= now {
+                    slot.swap_remove(i).waker.wake();
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.processed = now;
+        let next = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|timer| timer.deadline)
+            .min()
+            .unwrap_or(u64::MAX);
+        NEXT_DEADLINE.store(next, Ordering::Relaxed);
+    }
+}
+
+/// Called by the timer interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if now >= NEXT_DEADLINE.load(Ordering::Relaxed) {
+        WAKER.wake();
+    }
+}
+
+/// Timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The time between two timer interrupts.
+pub fn tick_duration() -> Duration {
+    Duration::from_nanos(TICK_NANOS.load(Ordering::Relaxed))
+}
+
+/// Change the tick length after reprogramming the timer. Time already
+/// counted and the deadlines of sleeping tasks stay as they were.
+pub fn set_tick_duration(duration: Duration) {
+    let nanos = u64::try_from(duration.as_nanos())
+        .unwrap_or(u64::MAX)
+        .max(1);
+    // interrupts don't touch the wheel, so it's safe to lock here
+    let mut wheel = WHEEL.lock();
+    interrupts::without_interrupts(|| {
+        let now = ticks();
+        BASE_NANOS.store(uptime_nanos(), Ordering::Relaxed);
+        BASE_TICKS.store(now, Ordering::Relaxed);
+        TICK_NANOS.store(nanos, Ordering::Relaxed);
+        wheel.reslot(now);
+    });
+}
+
+/// Time since boot in nanoseconds, with the resolution of one tick.
+fn uptime_nanos() -> u64 {
+    let ticks = ticks().saturating_sub(BASE_TICKS.load(Ordering::Relaxed));
+    BASE_NANOS
+        .load(Ordering::Relaxed)
+        .saturating_add(ticks.saturating_mul(TICK_NANOS.load(Ordering::Relaxed)))
+}
+
+/// The first tick at which the uptime reaches `nanos`.
+fn nanos_to_tick(nanos: u64) -> u64 {
+    let nanos = nanos.saturating_sub(BASE_NANOS.load(Ordering::Relaxed));
+    BASE_TICKS
+        .load(Ordering::Relaxed)
+        .saturating_add(nanos.div_ceil(TICK_NANOS.load(Ordering::Relaxed)))
+}
+
+/// Time since boot, with the resolution of one tick.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(uptime_nanos())
+}
+
+/// The number of ticks that last at least `duration`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = TICK_NANOS.load(Ordering::Relaxed) as u128;
+    u64::try_from(duration.as_nanos().div_ceil(nanos)).unwrap_or(u64::MAX)
+}
+
+/// The task that wakes sleeping tasks when their time has come.
+pub async fn run() {
+    core::future::poll_fn(|cx: &mut Context| -> Poll<()> {
+        WAKER.register(cx.waker());
+        // interrupts don't touch the wheel, so it's safe to lock here
+        WHEEL.lock().expire(ticks());
+        Poll::Pending
+    })
+    .await
+}
+
+/// Block until `ticks` more timer interrupts have happened.
+///
+/// For synchronous code such as drivers; async code should use `sleep`.
+/// With interrupts disabled the tick counter stands still, so this busy
+/// waits instead.
+pub fn wait_ticks(ticks: u64) {
+    if !x86_64::instructions::interrupts::are_enabled() {
+        crate::time::busy_wait(Duration::from_nanos(
+            TICK_NANOS.load(Ordering::Relaxed).saturating_mul(ticks),
+        ));
+        return;
+    }
+    let deadline = self::ticks().saturating_add(ticks);
+    while self::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// A future that completes once the uptime reaches its deadline.
+pub struct Sleep {
+    /// Uptime in nanoseconds
+    deadline: u64,
+    timer: Option<u64>,
+}
+
+/// Wait for at least `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    Sleep {
+        deadline: uptime_nanos().saturating_add(nanos),
+        timer: None,
+    }
+}
+
+pub fn sleep_ticks(ticks: u64) -> Sleep {
+    sleep(Duration::from_nanos(
+        TICK_NANOS.load(Ordering::Relaxed).saturating_mul(ticks),
+    ))
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let deadline = self.deadline;
+        if uptime_nanos() >= deadline {
+            if let Some(id) = self.timer.take() {
+                WHEEL.lock().remove(id, deadline);
+            }
+            return Poll::Ready(());
+        }
+        let mut wheel = WHEEL.lock();
+        match self.timer {
+            Some(id) => wheel.update(id, deadline, cx.waker()),
+            None => self.timer = Some(wheel.insert(deadline, cx.waker().clone())),
+        }
+        drop(wheel);
+        // the deadline may have passed before the timer was added
+        if uptime_nanos() >= deadline {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer {
+            WHEEL.lock().remove(id, self.deadline);
+        }
+    }
+}
+
+/// Error returned by `timeout` when the time ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Run `future` for at most `duration`, dropping it if it takes longer.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    match select(pin!(future), sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Elapsed),
+    }
+}
+
+#[test_case]
+fn test_duration_to_ticks() {
+    let tick = tick_duration();
+    assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+    assert_eq!(duration_to_ticks(tick), 1);
+    assert_eq!(duration_to_ticks(tick + Duration::from_nanos(1)), 2);
+    assert_eq!(duration_to_ticks(tick * 10), 10);
+    assert_eq!(duration_to_ticks(Duration::MAX), u64::MAX);
+}
+
+#[test_case]
+fn test_set_tick_duration_keeps_uptime() {
+    let tick = tick_duration();
+    interrupts::without_interrupts(|| {
+        let before = uptime();
+        set_tick_duration(tick * 1000);
+        assert_eq!(uptime(), before);
+        set_tick_duration(tick);
+        assert_eq!(uptime(), before);
+    });
+}
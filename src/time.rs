@@ -0,0 +1,234 @@
+use core::arch::x86_64::_rdtsc;
+use core::convert::TryFrom;
+use core::hint::spin_loop;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+pub use core::time::Duration;
+
+use crate::apic;
+use crate::task::timer::{self, PIT_FREQUENCY};
+
+/// Timer interrupts per second set up by `init`.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+/// How long to count TSC cycles against the PIT at boot.
+const CALIBRATION_MS: u64 = 10;
+/// Give up on PIT channel 2 after polling it this many times, which takes
+/// far longer than a full countdown on hardware where it works. Some
+/// emulators don't implement channel 2.
+const CHANNEL2_POLLS: u32 = 1_000_000;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Gate and output of PIT channel 2, shared with the PC speaker.
+const PIT_GATE: u16 = 0x61;
+
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+/// TSC cycles per second, or 0 before calibration.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value at calibration, which counts as time 0.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// Program the PIT and calibrate the TSC. If that fails, time is measured
+/// in timer ticks.
+pub fn init() {
+    set_frequency(DEFAULT_FREQUENCY);
+    let start = Instant::now();
+    let tsc = rdtsc();
+    // interrupt handlers would count as time the PIT took
+    let frequency = match interrupts::without_interrupts(calibrate_tsc) {
+        Some(frequency) => frequency,
+        None => return,
+    };
+    // keep the clock monotonic when switching from ticks to the TSC
+    let elapsed = (start.0 as u128 * frequency as u128 / 1_000_000_000) as u64;
+    TSC_START.store(tsc - elapsed, Ordering::Relaxed);
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Make the timer interrupt `hz` times a second, as close as it allows.
+/// This is the local APIC timer once `apic::init` has switched to it, and
+/// the PIT before.
+pub fn set_frequency(hz: u32) {
+    let hz = match apic::set_timer_frequency(hz) {
+        Some(hz) => hz,
+        None => set_pit_frequency(hz),
+    };
+    FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+/// Program PIT channel 0, returning the frequency it ends up at.
+fn set_pit_frequency(hz: u32) -> u32 {
+    let divisor = (PIT_FREQUENCY / hz.max(1) as u64).clamp(1, 65536);
+    interrupts::without_interrupts(|| unsafe {
+        // channel 0, low byte then high byte, mode 3 (square wave)
+        Port::<u8>::new(PIT_COMMAND).write(0x36);
+        let mut channel0 = Port::<u8>::new(PIT_CHANNEL0);
+        // a divisor of 65536 is written as 0
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    });
+    timer::set_tick_duration(Duration::from_nanos(
+        divisor * 1_000_000_000 / PIT_FREQUENCY,
+    ));
+    (PIT_FREQUENCY / divisor) as u32
+}
+
+/// Timer interrupts per second.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// TSC cycles per second, or `None` if it hasn't been calibrated.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Make PIT channel 2 count down `latch` input cycles, 1 to 65535.
+fn start_channel2(latch: u64) {
+    let mut gate = Port::<u8>::new(PIT_GATE);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel2 = Port::<u8>::new(PIT_CHANNEL2);
+    unsafe {
+        // enable the gate with the speaker off
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+        // channel 2, low byte then high byte, mode 0 (interrupt on terminal
+        // count)
+        command.write(0xb0);
+        channel2.write(latch as u8);
+        channel2.write((latch >> 8) as u8);
+    }
+}
+
+/// Wait for PIT channel 2 to finish counting down, or return false if it
+/// never does.
+fn wait_channel2() -> bool {
+    let mut gate = Port::<u8>::new(PIT_GATE);
+    // the output goes high when the count reaches 0
+    let mut polls = 0;
+    while unsafe { gate.read() } & 0x20 == 0 {
+        polls += 1;
+        if polls == CHANNEL2_POLLS {
+            return false;
+        }
+        spin_loop();
+    }
+    true
+}
+
+/// Count TSC cycles while PIT channel 2 counts down `CALIBRATION_MS`, or
+/// return `None` if it never finishes.
+fn calibrate_tsc() -> Option<u64> {
+    start_channel2(PIT_FREQUENCY * CALIBRATION_MS / 1000);
+    let start = rdtsc();
+    if !wait_channel2() {
+        return None;
+    }
+    let end = rdtsc();
+    Some((end - start) * 1000 / CALIBRATION_MS)
+}
+
+/// Spin for at least `duration`, for code that runs with interrupts
+/// disabled. This counts TSC cycles once they are calibrated and uses PIT
+/// channel 2 before; without either it returns at once.
+pub fn busy_wait(duration: Duration) {
+    if tsc_frequency().is_some() {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            spin_loop();
+        }
+        return;
+    }
+    let cycles = (duration.as_nanos() * PIT_FREQUENCY as u128).div_ceil(1_000_000_000);
+    let mut cycles = u64::try_from(cycles).unwrap_or(u64::MAX);
+    while cycles > 0 {
+        let latch = cycles.min(0xffff);
+        start_channel2(latch);
+        if !wait_channel2() {
+            return;
+        }
+        cycles -= latch;
+    }
+}
+
+/// A point in time since boot, measured with the TSC once it is calibrated
+/// and with timer ticks before that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        match tsc_frequency() {
+            Some(frequency) => {
+                let cycles = rdtsc().saturating_sub(TSC_START.load(Ordering::Relaxed));
+                Instant((cycles as u128 * 1_000_000_000 / frequency as u128) as u64)
+            }
+            None => Instant(timer::uptime().as_nanos() as u64),
+        }
+    }
+
+    /// Time since boot.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    /// Time from `earlier` to this instant, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration.as_nanos() as u64)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Time since boot.
+pub fn uptime() -> Duration {
+    Instant::now().since_boot()
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    let start = Instant::now();
+    let end = Instant::now();
+    assert!(end >= start);
+    assert_eq!(start - end, Duration::ZERO);
+    assert_eq!(
+        (start + Duration::from_millis(5)) - start,
+        Duration::from_millis(5)
+    );
+}
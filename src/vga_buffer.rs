@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -36,6 +37,11 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// The same colors with foreground and background swapped.
+    fn inverted(self) -> ColorCode {
+        ColorCode(self.0 << 4 | self.0 >> 4)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -45,8 +51,8 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 #[repr(transparent)]
 struct Buffer {
@@ -71,6 +77,14 @@ impl Pos {
     }
 }
 
+/// Screen contents saved by `Writer::save`.
+pub struct SavedScreen {
+    chars: Vec<ScreenChar>,
+    position: usize,
+    mutable_start: usize,
+    mutable: bool,
+}
+
 pub struct Writer {
     position: Pos,
     mutable_start: Pos,
@@ -168,12 +182,68 @@ impl Writer {
         }
     }
 
-    fn clear_screen(&mut self) {
+    pub fn clear_screen(&mut self) {
         for row in 0..BUFFER_HEIGHT {
             self.clear_row(row)
         }
     }
 
+    /// Replace a whole row with `text`, cut off or padded with spaces to
+    /// the screen width, without moving the cursor.
+    pub fn write_row(&mut self, row: usize, text: &str, inverted: bool) {
+        let color_code = if inverted {
+            self.color_code.inverted()
+        } else {
+            self.color_code
+        };
+        let mut bytes = text.bytes();
+        for col in 0..BUFFER_WIDTH {
+            let ascii_character = match bytes.next() {
+                Some(byte @ 0x20..=0x7e) => byte,
+                Some(_) => 0xfe,
+                None => b' ',
+            };
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii_character,
+                color_code,
+            });
+        }
+    }
+
+    /// Move the cursor, leaving input mode.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.mutable = false;
+        self.position = Pos::new(col, row);
+        update_cursor(self.position.pos as u16);
+    }
+
+    /// Copy the screen contents and cursor so they can be restored after a
+    /// full-screen program.
+    pub fn save(&self) -> SavedScreen {
+        let mut chars = Vec::with_capacity(BUFFER_WIDTH * BUFFER_HEIGHT);
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                chars.push(self.buffer.chars[row][col].read());
+            }
+        }
+        SavedScreen {
+            chars,
+            position: self.position.pos,
+            mutable_start: self.mutable_start.pos,
+            mutable: self.mutable,
+        }
+    }
+
+    pub fn restore(&mut self, screen: &SavedScreen) {
+        for (i, &character) in screen.chars.iter().enumerate() {
+            self.buffer.chars[i / BUFFER_WIDTH][i % BUFFER_WIDTH].write(character);
+        }
+        self.position.pos = screen.position;
+        self.mutable_start.pos = screen.mutable_start;
+        self.mutable = screen.mutable;
+        update_cursor(self.position.pos as u16);
+    }
+
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
             ascii_character: b' ',
@@ -255,11 +325,18 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    use crate::io::Console;
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        if crate::io::write_captured(args) {
+            return;
+        }
+        match crate::io::console() {
+            Console::Screen => WRITER.lock().write_fmt(args).unwrap(),
+            Console::Serial => crate::serial::write_terminal(args),
+        }
     });
 }
 
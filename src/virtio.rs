@@ -0,0 +1,563 @@
+//! Virtio block devices on the PCI bus, through the legacy (virtio 0.9)
+//! port interface or the modern (virtio 1.0) memory-mapped one.
+//!
+//! Requests are issued one at a time and the used ring is polled for their
+//! completion, the same way `ata` does PIO.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::block::{self, BlockDevice, BlockError, BLOCK_SIZE};
+use crate::memory;
+use crate::pci::{self, Bar, Device};
+use crate::time::{Duration, Instant};
+
+const VENDOR: u16 = 0x1af4;
+/// Transitional block device, with both interfaces.
+const DEVICE_BLOCK_LEGACY: u16 = 0x1001;
+/// Modern-only block device (0x1040 + device type 2).
+const DEVICE_BLOCK_MODERN: u16 = 0x1042;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+const FEATURE_BLOCK_READ_ONLY: u64 = 1 << 5;
+const FEATURE_BLOCK_FLUSH: u64 = 1 << 9;
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// Legacy registers, as offsets from the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+/// Device specific configuration, without MSI-X.
+const LEGACY_CONFIG: u16 = 0x14;
+
+// Modern capabilities, by their cfg_type
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_DEVICE: u8 = 4;
+
+// Modern common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Most descriptors to use, when the device lets us choose.
+const MAX_QUEUE_SIZE: u16 = 128;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const REQUEST_OK: u8 = 0;
+
+/// Pages of data moved by one request.
+const DATA_PAGES: usize = 8;
+const BLOCKS_PER_REQUEST: usize = DATA_PAGES * PAGE_SIZE / BLOCK_SIZE;
+
+/// How long a request may take before the device is given up on.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Block devices found so far, for naming them `vda`, `vdb`, ...
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The registers of a device, legacy or modern.
+enum Transport {
+    Legacy {
+        port: u16,
+    },
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        device: VirtAddr,
+    },
+}
+
+unsafe fn read_mmio<T>(base: VirtAddr, offset: usize) -> T {
+    ptr::read_volatile((base.as_u64() as usize + offset) as *const T)
+}
+
+unsafe fn write_mmio<T>(base: VirtAddr, offset: usize, value: T) {
+    ptr::write_volatile((base.as_u64() as usize + offset) as *mut T, value)
+}
+
+impl Transport {
+    /// Find the modern interface from the vendor capabilities, falling
+    /// back to the legacy one in BAR 0.
+    fn new(device: &Device) -> Option<Transport> {
+        Transport::modern(device).or_else(|| match device.bars[0] {
+            Bar::Io { port, .. } => Some(Transport::Legacy { port }),
+            _ => None,
+        })
+    }
+
+    fn modern(device: &Device) -> Option<Transport> {
+        let mut common = None;
+        let mut notify = None;
+        let mut device_config = None;
+        for cap in &device.capabilities {
+            if cap.id != pci::CAPABILITY_VENDOR {
+                continue;
+            }
+            let cfg_type = pci::read_u8(device.address, cap.offset + 3);
+            let bar = pci::read_u8(device.address, cap.offset + 4) as usize;
+            let offset = pci::read_u32(device.address, cap.offset + 8) as u64;
+            let length = pci::read_u32(device.address, cap.offset + 12) as u64;
+            let base = match device.bars.get(bar) {
+                Some(Bar::Memory { address, .. }) => *address,
+                _ => continue,
+            };
+            let addr = match memory::map_mmio(PhysAddr::new(base + offset), length) {
+                Ok(addr) => addr,
+                Err(_) => continue,
+            };
+            match cfg_type {
+                CAP_COMMON if common.is_none() => common = Some(addr),
+                CAP_NOTIFY if notify.is_none() => {
+                    let multiplier = pci::read_u32(device.address, cap.offset + 16);
+                    notify = Some((addr, multiplier));
+                }
+                CAP_DEVICE if device_config.is_none() => device_config = Some(addr),
+                _ => {}
+            }
+        }
+        let common = common?;
+        let (notify_base, multiplier) = notify?;
+        // only queue 0 is used, so work out its notify address right away
+        let notify_off = unsafe {
+            write_mmio::<u16>(common, COMMON_QUEUE_SELECT, 0);
+            read_mmio::<u16>(common, COMMON_QUEUE_NOTIFY_OFF)
+        };
+        Some(Transport::Modern {
+            common,
+            notify: notify_base + notify_off as u64 * multiplier as u64,
+            device: device_config?,
+        })
+    }
+
+    fn status(&self) -> u8 {
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => Port::<u8>::new(port + LEGACY_STATUS).read(),
+                Transport::Modern { common, .. } => read_mmio(common, COMMON_STATUS),
+            }
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => Port::<u8>::new(port + LEGACY_STATUS).write(status),
+                Transport::Modern { common, .. } => write_mmio(common, COMMON_STATUS, status),
+            }
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => {
+                    Port::<u32>::new(port + LEGACY_DEVICE_FEATURES).read() as u64
+                }
+                Transport::Modern { common, .. } => {
+                    write_mmio::<u32>(common, COMMON_DEVICE_FEATURE_SELECT, 0);
+                    let low = read_mmio::<u32>(common, COMMON_DEVICE_FEATURE) as u64;
+                    write_mmio::<u32>(common, COMMON_DEVICE_FEATURE_SELECT, 1);
+                    let high = read_mmio::<u32>(common, COMMON_DEVICE_FEATURE) as u64;
+                    high << 32 | low
+                }
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => {
+                    Port::<u32>::new(port + LEGACY_DRIVER_FEATURES).write(features as u32)
+                }
+                Transport::Modern { common, .. } => {
+                    write_mmio::<u32>(common, COMMON_DRIVER_FEATURE_SELECT, 0);
+                    write_mmio::<u32>(common, COMMON_DRIVER_FEATURE, features as u32);
+                    write_mmio::<u32>(common, COMMON_DRIVER_FEATURE_SELECT, 1);
+                    write_mmio::<u32>(common, COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+                }
+            }
+        }
+    }
+
+    /// Select queue 0 and agree on its size.
+    fn queue_size(&self) -> u16 {
+        unsafe {
+            match *self {
+                // legacy devices decide the size themselves
+                Transport::Legacy { port } => {
+                    Port::<u16>::new(port + LEGACY_QUEUE_SELECT).write(0);
+                    Port::<u16>::new(port + LEGACY_QUEUE_SIZE).read()
+                }
+                Transport::Modern { common, .. } => {
+                    write_mmio::<u16>(common, COMMON_QUEUE_SELECT, 0);
+                    let size = read_mmio::<u16>(common, COMMON_QUEUE_SIZE).min(MAX_QUEUE_SIZE);
+                    write_mmio::<u16>(common, COMMON_QUEUE_SIZE, size);
+                    size
+                }
+            }
+        }
+    }
+
+    /// Hand the rings of queue 0 to the device.
+    fn set_queue(&self, queue: &Queue) {
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => {
+                    let pfn = queue.phys.as_u64() / PAGE_SIZE as u64;
+                    Port::<u32>::new(port + LEGACY_QUEUE_ADDRESS).write(pfn as u32);
+                }
+                Transport::Modern { common, .. } => {
+                    let phys = queue.phys.as_u64();
+                    write_mmio(common, COMMON_QUEUE_DESC, phys);
+                    write_mmio(common, COMMON_QUEUE_DRIVER, phys + queue.avail as u64);
+                    write_mmio(common, COMMON_QUEUE_DEVICE, phys + queue.used as u64);
+                    write_mmio::<u16>(common, COMMON_QUEUE_ENABLE, 1);
+                }
+            }
+        }
+    }
+
+    fn notify(&self) {
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => Port::<u16>::new(port + LEGACY_QUEUE_NOTIFY).write(0),
+                Transport::Modern { notify, .. } => write_mmio::<u16>(notify, 0, 0),
+            }
+        }
+    }
+
+    /// Read a 32-bit field of the device specific configuration.
+    fn config_u32(&self, offset: usize) -> u32 {
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => {
+                    Port::<u32>::new(port + LEGACY_CONFIG + offset as u16).read()
+                }
+                Transport::Modern { device, .. } => read_mmio(device, offset),
+            }
+        }
+    }
+}
+
+/// A split virtqueue in physically contiguous frames, laid out the way
+/// legacy devices expect: the descriptor table, then the available ring,
+/// then the used ring on the next page boundary.
+struct Queue {
+    phys: PhysAddr,
+    size: u16,
+    /// Offsets of the rings from `phys`.
+    avail: usize,
+    used: usize,
+    /// Used ring index seen last.
+    last_used: u16,
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+impl Queue {
+    fn new(size: u16) -> Option<Queue> {
+        let avail = 16 * size as usize;
+        let used = align_up(avail + 6 + 2 * size as usize, PAGE_SIZE);
+        let length = used + align_up(6 + 8 * size as usize, PAGE_SIZE);
+        let phys = memory::allocate_dma(length / PAGE_SIZE)?;
+        let queue = Queue {
+            phys,
+            size,
+            avail,
+            used,
+            last_used: 0,
+        };
+        // requests are polled for
+        unsafe { queue.write::<u16>(avail, AVAIL_F_NO_INTERRUPT) };
+        Some(queue)
+    }
+
+    unsafe fn write<T>(&self, offset: usize, value: T) {
+        write_mmio(memory::phys_to_virt(self.phys), offset, value)
+    }
+
+    unsafe fn read<T>(&self, offset: usize) -> T {
+        read_mmio(memory::phys_to_virt(self.phys), offset)
+    }
+
+    /// Put a chain of `(address, length, device writes)` buffers in the
+    /// descriptors from 0 on and make it available.
+    fn submit(&mut self, buffers: &[(PhysAddr, u32, bool)]) {
+        for (i, &(addr, len, device_writes)) in buffers.iter().enumerate() {
+            let mut flags = if device_writes { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            let descriptor = Descriptor {
+                addr: addr.as_u64(),
+                len,
+                flags,
+                next: i as u16 + 1,
+            };
+            unsafe { self.write(i * 16, descriptor) };
+        }
+        unsafe {
+            let index = self.read::<u16>(self.avail + 2);
+            self.write::<u16>(self.avail + 4 + 2 * (index % self.size) as usize, 0);
+            // the ring entry must be visible before the index
+            fence(Ordering::SeqCst);
+            self.write::<u16>(self.avail + 2, index.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Wait for the device to be done with the submitted chain.
+    fn wait(&mut self) -> Result<(), BlockError> {
+        let start = Instant::now();
+        loop {
+            fence(Ordering::SeqCst);
+            let index = unsafe { self.read::<u16>(self.used + 2) };
+            if index != self.last_used {
+                self.last_used = index;
+                return Ok(());
+            }
+            if start.elapsed() > REQUEST_TIMEOUT {
+                return Err(BlockError::Io);
+            }
+            spin_loop();
+        }
+    }
+}
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+struct VirtioBlock {
+    name: String,
+    model: String,
+    transport: Transport,
+    queue: Queue,
+    capacity: u64,
+    read_only: bool,
+    /// Whether the device has a write cache that can be flushed.
+    can_flush: bool,
+    /// A page holding the request header, with the status byte after it.
+    request: PhysAddr,
+    /// `DATA_PAGES` pages for the data of a request.
+    data: PhysAddr,
+}
+
+impl VirtioBlock {
+    fn new(device: &Device) -> Option<VirtioBlock> {
+        let transport = Transport::new(device)?;
+        let modern = matches!(transport, Transport::Modern { .. });
+
+        // reset, then go through the initialization steps
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let features = transport.device_features();
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        let wanted = features & (FEATURE_BLOCK_READ_ONLY | FEATURE_BLOCK_FLUSH);
+        if modern {
+            if features & FEATURE_VERSION_1 == 0 {
+                transport.set_status(STATUS_FAILED);
+                return None;
+            }
+            transport.set_driver_features(wanted | FEATURE_VERSION_1);
+            status |= STATUS_FEATURES_OK;
+            transport.set_status(status);
+            if transport.status() & STATUS_FEATURES_OK == 0 {
+                transport.set_status(STATUS_FAILED);
+                return None;
+            }
+        } else {
+            transport.set_driver_features(wanted);
+        }
+
+        // a request takes up to three descriptors
+        let size = transport.queue_size();
+        let queue = match Some(size).filter(|&size| size >= 3).and_then(Queue::new) {
+            Some(queue) => queue,
+            None => {
+                transport.set_status(STATUS_FAILED);
+                return None;
+            }
+        };
+        transport.set_queue(&queue);
+        let request = memory::allocate_dma(1)?;
+        let data = memory::allocate_dma(DATA_PAGES)?;
+        transport.set_status(status | STATUS_DRIVER_OK);
+
+        let capacity = transport.config_u32(0) as u64 | (transport.config_u32(4) as u64) << 32;
+        let name = block::device_name("vd", COUNT.fetch_add(1, Ordering::Relaxed));
+        Some(VirtioBlock {
+            name,
+            model: String::from(if modern {
+                "Virtio block device"
+            } else {
+                "Virtio block device (legacy)"
+            }),
+            transport,
+            queue,
+            capacity,
+            read_only: wanted & FEATURE_BLOCK_READ_ONLY != 0,
+            can_flush: wanted & FEATURE_BLOCK_FLUSH != 0,
+            request,
+            data,
+        })
+    }
+
+    /// Run one request with `blocks` blocks of data in the data pages.
+    fn request(&mut self, kind: u32, sector: u64, blocks: usize) -> Result<(), BlockError> {
+        let header = RequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        };
+        let header_len = core::mem::size_of::<RequestHeader>();
+        let status = self.request + header_len as u64;
+        unsafe {
+            let virt = memory::phys_to_virt(self.request);
+            write_mmio(virt, 0, header);
+            write_mmio::<u8>(virt, header_len, 0xff);
+        }
+        let len = (blocks * BLOCK_SIZE) as u32;
+        if blocks == 0 {
+            self.queue
+                .submit(&[(self.request, header_len as u32, false), (status, 1, true)]);
+        } else {
+            self.queue.submit(&[
+                (self.request, header_len as u32, false),
+                (self.data, len, kind == REQUEST_IN),
+                (status, 1, true),
+            ]);
+        }
+        self.transport.notify();
+        self.queue.wait()?;
+        let status = unsafe { read_mmio::<u8>(memory::phys_to_virt(status), 0) };
+        if status == REQUEST_OK {
+            Ok(())
+        } else {
+            Err(BlockError::Io)
+        }
+    }
+
+    fn data(&self) -> *mut u8 {
+        memory::phys_to_virt(self.data).as_mut_ptr()
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf.chunks_mut(BLOCKS_PER_REQUEST * BLOCK_SIZE).enumerate() {
+            let sector = block + (i * BLOCKS_PER_REQUEST) as u64;
+            self.request(REQUEST_IN, sector, chunk.len() / BLOCK_SIZE)?;
+            unsafe { ptr::copy_nonoverlapping(self.data(), chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf.chunks(BLOCKS_PER_REQUEST * BLOCK_SIZE).enumerate() {
+            let sector = block + (i * BLOCKS_PER_REQUEST) as u64;
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), self.data(), chunk.len()) };
+            self.request(REQUEST_OUT, sector, chunk.len() / BLOCK_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            return Ok(());
+        }
+        self.request(REQUEST_FLUSH, 0, 0)
+    }
+}
+
+fn probe(device: &Device) -> bool {
+    device.enable();
+    match VirtioBlock::new(device) {
+        Some(disk) => {
+            block::register(Box::new(disk));
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn init() {
+    pci::register_driver(pci::Driver {
+        name: "virtio-blk",
+        matches: &[
+            pci::Match::Id {
+                vendor: VENDOR,
+                device: DEVICE_BLOCK_LEGACY,
+            },
+            pci::Match::Id {
+                vendor: VENDOR,
+                device: DEVICE_BLOCK_MODERN,
+            },
+        ],
+        probe,
+    });
+}
@@ -1,38 +0,0 @@
-use alloc::{string::String, vec::Vec};
-use wasmi::*;
-
-use crate::println;
-
-pub fn wasm_runner(wasm: Vec<u8>) -> Result<i32, Error> {
-    let engine = Engine::default();
-
-    let module = Module::new(&engine, &mut &wasm[..])?;
-
-    // All Wasm objects operate within the context of a `Store`.
-    // Each `Store` has a type parameter to store host-specific data,
-    // which in this case we are using `42` for.
-    type HostState = u32;
-    let mut store = Store::new(&engine, 42);
-    /*
-    let host_hello = Func::wrap(&mut store, |caller: Caller<'_, HostState>, param: i32| {
-        println!("Got {param} from WebAssembly");
-        println!("My host state is: {}", caller.data());
-    });*/
-
-    // In order to create Wasm module instances and link their imports
-    // and exports we require a `Linker`.
-    let mut linker = <Linker<HostState>>::new(&engine);
-    // Instantiation of a Wasm module requires defining its imports and then
-    // afterwards we can fetch exports by name, as well as asserting the
-    // type signature of the function with `get_typed_func`.
-    //
-    // Also before using an instance created this way we need to start it.
-    //linker.define("host", "hello", host_hello)?;
-    let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
-    let function = instance.get_typed_func::<(i32, i32), i32>(&store, "main")?;
-
-    // And finally we can call the wasm!
-    let res = function.call(&mut store, (0, 0))?;
-
-    Ok(res)
-}
@@ -0,0 +1,186 @@
+use alloc::{collections::BTreeMap, sync::Arc};
+use wasmi::Module;
+
+/// Default upper bound for the bytes held by the module cache (4M).
+pub const DEFAULT_CAPACITY: usize = 4 * 1024 * 1024;
+
+/// Roughly how many bytes a compiled module takes per byte of its binary:
+/// wasmi keeps the translated code and the module's metadata.
+const COMPILED_SIZE_FACTOR: usize = 4;
+
+/// Cache key: the FNV-1a hash of the module bytes together with their length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    hash: u64,
+    len: usize,
+}
+
+struct Entry {
+    module: Arc<Module>,
+    /// Estimated size of `module`
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub used: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A cache of validated modules, evicting the least recently used entries
+/// once the total size of the cached modules would exceed `capacity`.
+///
+/// Only the compiled modules are kept, not the binaries; their size is
+/// estimated with `compiled_size`.
+pub struct ModuleCache {
+    entries: BTreeMap<Key, Entry>,
+    capacity: usize,
+    used: usize,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl ModuleCache {
+    pub const fn new(capacity: usize) -> Self {
+        ModuleCache {
+            entries: BTreeMap::new(),
+            capacity,
+            used: 0,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns the cached module compiled from `wasm`, if there is one.
+    pub fn get(&mut self, wasm: &[u8]) -> Option<Arc<Module>> {
+        self.clock += 1;
+        match self.entries.get_mut(&key(wasm)) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.hits += 1;
+                Some(entry.module.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache `module`, compiled from `wasm`, replacing any entry with the
+    /// same key. Modules bigger than the whole cache aren't kept.
+    ///
+    /// Compiling takes a while, so it is done without the cache locked and
+    /// the result inserted afterwards.
+    pub fn insert(&mut self, wasm: &[u8], module: Arc<Module>) {
+        let key = key(wasm);
+        let size = compiled_size(wasm);
+        if size > self.capacity {
+            return;
+        }
+        if let Some(entry) = self.entries.remove(&key) {
+            self.used -= entry.size;
+        }
+        self.evict(size);
+        self.entries.insert(
+            key,
+            Entry {
+                module,
+                size,
+                last_used: self.clock,
+            },
+        );
+        self.used += size;
+    }
+
+    /// Evict least recently used entries until `size` more bytes fit.
+    fn evict(&mut self, size: usize) {
+        while self.used + size > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            match oldest.and_then(|key| self.entries.remove(&key)) {
+                Some(entry) => self.used -= entry.size,
+                None => break,
+            }
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict(0);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.used = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            used: self.used,
+            capacity: self.capacity,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+/// Estimated memory taken by the module compiled from `wasm`.
+fn compiled_size(wasm: &[u8]) -> usize {
+    wasm.len().saturating_mul(COMPILED_SIZE_FACTOR)
+}
+
+fn key(wasm: &[u8]) -> Key {
+    Key {
+        hash: hash(wasm),
+        len: wasm.len(),
+    }
+}
+
+/// 64-bit FNV-1a hash of `bytes`.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[test_case]
+fn test_fnv1a_hash() {
+    assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+}
+
+#[test_case]
+fn test_module_cache_hits_and_evicts() {
+    use wasmi::Engine;
+
+    let engine = Engine::default();
+    let compile = |wasm: &[u8]| Arc::new(Module::new(&engine, wasm).unwrap());
+    let empty = b"\0asm\x01\0\0\0";
+    let mut cache = ModuleCache::new(compiled_size(empty));
+    assert!(cache.get(empty).is_none());
+    cache.insert(empty, compile(empty));
+    assert!(cache.get(empty).is_some());
+    assert_eq!(cache.stats().hits, 1);
+    assert_eq!(cache.stats().misses, 1);
+
+    // an empty module with a custom section no longer fits next to the first
+    let named = b"\0asm\x01\0\0\0\0\x02\x01a";
+    cache.set_capacity(compiled_size(named));
+    cache.insert(named, compile(named));
+    assert_eq!(cache.stats().entries, 1);
+    assert_eq!(cache.stats().used, compiled_size(named));
+}
@@ -0,0 +1,100 @@
+use alloc::string::String;
+use core::time::Duration;
+use wasmi::{Caller, Error, Extern, Linker, Memory};
+
+use super::HostState;
+use crate::task::timer;
+use crate::{io, print, rtc, time};
+
+/// The linear memory exported by the calling module as `memory`.
+pub(super) fn memory(caller: &Caller<'_, HostState>) -> Option<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory)
+}
+
+/// Define the functions programs can import from the `host` module.
+pub fn define(linker: &mut Linker<HostState>) -> Result<(), Error> {
+    // write(ptr, len) -> written: print `len` bytes to standard output
+    linker.func_wrap(
+        "host",
+        "write",
+        |caller: Caller<'_, HostState>, ptr: i32, len: i32| -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return -1,
+            };
+            let data = memory.data(&caller);
+            match data.get(ptr as usize..(ptr as usize).saturating_add(len as usize)) {
+                Some(bytes) => {
+                    print!("{}", String::from_utf8_lossy(bytes));
+                    len
+                }
+                None => -1,
+            }
+        },
+    )?;
+    // read(ptr, len) -> read: read up to `len` bytes of standard input,
+    // 0 at the end of input
+    linker.func_wrap(
+        "host",
+        "read",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return -1,
+            };
+            let data = memory.data_mut(&mut caller);
+            match data.get_mut(ptr as usize..(ptr as usize).saturating_add(len as usize)) {
+                Some(buf) => io::read(buf) as i32,
+                None => -1,
+            }
+        },
+    )?;
+    // clock() -> nanoseconds: time since boot
+    linker.func_wrap("host", "clock", || -> i64 {
+        time::uptime().as_nanos() as i64
+    })?;
+    // sleep(ms): wait for `ms` milliseconds. Programs run synchronously, so
+    // this holds up the rest of the kernel too.
+    linker.func_wrap("host", "sleep", |ms: i32| {
+        let ticks = timer::duration_to_ticks(Duration::from_millis(ms.max(0) as u64));
+        timer::wait_ticks(ticks);
+    })?;
+    define_wasi(linker)?;
+    super::sockets::define(linker)
+}
+
+/// WASI clock IDs
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+
+/// WASI error numbers
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_FAULT: i32 = 21;
+const ERRNO_INVAL: i32 = 28;
+
+/// Define the parts of `wasi_snapshot_preview1` that are supported.
+fn define_wasi(linker: &mut Linker<HostState>) -> Result<(), Error> {
+    // clock_time_get(id, precision, time_ptr) -> errno: store the time of a
+    // clock in nanoseconds
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "clock_time_get",
+        |mut caller: Caller<'_, HostState>, id: i32, _precision: i64, ptr: i32| -> i32 {
+            let time = match id {
+                CLOCK_REALTIME => rtc::unix_time(),
+                CLOCK_MONOTONIC => time::uptime(),
+                _ => return ERRNO_INVAL,
+            };
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERRNO_FAULT,
+            };
+            let bytes = (time.as_nanos() as u64).to_le_bytes();
+            match memory.write(&mut caller, ptr as u32 as usize, &bytes) {
+                Ok(()) => ERRNO_SUCCESS,
+                Err(_) => ERRNO_FAULT,
+            }
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use wasmi::*;
+
+use crate::println;
+
+pub mod cache;
+pub mod host;
+pub mod sockets;
+
+use cache::ModuleCache;
+
+/// Host-specific data stored alongside every instance.
+#[derive(Default)]
+pub struct HostState {
+    /// Sockets the program has open, indexed by their handles.
+    pub sockets: Vec<Option<sockets::Socket>>,
+}
+
+lazy_static! {
+    /// Cached modules are bound to the engine that compiled them, so all
+    /// programs share a single engine.
+    static ref ENGINE: Engine = Engine::default();
+    pub static ref MODULE_CACHE: Mutex<ModuleCache> =
+        Mutex::new(ModuleCache::new(cache::DEFAULT_CAPACITY));
+}
+
+pub fn wasm_runner(wasm: Vec<u8>) -> Result<i32, Error> {
+    let engine = &*ENGINE;
+
+    // the cache isn't locked while compiling, which takes a while
+    let cached = MODULE_CACHE.lock().get(&wasm);
+    let module = match cached {
+        Some(module) => module,
+        None => {
+            let module = Arc::new(Module::new(engine, &wasm)?);
+            MODULE_CACHE.lock().insert(&wasm, module.clone());
+            module
+        }
+    };
+
+    // All Wasm objects operate within the context of a `Store`.
+    // Each `Store` has a type parameter to store host-specific data,
+    // which here holds the program's open sockets.
+    let mut store = Store::new(engine, HostState::default());
+    /*
+    let host_hello = Func::wrap(&mut store, |caller: Caller<'_, HostState>, param: i32| {
+        println!("Got {param} from WebAssembly");
+        println!("My host state is: {}", caller.data());
+    });*/
+
+    // In order to create Wasm module instances and link their imports
+    // and exports we require a `Linker`.
+    let mut linker = <Linker<HostState>>::new(engine);
+    // Instantiation of a Wasm module requires defining its imports and then
+    // afterwards we can fetch exports by name, as well as asserting the
+    // type signature of the function with `get_typed_func`.
+    //
+    // Also before using an instance created this way we need to start it.
+    //linker.define("host", "hello", host_hello)?;
+    host::define(&mut linker)?;
+    let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+    let function = instance.get_typed_func::<(i32, i32), i32>(&store, "main")?;
+
+    // And finally we can call the wasm!
+    let res = function.call(&mut store, (0, 0))?;
+
+    Ok(res)
+}
@@ -0,0 +1,251 @@
+use core::future::Future;
+use core::time::Duration;
+use wasmi::{Caller, Error, Linker};
+
+use super::host::memory;
+use super::HostState;
+use crate::net::socket::{IpAddress, IpEndpoint, Ipv4Address, TcpListener, TcpStream, UdpSocket};
+use crate::net::stack::block_on;
+use crate::net::NetError;
+use crate::task::timer;
+
+/// How long a call may wait for the network before failing with
+/// `ERROR_TIMED_OUT`.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A socket opened by a program, closed when the program ends.
+pub enum Socket {
+    Tcp(TcpStream),
+    Listener(TcpListener),
+    Udp(UdpSocket),
+}
+
+/// Errors returned by the `net` functions
+const ERROR_BAD_HANDLE: i32 = -1;
+const ERROR_FAULT: i32 = -2;
+const ERROR_NOT_CONFIGURED: i32 = -3;
+const ERROR_UNADDRESSABLE: i32 = -4;
+const ERROR_REFUSED: i32 = -5;
+const ERROR_CLOSED: i32 = -6;
+const ERROR_TIMED_OUT: i32 = -7;
+const ERROR_TOO_LONG: i32 = -8;
+
+fn error_code(err: NetError) -> i32 {
+    match err {
+        NetError::NotFound | NetError::NotConfigured => ERROR_NOT_CONFIGURED,
+        NetError::Unaddressable => ERROR_UNADDRESSABLE,
+        NetError::Refused => ERROR_REFUSED,
+        NetError::Closed => ERROR_CLOSED,
+        NetError::TimedOut => ERROR_TIMED_OUT,
+        NetError::TooLong | NetError::Busy => ERROR_TOO_LONG,
+    }
+}
+
+/// Store `socket` in the first free slot, returning its handle.
+fn open(state: &mut HostState, socket: Result<Socket, NetError>) -> i32 {
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(err) => return error_code(err),
+    };
+    match state.sockets.iter().position(Option::is_none) {
+        Some(handle) => {
+            state.sockets[handle] = Some(socket);
+            handle as i32
+        }
+        None => {
+            state.sockets.push(Some(socket));
+            state.sockets.len() as i32 - 1
+        }
+    }
+}
+
+/// Block until `future` is done, or for at most `TIMEOUT`.
+fn wait<T>(future: impl Future<Output = Result<T, NetError>>) -> Result<T, NetError> {
+    block_on(timer::timeout(TIMEOUT, future)).unwrap_or(Err(NetError::TimedOut))
+}
+
+fn socket(state: &mut HostState, handle: i32) -> Option<&mut Socket> {
+    state.sockets.get_mut(handle as usize)?.as_mut()
+}
+
+/// An IPv4 address passed as a big-endian `i32`, so 10.0.2.2 is 0x0a000202.
+fn endpoint(ip: i32, port: i32) -> IpEndpoint {
+    let address = Ipv4Address::from_bytes(&(ip as u32).to_be_bytes());
+    IpEndpoint::new(address.into(), port as u16)
+}
+
+/// Define the functions programs can import from the `net` module. They
+/// return a handle or a length on success and a negative error otherwise,
+/// and block until done, holding up the rest of the kernel like `sleep`.
+/// Calls that wait for the other side give up after `TIMEOUT`.
+pub fn define(linker: &mut Linker<HostState>) -> Result<(), Error> {
+    // tcp_connect(ip, port) -> handle
+    linker.func_wrap(
+        "net",
+        "tcp_connect",
+        |mut caller: Caller<'_, HostState>, ip: i32, port: i32| -> i32 {
+            let stream = block_on(TcpStream::connect(endpoint(ip, port)));
+            open(caller.data_mut(), stream.map(Socket::Tcp))
+        },
+    )?;
+    // tcp_listen(port) -> handle: wait for connections with `tcp_accept`
+    linker.func_wrap(
+        "net",
+        "tcp_listen",
+        |mut caller: Caller<'_, HostState>, port: i32| -> i32 {
+            let listener = TcpListener::bind(port as u16);
+            open(caller.data_mut(), listener.map(Socket::Listener))
+        },
+    )?;
+    // tcp_accept(listener) -> handle of the connection
+    linker.func_wrap(
+        "net",
+        "tcp_accept",
+        |mut caller: Caller<'_, HostState>, listener: i32| -> i32 {
+            let state = caller.data_mut();
+            let stream = match socket(state, listener) {
+                Some(Socket::Listener(listener)) => wait(listener.accept()),
+                _ => return ERROR_BAD_HANDLE,
+            };
+            open(state, stream.map(Socket::Tcp))
+        },
+    )?;
+    // udp_bind(port) -> handle: port 0 picks a free one
+    linker.func_wrap(
+        "net",
+        "udp_bind",
+        |mut caller: Caller<'_, HostState>, port: i32| -> i32 {
+            let socket = UdpSocket::bind(port as u16);
+            open(caller.data_mut(), socket.map(Socket::Udp))
+        },
+    )?;
+    // send(handle, ptr, len) -> sent: write to a TCP connection
+    linker.func_wrap(
+        "net",
+        "send",
+        |mut caller: Caller<'_, HostState>, handle: i32, ptr: i32, len: i32| -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERROR_FAULT,
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+            let data = match data.get(ptr as usize..(ptr as usize).saturating_add(len as usize)) {
+                Some(data) => data,
+                None => return ERROR_FAULT,
+            };
+            match socket(state, handle) {
+                Some(Socket::Tcp(stream)) => match wait(stream.write(data)) {
+                    Ok(sent) => sent as i32,
+                    Err(err) => error_code(err),
+                },
+                _ => ERROR_BAD_HANDLE,
+            }
+        },
+    )?;
+    // recv(handle, ptr, len) -> received: read from a TCP connection, 0 once
+    // the other side has closed it
+    linker.func_wrap(
+        "net",
+        "recv",
+        |mut caller: Caller<'_, HostState>, handle: i32, ptr: i32, len: i32| -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERROR_FAULT,
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+            let buf = match data.get_mut(ptr as usize..(ptr as usize).saturating_add(len as usize))
+            {
+                Some(buf) => buf,
+                None => return ERROR_FAULT,
+            };
+            match socket(state, handle) {
+                Some(Socket::Tcp(stream)) => match wait(stream.read(buf)) {
+                    Ok(received) => received as i32,
+                    Err(err) => error_code(err),
+                },
+                _ => ERROR_BAD_HANDLE,
+            }
+        },
+    )?;
+    // send_to(handle, ptr, len, ip, port) -> sent: send a UDP datagram
+    linker.func_wrap(
+        "net",
+        "send_to",
+        |mut caller: Caller<'_, HostState>,
+         handle: i32,
+         ptr: i32,
+         len: i32,
+         ip: i32,
+         port: i32|
+         -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERROR_FAULT,
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+            let data = match data.get(ptr as usize..(ptr as usize).saturating_add(len as usize)) {
+                Some(data) => data,
+                None => return ERROR_FAULT,
+            };
+            match socket(state, handle) {
+                Some(Socket::Udp(socket)) => match wait(socket.send_to(data, endpoint(ip, port))) {
+                    Ok(()) => len,
+                    Err(err) => error_code(err),
+                },
+                _ => ERROR_BAD_HANDLE,
+            }
+        },
+    )?;
+    // recv_from(handle, ptr, len, from_ptr) -> received: wait for a UDP
+    // datagram, storing the sender's address at `from_ptr` as 4 bytes of IP
+    // followed by the port in little-endian
+    linker.func_wrap(
+        "net",
+        "recv_from",
+        |mut caller: Caller<'_, HostState>,
+         handle: i32,
+         ptr: i32,
+         len: i32,
+         from_ptr: i32|
+         -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERROR_FAULT,
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+            let buf = ptr as usize..(ptr as usize).saturating_add(len as usize);
+            let from = from_ptr as usize..(from_ptr as usize).saturating_add(6);
+            if buf.end > data.len() || from.end > data.len() {
+                return ERROR_FAULT;
+            }
+            let received = match socket(state, handle) {
+                Some(Socket::Udp(socket)) => wait(socket.recv_from(&mut data[buf])),
+                _ => return ERROR_BAD_HANDLE,
+            };
+            match received {
+                Ok((received, sender)) => {
+                    let IpAddress::Ipv4(ip) = sender.addr;
+                    data[from.start..from.start + 4].copy_from_slice(ip.as_bytes());
+                    data[from.start + 4..from.end].copy_from_slice(&sender.port.to_le_bytes());
+                    received as i32
+                }
+                Err(err) => error_code(err),
+            }
+        },
+    )?;
+    // close(handle) -> 0
+    linker.func_wrap(
+        "net",
+        "close",
+        |mut caller: Caller<'_, HostState>, handle: i32| -> i32 {
+            match caller.data_mut().sockets.get_mut(handle as usize) {
+                Some(socket @ Some(_)) => {
+                    *socket = None;
+                    0
+                }
+                _ => ERROR_BAD_HANDLE,
+            }
+        },
+    )?;
+    Ok(())
+}